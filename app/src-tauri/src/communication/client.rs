@@ -7,6 +7,7 @@ use sha2::Sha256;
 use crate::x3dh::x3dh::X3DHError;
 use crate::double_ratchet::double_ratchet::DoubleRatchetHE;
 use x25519_dalek::PublicKey;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use super::key_collection::KeyError;
 use super::message::{Ciphertext, HeaderHE, Message};
@@ -18,6 +19,7 @@ pub struct Client {
     name: String,
     communications: HashMap<String, (Vec<u8>, DoubleRatchetHE)>, // Each communication has a different double ratchet (Key: username, ad) (Value: double ratchet for the communication)
     keys: ClientKeyCollection,
+    ephemeral_counters: HashMap<String, u64>, // Counter of the last ephemeral message sent to or received from each user (the replays are dropped)
 }
 
 impl Client {
@@ -31,11 +33,12 @@ impl Client {
             name: name,
             communications: HashMap::new(),
             keys: keys,
+            ephemeral_counters: HashMap::new(),
         }
     }
 
     pub fn from(name: String, communications: HashMap<String, (Vec<u8>, DoubleRatchetHE)>, keys: ClientKeyCollection) -> Self {
-        Client { name, communications, keys }
+        Client { name, communications, keys, ephemeral_counters: HashMap::new() }
     }
 
     pub fn get_server_keys(&self) -> ServerKeyCollection {
@@ -114,26 +117,41 @@ impl Client {
         let plaintext: Vec<u8> = double_ratchet.decrypt_he((message.get_header_he().get_ciphertext(), message.get_header_he().get_nonce()), 
                     message.get_ciphertext().get_ciphertext(), 
                     message.get_ciphertext().get_nonce(), 
-                    &ad);
+                    &ad).map_err(KeyError::UnreadableMessage)?;
         self.communications.insert(sender_name.clone(), (ad, double_ratchet));
 
         Ok(plaintext)
     }
     
+    /// Return the Identity Key of the user, None if there is no session with this user
+    ///
+    /// The associated data of a session is the Identity Key of the sender of the first message, then the one of its receiver.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` (&str): Name of the other user of the session
+    pub fn get_session_ik(&self, name: &str) -> Option<PublicKey> {
+        let (ad, _) = self.communications.get(name)?;
+        let own_ik: [u8; 32] = self.keys.get_ik_public().to_bytes();
+        let (first_ik, second_ik): ([u8; 32], [u8; 32]) = (ad[0..32].try_into().ok()?, ad[32..64].try_into().ok()?);
+        Some(PublicKey::from(if first_ik == own_ik { second_ik } else { first_ik }))
+    }
+
     /// Read all the messages sent by one user
     /// 
     /// # Arguments
     /// 
     /// * `receiver_name` (&String): Name of the person that will receive the message
     /// * `messages` (&[u8]): Message(s) sent by the user *(can have multiple ciphertext when you are offline)*
-    /// * `r_keys`: (&ServerKeyCollection)
+    /// * `r_keys`: (Option\<&ServerKeyCollection\>): Public keys of the receiver, only needed for the first message
     /// 
     /// # Output
     /// 
     /// * `ciphertext` (Result\<(Option\<(PublicKey, Option<PublicKey>)>, (Header, Ciphertext)), X3DHError>): ((Public Ephemeral Key, Public One Time Prekey used), (Header, Ciphertext))
-    pub fn send_message(&mut self, receiver_name: &String, message: &[u8], r_keys: &ServerKeyCollection) -> Result<(Option<(PublicKey, Option<PublicKey>)>, (HeaderHE, Ciphertext)), X3DHError> {
+    pub fn send_message(&mut self, receiver_name: &String, message: &[u8], r_keys: Option<&ServerKeyCollection>) -> Result<(Option<(PublicKey, Option<PublicKey>)>, (HeaderHE, Ciphertext)), X3DHError> {
         // Send a message to the define user (check if the first message has already been sends, otherwise use first message instead)
         if !self.communications.contains_key(receiver_name) {
+            let r_keys: &ServerKeyCollection = r_keys.ok_or(X3DHError::PublicKeysAbsent)?;
             match self.send_first_message(receiver_name, message, r_keys) {
                 Ok(((ek_pub, opk_used), (header, ciphertext))) => return Ok((Some((ek_pub, opk_used)), (header, ciphertext))),
                 Err(error) => return Err(error),
@@ -151,6 +169,50 @@ impl Client {
         panic!("User not found, which is not normal");
     }
     
+    /// Encrypt an ephemeral message with the session of the receiver, without moving the double ratchet forward
+    ///
+    /// The counter is the time in milliseconds *(increasing after a restart of the app)*.
+    ///
+    /// # Arguments
+    ///
+    /// * `receiver_name` (&str): Name of the person that will receive the message
+    /// * `message` (&\[u8\]): Plaintext
+    ///
+    /// # Output
+    ///
+    /// * `ciphertext` (Option\<(HeaderHE, Ciphertext)\>): Header and ciphertext, None if there is no session with the receiver
+    pub fn send_ephemeral_message(&mut self, receiver_name: &str, message: &[u8]) -> Option<(HeaderHE, Ciphertext)> {
+        let (ad, double_ratchet) = self.communications.get(receiver_name)?;
+        let now: u64 = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_millis() as u64);
+        let counter: u64 = now.max(self.ephemeral_counters.get(receiver_name).map_or(0, |counter| counter + 1));
+        let (encrypted_header, ciphertext) = double_ratchet.encrypt_ephemeral_he(message, ad, counter)?;
+        self.ephemeral_counters.insert(receiver_name.to_string(), counter);
+        Some((HeaderHE::new(encrypted_header.0, encrypted_header.1), Ciphertext::new(ciphertext.0, ciphertext.1)))
+    }
+
+    /// Read an ephemeral message, without moving the double ratchet forward
+    ///
+    /// # Arguments
+    ///
+    /// * `sender_name` (&str): Name of the person that sent you the message
+    /// * `message` (&Message): Message sent by the user
+    ///
+    /// # Output
+    ///
+    /// * `plaintext` (Option\<Vec\<u8\>\>): Plaintext, None if there is no session with the sender, if the message can not be decrypted or if it is a replay
+    pub fn read_ephemeral_message(&mut self, sender_name: &str, message: &Message) -> Option<Vec<u8>> {
+        let (ad, double_ratchet) = self.communications.get(sender_name)?;
+        let (counter, plaintext) = double_ratchet.decrypt_ephemeral_he((message.get_header_he().get_ciphertext(), message.get_header_he().get_nonce()),
+            message.get_ciphertext().get_ciphertext(),
+            message.get_ciphertext().get_nonce(),
+            ad)?;
+        if self.ephemeral_counters.get(sender_name).is_some_and(|last_counter| counter <= *last_counter) {
+            return None
+        }
+        self.ephemeral_counters.insert(sender_name.to_string(), counter);
+        Some(plaintext)
+    }
+
     /// Read all the messages sent by one user
     /// 
     /// # Arguments
@@ -179,15 +241,17 @@ impl Client {
                 
             }
             
-            if let Some((ad, double_ratchet)) = self.communications.get_mut(sender_name) {
+            if let Some((ad, double_ratchet)) = self.communications.get(sender_name) {
+                // The session is changed only if all the messages can be decrypted
+                let mut double_ratchet: DoubleRatchetHE = double_ratchet.clone();
                 for message in messages {
                     let current_plaintext: Vec<u8> = double_ratchet.decrypt_he((message.get_header_he().get_ciphertext(), message.get_header_he().get_nonce()), 
                         message.get_ciphertext().get_ciphertext(), 
                         message.get_ciphertext().get_nonce(), 
-                        ad);
+                        ad).map_err(KeyError::UnreadableMessage)?;
                     plaintext_received.push(current_plaintext);                    
                 }
-                *self.communications.get_mut(sender_name).unwrap() = (ad.clone(), double_ratchet.clone());
+                *self.communications.get_mut(sender_name).unwrap() = (ad.clone(), double_ratchet);
             }
         }
 
@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+/// Plaintext encrypted with the double ratchet *(JSON object)*
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Content {
+    pub text: String,
+    pub profile_key: Option<[u8; 32]>, // Profile key of the sender, so the receiver can answer with sealed messages
+    #[serde(default)]
+    pub receipt: Option<Receipt>, // Control message: not shown to the user *(the text is empty)*
+    #[serde(default)]
+    pub typing: Option<Typing>, // Typing indicator, sent as an ephemeral message *(the text is empty)*
+}
+
+/// Receipt of messages received or read, referencing the GUIDs given by the server to the original messages
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Receipt {
+    pub kind: ReceiptKind,
+    pub guids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Typing {
+    Started,
+    Stopped,
+}
+
+impl Content {
+    pub fn new(text: String, profile_key: Option<[u8; 32]>) -> Self {
+        Content { text, profile_key, receipt: None, typing: None }
+    }
+
+    pub fn new_receipt(kind: ReceiptKind, guids: Vec<String>, profile_key: Option<[u8; 32]>) -> Self {
+        Content { text: String::new(), profile_key, receipt: Some(Receipt { kind, guids }), typing: None }
+    }
+
+    pub fn new_typing(typing: Typing) -> Self {
+        Content { text: String::new(), profile_key: None, receipt: None, typing: Some(typing) }
+    }
+
+    /// Receipt or typing indicator *(sent over an existing session only, never starts one)*
+    pub fn is_control(&self) -> bool {
+        self.receipt.is_some() || self.typing.is_some()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("Error when encoding the content")
+    }
+
+    /// Decode the plaintext of a message *(an older client sends the text alone)*
+    pub fn from_bytes(plaintext: &[u8]) -> Self {
+        serde_json::from_slice(plaintext)
+            .unwrap_or_else(|_| Content::new(String::from_utf8_lossy(plaintext).to_string(), None))
+    }
+}
@@ -1,6 +1,7 @@
 use crate::x3dh::x3dh::{IdentityKey, SignedPrekey, OneTimePrekey,  x3dh_sender, x3dh_receiver, create_prekey_signature, create_prekey_bundle, X3DHError, get_ad};
 use ed25519_dalek::{Signature, VerifyingKey};
 use x25519_dalek::{PublicKey, StaticSecret};
+use crate::double_ratchet::aead::CryptoError;
 use std::fmt;
 
 use super::message::Message;
@@ -11,6 +12,7 @@ const BASIC_AMOUNT_OF_OPK: u8 = 50; // Change base on the average user behaviour
 pub enum KeyError {
     EphemeralKeyAbsent,
     IdentityKeyAbsent,
+    UnreadableMessage(CryptoError),
 }
 
 pub struct ClientKeyCollection {
@@ -196,6 +198,7 @@ impl fmt::Display for KeyError {
         match self {
             KeyError::EphemeralKeyAbsent => write!(f, "No ephemeral key to initialize the receiver X3DH"),
             KeyError::IdentityKeyAbsent => write!(f, "No identity key to initialize the receiver X3DH"),
+            KeyError::UnreadableMessage(error) => write!(f, "{}", error),
         }
     }
 }
\ No newline at end of file
@@ -1,4 +1,6 @@
 pub mod client;
 pub mod server;
 pub mod key_collection;
-pub mod message;
\ No newline at end of file
+pub mod message;
+pub mod content;
+pub mod sealed_sender;
\ No newline at end of file
@@ -0,0 +1,181 @@
+//! Sealed sender: the identity of the sender is encrypted to the receiver, the server only sees the receiver
+//!
+//! The sealed sender has two layers *(simplified version of Signal sealed sender v1)*:
+//! * an ephemeral key agreement with the Identity Key of the receiver encrypts the Identity Key of the sender,
+//! * a key agreement between the two Identity Keys encrypts the sender certificate *(only the owner of the certified Identity Key can seal it)*.
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use mini_signal_protocol::{Encoding, SenderCertificate};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use std::fmt;
+use x25519_dalek::{PublicKey, StaticSecret};
+use crate::double_ratchet::aead::{decrypt, encrypt};
+use crate::x3dh::x3dh::IdentityKey;
+
+const ACCESS_KEY_INFO: &[u8] = b"mini-signal delivery access key";
+const EPHEMERAL_LAYER_INFO: &[u8] = b"mini-signal sealed sender ephemeral";
+const STATIC_LAYER_INFO: &[u8] = b"mini-signal sealed sender static";
+const NONCE_SIZE: usize = 12; // AES-GCM-SIV
+const SEALED_IK_SIZE: usize = 32 + 16; // Identity Key and AEAD tag
+
+#[derive(Debug)]
+pub enum SealedSenderError {
+    Malformed, // Too short, or the certificate can not be decoded
+    DecryptionFailed,
+    IdentityKeyMismatch, // The certificate does not belong to the key that sealed it
+    InvalidSignature, // Not issued by the server
+    Expired, // Expired before the server received the message
+}
+
+/// Keys needed to send sealed messages *(loaded at login)*
+pub struct SealedSender {
+    profile_key: [u8; 32],
+    certificate: SenderCertificate,
+    server_key: VerifyingKey,
+}
+
+impl SealedSender {
+    pub fn new(profile_key: [u8; 32], certificate: SenderCertificate, server_key: VerifyingKey) -> Self {
+        SealedSender { profile_key, certificate, server_key }
+    }
+
+    pub fn get_profile_key(&self) -> [u8; 32] {
+        self.profile_key
+    }
+
+    pub fn get_certificate(&self) -> &SenderCertificate {
+        &self.certificate
+    }
+
+    pub fn get_server_key(&self) -> &VerifyingKey {
+        &self.server_key
+    }
+
+    /// Check if the certificate is still valid at `now` *(seconds since the UNIX epoch)*
+    pub fn is_certificate_valid(&self, now: u64) -> bool {
+        self.certificate.expires_at > now
+    }
+
+    /// Replace the expired certificate *(the key of the server is given with each certificate)*
+    pub fn set_certificate(&mut self, certificate: SenderCertificate, server_key: VerifyingKey) {
+        self.certificate = certificate;
+        self.server_key = server_key;
+    }
+}
+
+/// Generate the profile key of the user *(shared with its contacts inside the encrypted messages)*
+pub fn generate_profile_key() -> [u8; 32] {
+    let mut profile_key: [u8; 32] = [0u8; 32];
+    OsRng.fill_bytes(&mut profile_key);
+    profile_key
+}
+
+/// Derive the delivery access key from a profile key *(the server only knows the access key)*
+///
+/// # Arguments
+///
+/// * `profile_key` (&\[u8; 32\]): Profile key of the receiver
+///
+/// # Output
+///
+/// * `access_key` (\[u8; 16\]): Delivery access key
+pub fn derive_access_key(profile_key: &[u8; 32]) -> [u8; 16] {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(profile_key).expect("HMAC can take key of any size");
+    mac.update(ACCESS_KEY_INFO);
+    mac.finalize().into_bytes()[..16].try_into().expect("Incorrect length")
+}
+
+/// Encrypt the sender certificate to the Identity Key of the receiver
+///
+/// # Arguments
+///
+/// * `certificate` (&SenderCertificate): Certificate of the sender *(issued by the server)*
+/// * `ik_sender` (&IdentityKey): Identity Key of the sender *(the key of the certificate)*
+/// * `ik_receiver` (&PublicKey): Identity Key of the receiver
+///
+/// # Output
+///
+/// * `sealed_sender` (Vec\<u8\>): Ephemeral public key, sealed Identity Key of the sender and sealed certificate *(each with its nonce)*
+pub fn seal(certificate: &SenderCertificate, ik_sender: &IdentityKey, ik_receiver: &PublicKey) -> Vec<u8> {
+    let ephemeral_private_key: StaticSecret = StaticSecret::random_from_rng(OsRng);
+    let ephemeral_public_key: PublicKey = PublicKey::from(&ephemeral_private_key);
+
+    let ephemeral_key: [u8; 32] = derive_key(ephemeral_private_key.diffie_hellman(ik_receiver).as_bytes(), &[ephemeral_public_key.as_bytes().as_slice(), ik_receiver.as_bytes()].concat(), EPHEMERAL_LAYER_INFO);
+    let (sealed_ik, ik_nonce) = encrypt(ephemeral_key, ik_sender.get_public_key().as_bytes(), ephemeral_public_key.as_bytes())
+        .expect("Error when sealing the identity key");
+
+    let static_key: [u8; 32] = derive_key(ik_sender.get_private_key().diffie_hellman(ik_receiver).as_bytes(), &sealed_ik, STATIC_LAYER_INFO);
+    let certificate_bytes: Vec<u8> = Encoding::Cbor.encode(certificate).expect("Error when encoding the sender certificate");
+    let (sealed_certificate, certificate_nonce) = encrypt(static_key, &certificate_bytes, &sealed_ik)
+        .expect("Error when sealing the sender certificate");
+
+    [ephemeral_public_key.as_bytes().as_slice(), &ik_nonce, &sealed_ik, &certificate_nonce, &sealed_certificate].concat()
+}
+
+/// Decrypt and verify the sender certificate of a sealed message
+///
+/// # Arguments
+///
+/// * `sealed_sender` (&\[u8\]): Sealed sender of the envelope
+/// * `ik_receiver` (&IdentityKey): Identity Key of the current user
+/// * `server_key` (&VerifyingKey): Key of the server signing the certificates
+/// * `received_at` (u64): Reception of the message by the server *(the certificate must be valid at that time)*
+///
+/// # Output
+///
+/// * `certificate` (Result\<SenderCertificate, SealedSenderError\>): Certificate of the sender
+pub fn unseal(sealed_sender: &[u8], ik_receiver: &IdentityKey, server_key: &VerifyingKey, received_at: u64) -> Result<SenderCertificate, SealedSenderError> {
+    if sealed_sender.len() < 32 + NONCE_SIZE + SEALED_IK_SIZE + NONCE_SIZE {
+        return Err(SealedSenderError::Malformed)
+    }
+    let (ephemeral_public_key, rest) = sealed_sender.split_at(32);
+    let (ik_nonce, rest) = rest.split_at(NONCE_SIZE);
+    let (sealed_ik, rest) = rest.split_at(SEALED_IK_SIZE);
+    let (certificate_nonce, sealed_certificate) = rest.split_at(NONCE_SIZE);
+
+    let ephemeral_public_key: PublicKey = PublicKey::from(<[u8; 32]>::try_from(ephemeral_public_key).expect("Incorrect length"));
+    let ik_receiver_public: PublicKey = ik_receiver.get_public_key();
+    let ephemeral_key: [u8; 32] = derive_key(ik_receiver.get_private_key().diffie_hellman(&ephemeral_public_key).as_bytes(), &[ephemeral_public_key.as_bytes().as_slice(), ik_receiver_public.as_bytes()].concat(), EPHEMERAL_LAYER_INFO);
+    let ik_sender: [u8; 32] = decrypt(ephemeral_key, &sealed_ik.to_vec(), &ik_nonce.to_vec(), ephemeral_public_key.as_bytes())
+        .map_err(|_| SealedSenderError::DecryptionFailed)?
+        .try_into().map_err(|_| SealedSenderError::Malformed)?;
+
+    let static_key: [u8; 32] = derive_key(ik_receiver.get_private_key().diffie_hellman(&PublicKey::from(ik_sender)).as_bytes(), sealed_ik, STATIC_LAYER_INFO);
+    let certificate_bytes: Vec<u8> = decrypt(static_key, &sealed_certificate.to_vec(), &certificate_nonce.to_vec(), sealed_ik)
+        .map_err(|_| SealedSenderError::DecryptionFailed)?;
+    let certificate: SenderCertificate = Encoding::Cbor.decode(&certificate_bytes).map_err(|_| SealedSenderError::Malformed)?;
+
+    if certificate.ik != ik_sender {
+        return Err(SealedSenderError::IdentityKeyMismatch)
+    }
+    server_key.verify(&SenderCertificate::signed_bytes(&certificate.sender, &certificate.ik, certificate.expires_at), &Signature::from_bytes(&certificate.signature))
+        .map_err(|_| SealedSenderError::InvalidSignature)?;
+    if certificate.expires_at <= received_at {
+        return Err(SealedSenderError::Expired)
+    }
+    Ok(certificate)
+}
+
+/// Key of a layer of the sealed sender *(HKDF-SHA256 of the shared secret)*
+fn derive_key(shared_secret: &[u8; 32], salt: &[u8], info: &[u8]) -> [u8; 32] {
+    let hk = Hkdf::<Sha256>::new(Some(salt), shared_secret);
+    let mut okm = [0u8; 32];
+    hk.expand(info, &mut okm)
+        .expect("Output length invalid");
+    okm
+}
+
+impl fmt::Display for SealedSenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SealedSenderError::Malformed => write!(f, "malformed sealed sender"),
+            SealedSenderError::DecryptionFailed => write!(f, "the sealed sender can not be decrypted"),
+            SealedSenderError::IdentityKeyMismatch => write!(f, "the certificate does not match the identity key of the sender"),
+            SealedSenderError::InvalidSignature => write!(f, "the certificate has not been issued by the server"),
+            SealedSenderError::Expired => write!(f, "the certificate has expired"),
+        }
+    }
+}
@@ -12,6 +12,7 @@ use crate::double_ratchet::double_ratchet::DoubleRatchetHE;
 use crate::communication::key_collection::ClientKeyCollection;
 use serde_json::Value;
 use crate::x3dh::x3dh::{IdentityKey, OneTimePrekey, SignedPrekey};
+use mini_signal_protocol::normalize_username;
 
 #[derive(Serialize, Deserialize)]
 struct MkSkippedForSQL {
@@ -21,11 +22,16 @@ struct MkSkippedForSQL {
 pub struct DoubleRatchetDatabase {
     conn: Connection
 }
+/// Path of the double ratchet database of the corresponding `username`
+pub fn get_database_path(username: &str) -> String {
+    format!("double_ratchet_{}.db", username.to_lowercase())
+}
+
 // https://docs.rs/crate/rusqlcipher/latest
 impl DoubleRatchetDatabase {
     pub fn new(username: &str) -> Result<Self> {
         // Create one database per user on the same computer to limit the leak of information (the database should be encrypted with the user password)
-        let conn: Connection = Connection::open(format!("double_ratchet_{}.db", username.to_lowercase()))?;
+        let conn: Connection = Connection::open(get_database_path(username))?;
 
         // Store the X3DH keys but also the information necessary to continue a Double ratchet communication
         conn.execute(
@@ -73,11 +79,56 @@ impl DoubleRatchetDatabase {
             mkskipped TEXT NOT NULL
         )", ())?;
 
-        Ok(DoubleRatchetDatabase { conn })
+        // Profile key of the user and of its contacts (sealed sender, see communication::sealed_sender)
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS profile_keys (
+            username TEXT NOT NULL PRIMARY KEY,
+            profile_key BLOB NOT NULL
+        )", ())?;
+
+        let mut double_ratchet_database: DoubleRatchetDatabase = DoubleRatchetDatabase { conn };
+        double_ratchet_database.normalize_usernames()?;
+        Ok(double_ratchet_database)
+    }
+
+    /// Rename the usernames stored before the username policy to their canonical form *(see mini_signal_protocol::normalize_username)*
+    fn normalize_usernames(&mut self) -> Result<()> {
+        let usernames: Vec<String> = {
+            let mut stmt: Statement = self.conn.prepare("SELECT username FROM x3dh UNION SELECT username_interlocutor FROM double_ratchet UNION SELECT username FROM profile_keys")?;
+            let usernames: Result<Vec<String>> = stmt.query_map([], |row| row.get(0))?.collect();
+            usernames?
+        };
+
+        let tx: Transaction = self.conn.transaction()?;
+        for username in usernames.iter().filter(|username| normalize_username(username) != **username) {
+            let normalized_username: String = normalize_username(username);
+            tx.execute("UPDATE x3dh SET username = ?1 WHERE username = ?2", params![normalized_username, username])?;
+            tx.execute("UPDATE opk_bundle SET username = ?1 WHERE username = ?2", params![normalized_username, username])?;
+            // Keep the existing conversation if both forms were used
+            tx.execute("UPDATE OR IGNORE double_ratchet SET username_interlocutor = ?1 WHERE username_interlocutor = ?2", params![normalized_username, username])?;
+            tx.execute("UPDATE OR IGNORE profile_keys SET username = ?1 WHERE username = ?2", params![normalized_username, username])?;
+        }
+        tx.commit()
     }
 
     /// # X3DH database
 
+    /// Check if the X3DH keys of the corresponding `username` are stored
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (&str): Username
+    ///
+    /// # Output
+    ///
+    /// * bool
+    pub fn client_exist(&self, username: &str) -> Result<bool> {
+        let mut stmt: Statement = self.conn.prepare("SELECT 1 FROM x3dh WHERE username=:username")?;
+        let exists: bool = stmt.exists(&[(":username", username)])?;
+
+        Ok(exists)
+    }
+
     /// Insert X3DH keys
     ///
     /// # Arguments
@@ -231,6 +282,42 @@ impl DoubleRatchetDatabase {
         tx.commit()
     }
 
+    /// # Profile keys database
+
+    /// Return the profile key of the corresponding `username` *(the current user or one of its contacts)*
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (&str): Username
+    ///
+    /// # Output
+    ///
+    /// * `profile_key` (Result\<Option\<\[u8; 32\]\>\>): None if the profile key is unknown
+    pub fn get_profile_key(&self, username: &str) -> Result<Option<[u8; 32]>> {
+        let mut stmt: Statement = self.conn.prepare("SELECT profile_key FROM profile_keys WHERE username = ?1")?;
+
+        let profile_key: Result<Vec<[u8; 32]>> = stmt.query_map(params![username], |row| {
+            Ok(row.get(0)?)
+        })?.collect();
+
+        Ok(profile_key?.pop())
+    }
+
+    /// Store the profile key of the corresponding `username` *(replace the previous one)*
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (&str): Username
+    /// * `profile_key` (\[u8; 32\]): Profile key
+    pub fn insert_profile_key(&mut self, username: &str, profile_key: [u8; 32]) -> Result<()> {
+        let tx: Transaction = self.conn.transaction()?;
+
+        tx.execute("REPLACE INTO profile_keys (username, profile_key) VALUES (?1, ?2)",
+                   params![username, profile_key])?;
+
+        tx.commit()
+    }
+
     /// # Double Ratchet database
 
     /// Store the communication HashMap from the Client object (communication::client::Client)
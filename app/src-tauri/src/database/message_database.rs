@@ -1,35 +1,133 @@
-use rusqlite::{Connection, Result, params, Transaction, Statement};
+use rusqlite::{Connection, OptionalExtension, Result, params, Transaction, Statement, ToSql};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use serde::Serialize;
+use mini_signal_protocol::normalize_username;
 
 pub struct MessageDatabase {
     conn: Connection
 }
 
+/// Status of a message, shown next to the messages sent
+///
+/// A receipt only moves a message forward *(sent, delivered, read)*, and a message failed never changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageStatus {
+    Sending = 0,
+    Sent = 1,
+    Delivered = 2, // Received messages not read yet
+    Read = 3,
+    Failed = 4, // Not accepted by the server, or expired before being received
+}
+
+/// Path of the message database of the corresponding `username`
+pub fn get_database_path(username: &str) -> String {
+    format!("messages_{}.db", username.to_lowercase())
+}
+
 impl MessageDatabase {
     pub fn new(username: &str) -> Result<Self> {
-        let conn: Connection = Connection::open(format!("messages_{}.db", username.to_lowercase()))?;
+        let conn: Connection = Connection::open(get_database_path(username))?;
 
         conn.execute("CREATE TABLE IF NOT EXISTS messages (
             message_id INTEGER PRIMARY KEY AUTOINCREMENT,
             username_sender TEXT NOT NULL,
             username_receiver TEXT NOT NULL,
-            message TEXT NOT NULL
+            message TEXT NOT NULL,
+            server_message_id INTEGER,
+            expired INTEGER NOT NULL DEFAULT 0,
+            server_guid TEXT,
+            server_timestamp INTEGER,
+            server_seq INTEGER,
+            status INTEGER NOT NULL DEFAULT 1
+        )", ())?;
+
+        // Databases created before the server message ids
+        let has_server_message_id: bool = conn.prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name='server_message_id'")?.exists([])?;
+        if !has_server_message_id {
+            conn.execute("ALTER TABLE messages ADD COLUMN server_message_id INTEGER", ())?;
+            conn.execute("ALTER TABLE messages ADD COLUMN expired INTEGER NOT NULL DEFAULT 0", ())?;
+        }
+        // Databases created before the envelopes stamped by the server
+        let has_server_guid: bool = conn.prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name='server_guid'")?.exists([])?;
+        if !has_server_guid {
+            conn.execute("ALTER TABLE messages ADD COLUMN server_guid TEXT", ())?;
+            conn.execute("ALTER TABLE messages ADD COLUMN server_timestamp INTEGER", ())?;
+            conn.execute("ALTER TABLE messages ADD COLUMN server_seq INTEGER", ())?;
+        }
+        // Databases created before the receipts
+        let has_status: bool = conn.prepare("SELECT 1 FROM pragma_table_info('messages') WHERE name='status'")?.exists([])?;
+        if !has_status {
+            conn.execute("ALTER TABLE messages ADD COLUMN status INTEGER NOT NULL DEFAULT 1", ())?;
+        }
+
+        // Sequence numbers already checked for missing messages (see find_lost_messages)
+        conn.execute("CREATE TABLE IF NOT EXISTS mailbox_state (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            checked_seq INTEGER NOT NULL
+        )", ())?;
+
+        // Receipts received: not shown, but their sequence numbers are not lost messages
+        conn.execute("CREATE TABLE IF NOT EXISTS control_messages (
+            server_guid TEXT PRIMARY KEY,
+            username_receiver TEXT NOT NULL,
+            server_seq INTEGER NOT NULL
+        )", ())?;
+
+        conn.execute("CREATE TABLE IF NOT EXISTS settings (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            read_receipts INTEGER NOT NULL DEFAULT 1
         )", ())?;
+        conn.execute("INSERT OR IGNORE INTO settings (id) VALUES (0)", ())?;
 
-        Ok(MessageDatabase { conn })
+        let mut message_database: MessageDatabase = MessageDatabase { conn };
+        message_database.normalize_usernames()?;
+        if !has_status {
+            // No receipt is sent for the messages received before, and the ones sent stay without receipt
+            message_database.conn.execute("UPDATE messages SET status = CASE WHEN username_sender != ?1 THEN ?2 WHEN expired = 1 THEN ?3 ELSE ?4 END",
+                                          params![normalize_username(username), MessageStatus::Read, MessageStatus::Failed, MessageStatus::Sent])?;
+        }
+        Ok(message_database)
     }
 
-    pub fn get_messages_with(&self, username_receiver: &str) -> Result<Vec<(String, String, String)>> {
-        let mut stmt: Statement = self.conn.prepare("SELECT message_id, username_sender, username_receiver, message FROM messages WHERE username_sender = ?1 OR username_receiver = ?1 ORDER BY message_id ASC")?;
+    /// Rename the usernames stored before the username policy to their canonical form *(see mini_signal_protocol::normalize_username)*
+    fn normalize_usernames(&mut self) -> Result<()> {
+        let usernames: Vec<String> = {
+            let mut stmt: Statement = self.conn.prepare("SELECT username_sender FROM messages UNION SELECT username_receiver FROM messages")?;
+            let usernames: Result<Vec<String>> = stmt.query_map([], |row| row.get(0))?.collect();
+            usernames?
+        };
+
+        let tx: Transaction = self.conn.transaction()?;
+        for username in usernames.iter().filter(|username| normalize_username(username) != **username) {
+            let normalized_username: String = normalize_username(username);
+            tx.execute("UPDATE messages SET username_sender = ?1 WHERE username_sender = ?2", params![normalized_username, username])?;
+            tx.execute("UPDATE messages SET username_receiver = ?1 WHERE username_receiver = ?2", params![normalized_username, username])?;
+        }
+        tx.commit()
+    }
+
+    /// Return the messages of the conversation in the order the server received them
+    ///
+    /// # Output
+    ///
+    /// * `messages` (Result\<Vec\<(String, String, String, Option\<u64\>, MessageStatus, Option\<String\>)\>\>): (sender, receiver, message, reception by the server, status, server GUID) *(no time for the messages stored before the server timestamps)*
+    pub fn get_messages_with(&self, username_receiver: &str) -> Result<Vec<(String, String, String, Option<u64>, MessageStatus, Option<String>)>> {
+        // The messages without timestamp are older, and a NULL comes first
+        let mut stmt: Statement = self.conn.prepare("SELECT message_id, username_sender, username_receiver, message, server_timestamp, status, server_guid FROM messages WHERE username_sender = ?1 OR username_receiver = ?1 ORDER BY server_timestamp ASC, server_seq ASC, message_id ASC")?;
 
         let mut result = stmt.query_map(&[username_receiver], |row| {
             let username_sender: String = row.get(1)?;
             let username_receiver: String = row.get(2)?;
             let message: String = row.get(3)?;
+            let server_timestamp: Option<u64> = row.get(4)?;
+            let status: MessageStatus = row.get(5)?;
+            let server_guid: Option<String> = row.get(6)?;
 
-            Ok((username_sender, username_receiver, message))
+            Ok((username_sender, username_receiver, message, server_timestamp, status, server_guid))
         })?;
 
-        let mut messages: Vec<(String, String, String)> = Vec::new();
+        let mut messages: Vec<(String, String, String, Option<u64>, MessageStatus, Option<String>)> = Vec::new();
 
         while let Some(result) = result.next() {
             messages.push(result.unwrap());
@@ -38,12 +136,209 @@ impl MessageDatabase {
         Ok(messages)
     }
 
-    pub fn insert_message(&mut self, username_sender: &str, username_receiver: &str, message: &str) -> Result<()> {
+    /// Insert a message sent or received
+    ///
+    /// # Arguments
+    ///
+    /// * `username_sender` (&str): Username of the sender
+    /// * `username_receiver` (&str): Username of the receiver
+    /// * `message` (&str): Plaintext message
+    /// * `server_message_id` (Option\<i64\>): Id given by the server *(to find a message that expired)*
+    /// * `server_guid` (Option\<&str\>): GUID given by the server *(to ignore a message sent again when the acknowledgement is lost)*
+    /// * `server_timestamp` (Option\<u64\>): Reception by the server *(seconds since the UNIX epoch)*
+    /// * `server_seq` (Option\<u64\>): Sequence number in the mailbox of the current user *(messages received only)*
+    /// * `status` (MessageStatus): `Sending` before a message is sent, `Delivered` when it is received
+    ///
+    /// # Output
+    ///
+    /// * `message_id` (Result\<i64\>): Id of the message in the database
+    pub fn insert_message(&mut self, username_sender: &str, username_receiver: &str, message: &str, server_message_id: Option<i64>,
+                          server_guid: Option<&str>, server_timestamp: Option<u64>, server_seq: Option<u64>, status: MessageStatus) -> Result<i64> {
         let tx: Transaction = self.conn.transaction()?;
 
-        tx.execute("INSERT INTO messages (username_sender, username_receiver, message) VALUES (?1, ?2, ?3)",
-                   (username_sender, username_receiver, message))?;
+        tx.execute("INSERT INTO messages (username_sender, username_receiver, message, server_message_id, server_guid, server_timestamp, server_seq, status) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                   (username_sender, username_receiver, message, server_message_id, server_guid, server_timestamp, server_seq, status))?;
+        let message_id: i64 = tx.last_insert_rowid();
 
-        tx.commit()
+        tx.commit()?;
+        Ok(message_id)
+    }
+
+    /// Store the ids given by the server to a message sent
+    ///
+    /// # Arguments
+    ///
+    /// * `message_id` (i64): Id returned by `insert_message`
+    /// * `server_message_id` (i64): Id given by the server
+    /// * `server_guid` (&str): GUID given by the server *(referenced by the receipts)*
+    /// * `server_timestamp` (u64): Reception by the server
+    pub fn mark_sent(&mut self, message_id: i64, server_message_id: i64, server_guid: &str, server_timestamp: u64) -> Result<()> {
+        self.conn.execute("UPDATE messages SET server_message_id = ?1, server_guid = ?2, server_timestamp = ?3, status = ?4 WHERE message_id = ?5",
+                          params![server_message_id, server_guid, server_timestamp, MessageStatus::Sent, message_id])?;
+        Ok(())
+    }
+
+    /// Mark a message that the server did not accept
+    pub fn mark_failed(&mut self, message_id: i64) -> Result<()> {
+        self.conn.execute("UPDATE messages SET status = ?1 WHERE message_id = ?2", params![MessageStatus::Failed, message_id])?;
+        Ok(())
     }
-}
\ No newline at end of file
+
+    /// Apply a receipt to the messages sent to its sender
+    ///
+    /// # Arguments
+    ///
+    /// * `username_sender` (&str): Username of the current user
+    /// * `username_receiver` (&str): Sender of the receipt *(only the receiver of a message can acknowledge it)*
+    /// * `server_guids` (&Vec\<String\>): GUIDs of the messages
+    /// * `status` (MessageStatus): `Delivered` or `Read`
+    ///
+    /// # Output
+    ///
+    /// * `updated_guids` (Result\<Vec\<String\>\>): GUIDs of the messages whose status changed
+    pub fn update_status(&mut self, username_sender: &str, username_receiver: &str, server_guids: &Vec<String>, status: MessageStatus) -> Result<Vec<String>> {
+        let tx: Transaction = self.conn.transaction()?;
+
+        let mut updated_guids: Vec<String> = Vec::new();
+        for server_guid in server_guids {
+            // A receipt received late never moves a message back (read then delivered)
+            let updated: usize = tx.execute("UPDATE messages SET status = ?1 WHERE username_sender = ?2 AND username_receiver = ?3 AND server_guid = ?4 AND status < ?1",
+                                            params![status, username_sender, username_receiver, server_guid])?;
+            if updated > 0 {
+                updated_guids.push(server_guid.clone());
+            }
+        }
+
+        tx.commit()?;
+        Ok(updated_guids)
+    }
+
+    /// Mark the messages received from `username_sender` as read
+    ///
+    /// # Output
+    ///
+    /// * `server_guids` (Result\<Vec\<String\>\>): GUIDs of the messages not read before *(for the read receipt)*
+    pub fn mark_read(&mut self, username_sender: &str, username_receiver: &str) -> Result<Vec<String>> {
+        let tx: Transaction = self.conn.transaction()?;
+
+        let server_guids: Vec<String> = {
+            let mut stmt: Statement = tx.prepare("SELECT server_guid FROM messages WHERE username_sender = ?1 AND username_receiver = ?2 AND status = ?3 AND server_guid IS NOT NULL")?;
+            let server_guids: Result<Vec<String>> = stmt.query_map(params![username_sender, username_receiver, MessageStatus::Delivered], |row| row.get(0))?.collect();
+            server_guids?
+        };
+        tx.execute("UPDATE messages SET status = ?1 WHERE username_sender = ?2 AND username_receiver = ?3 AND status = ?4",
+                   params![MessageStatus::Read, username_sender, username_receiver, MessageStatus::Delivered])?;
+
+        tx.commit()?;
+        Ok(server_guids)
+    }
+
+    /// Store a control message received *(receipt)*, so it is not read again and its sequence number is not counted as lost
+    pub fn insert_control_message(&mut self, server_guid: &str, username_receiver: &str, server_seq: u64) -> Result<()> {
+        self.conn.execute("INSERT OR IGNORE INTO control_messages (server_guid, username_receiver, server_seq) VALUES (?1, ?2, ?3)",
+                          params![server_guid, username_receiver, server_seq])?;
+        Ok(())
+    }
+
+    /// Check if the message with the corresponding server GUID has already been read
+    pub fn is_received(&self, server_guid: &str) -> Result<bool> {
+        let mut stmt: Statement = self.conn.prepare("SELECT 1 FROM messages WHERE server_guid=?1 UNION ALL SELECT 1 FROM control_messages WHERE server_guid=?1")?;
+        stmt.exists(params![server_guid])
+    }
+
+    pub fn get_read_receipts(&self) -> Result<bool> {
+        self.conn.query_row("SELECT read_receipts FROM settings WHERE id = 0", [], |row| row.get(0))
+    }
+
+    /// Enable or disable the read receipts sent *(the delivery receipts are always sent)*
+    pub fn set_read_receipts(&mut self, enabled: bool) -> Result<()> {
+        self.conn.execute("UPDATE settings SET read_receipts = ?1 WHERE id = 0", params![enabled])?;
+        Ok(())
+    }
+
+    /// Count the messages missing in the sequence numbers received since the last check
+    ///
+    /// Called once the mailbox has been collected: a message missing then has been deleted by the server *(expired)* or could not be decrypted.
+    ///
+    /// # Arguments
+    ///
+    /// * `username_receiver` (&str): Username of the current user
+    ///
+    /// # Output
+    ///
+    /// * `lost` (Result\<u64\>): Number of messages missing since the last check
+    pub fn find_lost_messages(&mut self, username_receiver: &str) -> Result<u64> {
+        let tx: Transaction = self.conn.transaction()?;
+
+        let checked_seq: Option<u64> = tx.query_row("SELECT checked_seq FROM mailbox_state WHERE id = 0", [], |row| row.get(0)).optional()?;
+        let sequence_numbers: Vec<u64> = {
+            let mut stmt: Statement = tx.prepare("SELECT server_seq FROM messages WHERE username_receiver=?1 AND server_seq > ?2
+                                                  UNION SELECT server_seq FROM control_messages WHERE username_receiver=?1 AND server_seq > ?2 ORDER BY server_seq ASC")?;
+            let sequence_numbers: Result<Vec<u64>> = stmt.query_map(params![username_receiver, checked_seq.unwrap_or(0)], |row| row.get(0))?.collect();
+            sequence_numbers?
+        };
+
+        let mut lost: u64 = 0;
+        // First check on this device: the messages before the first one received are not counted
+        let mut previous_seq: Option<u64> = checked_seq;
+        for seq in &sequence_numbers {
+            if let Some(previous_seq) = previous_seq {
+                lost += seq.saturating_sub(previous_seq + 1);
+            }
+            previous_seq = Some(*seq);
+        }
+
+        if let Some(last_seq) = sequence_numbers.last() {
+            tx.execute("INSERT INTO mailbox_state (id, checked_seq) VALUES (0, ?1) ON CONFLICT (id) DO UPDATE SET checked_seq = ?1", params![last_seq])?;
+        }
+        tx.commit()?;
+        Ok(lost)
+    }
+
+    /// Mark the messages sent that expired on the server before being received
+    ///
+    /// # Arguments
+    ///
+    /// * `username_sender` (&str): Username of the current user
+    /// * `server_message_ids` (&Vec\<i64\>): Ids returned by the server when the messages were sent
+    ///
+    /// # Output
+    ///
+    /// * `expired_messages` (Result\<Vec\<(String, String)\>\>): (receiver, message) of the messages marked
+    pub fn mark_expired(&mut self, username_sender: &str, server_message_ids: &Vec<i64>) -> Result<Vec<(String, String)>> {
+        let tx: Transaction = self.conn.transaction()?;
+
+        let mut expired_messages: Vec<(String, String)> = Vec::new();
+        {
+            let mut stmt: Statement = tx.prepare("SELECT username_receiver, message FROM messages WHERE username_sender=?1 AND server_message_id=?2")?;
+            for server_message_id in server_message_ids {
+                let messages: Result<Vec<(String, String)>> = stmt.query_map(params![username_sender, server_message_id], |row| Ok((row.get(0)?, row.get(1)?)))?.collect();
+                expired_messages.extend(messages?);
+                tx.execute("UPDATE messages SET expired = 1, status = ?3 WHERE username_sender=?1 AND server_message_id=?2",
+                           params![username_sender, server_message_id, MessageStatus::Failed])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(expired_messages)
+    }
+}
+
+impl ToSql for MessageStatus {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(*self as i64))
+    }
+}
+
+impl FromSql for MessageStatus {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_i64()? {
+            0 => Ok(MessageStatus::Sending),
+            1 => Ok(MessageStatus::Sent),
+            2 => Ok(MessageStatus::Delivered),
+            3 => Ok(MessageStatus::Read),
+            4 => Ok(MessageStatus::Failed),
+            other => Err(FromSqlError::OutOfRange(other)),
+        }
+    }
+}
@@ -3,11 +3,15 @@ use aes_gcm_siv::{
     Aes256GcmSiv, AeadCore,
 };
 use x25519_dalek::PublicKey;
+use std::fmt;
+
+const NONCE_LEN: usize = 12; // AES-GCM-SIV
 
 #[derive(Debug)]
 pub enum CryptoError {
     EncryptionError,
     DecryptionError,
+    TooManySkippedMessages,
 }
 
 /// Encrypt the message using AES-GCM-SIV-256
@@ -49,6 +53,9 @@ pub fn encrypt(mk: [u8; 32], plaintext: &[u8], ad: &[u8]) -> Result<(Vec<u8>, Ve
 /// 
 /// * `plaintext` (Result\<Vec\<u8\>, CryptoError\>): Plaintext
 pub fn decrypt(mk: [u8; 32], ciphertext: &Vec<u8>, nonce: &Vec<u8>, ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+    if nonce.len() != NONCE_LEN {
+        return Err(CryptoError::DecryptionError)
+    }
     let cipher = Aes256GcmSiv::new(&GenericArray::clone_from_slice(&mk));
     let payload = Payload {
         msg: &ciphertext,
@@ -104,19 +111,28 @@ pub fn hencrypt(hk: [u8; 32], header: (PublicKey, u8, u8)) -> Result<(Vec<u8>, V
 /// 
 /// * `header decrypted` (Result\<(PublicKey, u8, u8), CryptoError\>): Header
 pub fn hdecrypt(hk: [u8; 32], ciphertext: &Vec<u8>, nonce: &Vec<u8>) -> Option<(PublicKey, u8, u8)> {
+    if nonce.len() != NONCE_LEN {
+        return None
+    }
     let cipher = Aes256GcmSiv::new(&GenericArray::clone_from_slice(&hk));
 
-    let decrypted_header = cipher
+    let decrypted_header: Vec<u8> = cipher
         .decrypt(&GenericArray::clone_from_slice(&nonce), ciphertext.as_ref())
-        .ok();
+        .ok()?;
 
-    if decrypted_header.is_some() {
-        let public_key_bytes: [u8; 32] = decrypted_header.clone().unwrap()[0..32].try_into().ok()?;
-        let public_key: PublicKey = PublicKey::from(public_key_bytes);
-        let pn: u8 = decrypted_header.clone().unwrap()[32];
-        let n: u8 = decrypted_header.clone().unwrap()[33];
-        return Some((public_key, pn, n))
-    }
+    let public_key_bytes: [u8; 32] = decrypted_header.get(0..32)?.try_into().ok()?;
+    let public_key: PublicKey = PublicKey::from(public_key_bytes);
+    let pn: u8 = *decrypted_header.get(32)?;
+    let n: u8 = *decrypted_header.get(33)?;
+    Some((public_key, pn, n))
+}
 
-    None
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptoError::EncryptionError => write!(f, "The message can not be encrypted"),
+            CryptoError::DecryptionError => write!(f, "The message can not be decrypted"),
+            CryptoError::TooManySkippedMessages => write!(f, "Too many messages skipped in the receiving chain"),
+        }
+    }
 }
\ No newline at end of file
@@ -1,5 +1,6 @@
 use crate::double_ratchet::state::State;
 use crate::double_ratchet::aead::{encrypt as aead_encrypt, decrypt as aead_decrypt, hencrypt, hdecrypt};
+use crate::double_ratchet::padding::{pad, unpad};
 use sha2::Sha256;
 use hmac::{Hmac, Mac};
 use hkdf::Hkdf;
@@ -12,6 +13,7 @@ use super::aead::CryptoError;
 const MAX_SKIP: u16 = 1000;
 const BYTE_MESSAGE_KEY: &[u8] = &[0x01];
 const BYTE_NEXT_CHAIN_KEY: &[u8] = &[0x02];
+const BYTE_EPHEMERAL_KEY: &[u8] = &[0x03];
 const INFO: &[u8] = &[0x73];
 type HmacSha256 = Hmac<Sha256>;
 
@@ -142,7 +144,7 @@ impl DoubleRatchetHE {
         (Some(new_chain_key), new_message_key)
     }
     
-    /// Returns an AEAD (AES-GCM-SIV-256) encryption of plaintext with message key `mk`.
+    /// Returns an AEAD (AES-GCM-SIV-256) encryption of the padded plaintext with message key `mk`.
     /// 
     /// # Arguments
     /// 
@@ -161,14 +163,16 @@ impl DoubleRatchetHE {
             Err(error) => panic!("Error header (AES-GCM-SIV): {:?}", error),
         };
         self.state.n_s += 1;
-        let res = match aead_encrypt(mk, plaintext, &self.concat(ad, header)) {
+        let res = match aead_encrypt(mk, &pad(plaintext), &self.concat(ad, header)) {
             Ok((ciphertext, nonce)) => (ciphertext, nonce),
             Err(error) => panic!("Error (AES-GCM-SIV): {:?}", error),
         };
         (enc_header, res)
     }
     
-    /// Returns the AEAD (AES-GCM-SIV-256) decryption of ciphertext with message key mk.
+    /// Returns the AEAD (AES-GCM-SIV-256) decryption of ciphertext with message key mk, without the padding.
+    /// 
+    /// If the message can not be decrypted, it is discarded and the state is not changed.
     /// 
     /// # Arguments
     /// 
@@ -179,18 +183,22 @@ impl DoubleRatchetHE {
     /// 
     /// # Output
     /// 
-    /// * `plaintext` (Vec\<u8\>): Plaintext
-    pub fn decrypt_he(&mut self, enc_header: (Vec<u8>, Vec<u8>), ciphertext: Vec<u8>, nonce: Vec<u8>, ad: &[u8]) -> Vec<u8> {
-        let plaintext: Option<Vec<u8>> = self.try_skipped_message_keys_he(enc_header.clone(), &ciphertext, &nonce, ad);
-        if plaintext.is_some() {
-            return plaintext.unwrap()
+    /// * `plaintext` (Result\<Vec\<u8\>, CryptoError\>): Plaintext
+    pub fn decrypt_he(&mut self, enc_header: (Vec<u8>, Vec<u8>), ciphertext: Vec<u8>, nonce: Vec<u8>, ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let mut double_ratchet: DoubleRatchetHE = self.clone();
+        let plaintext: Vec<u8> = double_ratchet.ratchet_decrypt_he(enc_header, ciphertext, nonce, ad)?;
+        *self = double_ratchet;
+        Ok(plaintext)
+    }
+
+    /// Decryption of `decrypt_he`, the state is left in an intermediate state on error
+    fn ratchet_decrypt_he(&mut self, enc_header: (Vec<u8>, Vec<u8>), ciphertext: Vec<u8>, nonce: Vec<u8>, ad: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if let Some(plaintext) = self.try_skipped_message_keys_he(enc_header.clone(), &ciphertext, &nonce, ad)? {
+            return Ok(unpad(plaintext))
         }
-        let (header, dh_ratchet): ((PublicKey, u8, u8), bool) = match self.decrypt_header(enc_header.clone()) {
-            Ok((current_header, current_dh_ratchet)) => (current_header, current_dh_ratchet),
-            Err(error) => panic!("Error header (AES-GCM-SIV): {:?}", error),
-        };
+        let (header, dh_ratchet): ((PublicKey, u8, u8), bool) = self.decrypt_header(enc_header)?;
         if dh_ratchet {
-            self.skip_message_keys_he(header.1);
+            self.skip_message_keys_he(header.1)?;
             self.state.pn = self.state.n_s;
             (self.state.n_s, self.state.n_r) = (0, 0);
             self.state.hk_s = self.state.nhk_s;
@@ -202,18 +210,81 @@ impl DoubleRatchetHE {
             let (rk_result, ck_s_result, nhk_s_result) = self.kdf_rk_he(self.state.rk.unwrap(), self.dh(self.state.dh_s.as_ref().unwrap(), self.state.dh_r.unwrap()));
             (self.state.rk, self.state.ck_s, self.state.nhk_s) = (Some(rk_result), Some(ck_s_result), Some(nhk_s_result));
         }
-        self.skip_message_keys_he(header.2);
+        self.skip_message_keys_he(header.2)?;
         let mk: [u8; 32];
-        (self.state.ck_r, mk) = self.kdf_ck(self.state.ck_r.unwrap());
+        (self.state.ck_r, mk) = self.kdf_ck(self.state.ck_r.ok_or(CryptoError::DecryptionError)?);
         self.state.n_r += 1;
-        
-        let res = match aead_decrypt(mk, &ciphertext, &nonce, &self.concat(ad, header)) {
-            Ok(plaintext) => plaintext,
-            Err(error) => panic!("Error (AES-GCM-SIV): {:?}", error),
-        };
-        res
+
+        Ok(unpad(aead_decrypt(mk, &ciphertext, &nonce, &self.concat(ad, header))?))
     }
     
+    /// Returns the encryption of an ephemeral message, without changing the state.
+    /// 
+    /// The server drops an ephemeral message if the receiver is not connected: moving the sending chain forward would make
+    /// the receiver skip a message key for each message dropped. The ephemeral key is derived from the current sending chain key
+    /// with its own constant, it is never a message key. The counter is encrypted with the plaintext, so the receiver can drop the replays.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `plaintext` (&\[u8\]): Plaintext
+    /// * `ad` (&\[u8\]): Associated Data
+    /// * `counter` (u64): Counter of the ephemeral messages sent to the receiver *(increasing)*
+    /// 
+    /// # Output
+    /// 
+    /// * `(enc_header, res)` (Option\<((Vec<u8>, Vec<u8>), (Vec\<u8\>, Vec\<u8\>))\>): Encrypted header and ciphertext, None if the session can not send yet
+    pub fn encrypt_ephemeral_he(&self, plaintext: &[u8], ad: &[u8], counter: u64) -> Option<((Vec<u8>, Vec<u8>), (Vec<u8>, Vec<u8>))> {
+        let ek: [u8; 32] = self.kdf_ephemeral(self.state.ck_s?);
+        let header: (PublicKey, u8, u8) = self.header(self.state.dh_s.as_ref()?, self.state.pn, self.state.n_s);
+        let enc_header: (Vec<u8>, Vec<u8>) = hencrypt(self.state.hk_s?, header).ok()?;
+        let res: (Vec<u8>, Vec<u8>) = aead_encrypt(ek, &pad(&[&counter.to_be_bytes()[..], plaintext].concat()), &self.concat(ad, header)).ok()?;
+        Some((enc_header, res))
+    }
+
+    /// Returns the decryption of an ephemeral message *(see `encrypt_ephemeral_he`)*, without changing the state.
+    /// 
+    /// # Arguments
+    /// 
+    /// * `enc_header` ((Vec<u8>, Vec<u8>)): Encrypted Header
+    /// * `ciphertext` (&\[u8\]): Ciphertext
+    /// * `nonce` (Vec\<u8\>): Nonce
+    /// * `ad` (&\[u8\]): Associated Data
+    /// 
+    /// # Output
+    /// 
+    /// * `(counter, plaintext)` (Option\<(u64, Vec\<u8\>)\>): Counter and plaintext, None if the message can not be decrypted
+    pub fn decrypt_ephemeral_he(&self, enc_header: (Vec<u8>, Vec<u8>), ciphertext: Vec<u8>, nonce: Vec<u8>, ad: &[u8]) -> Option<(u64, Vec<u8>)> {
+        let (header, dh_ratchet): ((PublicKey, u8, u8), bool) = self.decrypt_header(enc_header).ok()?;
+        // Receiving chain key at the position of the header (the chain of the next DH ratchet step is computed, not stored)
+        let (mut ck, mut n): ([u8; 32], u8) = if dh_ratchet {
+            let (_, ck_r, _) = self.kdf_rk_he(self.state.rk?, self.dh(self.state.dh_s.as_ref()?, header.0));
+            (ck_r, 0)
+        } else {
+            (self.state.ck_r?, self.state.n_r)
+        };
+        // The chain key has moved past this position
+        if header.2 < n || n as u16 + MAX_SKIP < header.2 as u16 {
+            return None
+        }
+        while n < header.2 {
+            ck = self.kdf_ck(ck).0?;
+            n += 1;
+        }
+
+        let padded: Vec<u8> = aead_decrypt(self.kdf_ephemeral(ck), &ciphertext, &nonce, &self.concat(ad, header)).ok()?;
+        let plaintext: Vec<u8> = unpad(padded);
+        let counter: [u8; 8] = plaintext.get(0..8)?.try_into().ok()?;
+        Some((u64::from_be_bytes(counter), plaintext[8..].to_vec()))
+    }
+
+    /// Returns the key of the ephemeral messages sent with the chain key `ck` *(the chain key is not moved forward)*
+    fn kdf_ephemeral(&self, ck: [u8; 32]) -> [u8; 32] {
+        let mut mac_ek = HmacSha256::new_from_slice(&ck)
+            .expect("HMAC can take key of any size");
+        mac_ek.update(BYTE_EPHEMERAL_KEY);
+        mac_ek.finalize().into_bytes().into()
+    }
+
     /// Check if the message corresponds to a skipped message key. 
     /// 
     /// If it's a skipped message, this function decrypts the message, deletes the message key, and return the plaintext.
@@ -226,20 +297,16 @@ impl DoubleRatchetHE {
     /// 
     /// # Output
     /// 
-    /// `plaintext` (Option\<Vec\<u8\>\>): Optional plaintext
-    fn try_skipped_message_keys_he(&mut self, enc_header: (Vec<u8>, Vec<u8>), ciphertext: &Vec<u8>, nonce: &Vec<u8>,  ad: &[u8]) -> Option<Vec<u8>> {
+    /// `plaintext` (Result\<Option\<Vec\<u8\>\>, CryptoError\>): Optional plaintext, error if the message key is found but the message can not be decrypted
+    fn try_skipped_message_keys_he(&mut self, enc_header: (Vec<u8>, Vec<u8>), ciphertext: &Vec<u8>, nonce: &Vec<u8>,  ad: &[u8]) -> Result<Option<Vec<u8>>, CryptoError> {
         for ((hk, n), mk) in self.state.mkskipped.clone().iter() {
             let header: Option<(PublicKey, u8, u8)> = hdecrypt(*hk, &enc_header.0, &enc_header.1);
             if header.is_some() && header.unwrap().2 == *n {
                 self.state.mkskipped.remove(&(*hk, *n));
-                let res = match aead_decrypt(*mk, ciphertext, &nonce, &self.concat(ad, header.unwrap())) {
-                    Ok(plaintext) => plaintext,
-                    Err(error) => panic!("Error (AES-GCM-SIV): {:?}", error),
-                };
-                return Some(res)
+                return Ok(Some(aead_decrypt(*mk, ciphertext, &nonce, &self.concat(ad, header.unwrap()))?))
             }
         }
-        None
+        Ok(None)
     }
 
     /// Decrypt the header and define if we need to applies a DH ratchet step
@@ -266,9 +333,13 @@ impl DoubleRatchetHE {
     /// 
     /// # Arguments
     /// * `until` (u8)
-    fn skip_message_keys_he(&mut self, until: u8) -> () {
+    /// 
+    /// # Output
+    /// 
+    /// Error if more than `MAX_SKIP` message keys would be skipped
+    fn skip_message_keys_he(&mut self, until: u8) -> Result<(), CryptoError> {
         if self.state.n_r as u16 + MAX_SKIP < until as u16 {
-            panic!("Error: state.Nr + MAX_SKIP < until");
+            return Err(CryptoError::TooManySkippedMessages)
         }
         if self.state.ck_r != None {
             while self.state.n_r < until {
@@ -278,6 +349,7 @@ impl DoubleRatchetHE {
                 self.state.n_r += 1;
             }
         }
+        Ok(())
     }
     
     /// Returns the output of applying a KDF keyed by a 32-byte chain key `ck` to some constant.
@@ -312,4 +384,69 @@ impl DoubleRatchetHE {
 
         [ad, public_key, &nb_messages_previous_chain.to_be_bytes(), &message_number.to_be_bytes()].concat()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AD: &[u8] = b"alice and bob";
+
+    fn sessions() -> (DoubleRatchetHE, DoubleRatchetHE) {
+        let bob_private_key: StaticSecret = StaticSecret::from([1; 32]);
+        let bob_public_key: PublicKey = PublicKey::from(&bob_private_key);
+        let mut alice: DoubleRatchetHE = DoubleRatchetHE::new();
+        let mut bob: DoubleRatchetHE = DoubleRatchetHE::new();
+        alice.init_sender_he([2; 32], bob_public_key, [3; 32], [4; 32]);
+        bob.init_receiver_he([2; 32], (bob_private_key, bob_public_key), [3; 32], [4; 32]);
+        (alice, bob)
+    }
+
+    #[test]
+    fn ephemeral_key_is_not_a_message_key() {
+        let (mut alice, mut bob) = sessions();
+        let (enc_header, (ciphertext, nonce)) = alice.encrypt_ephemeral_he(b"typing", AD, 7).unwrap();
+        // First message of a new chain on the side of the receiver
+        assert_eq!(bob.decrypt_ephemeral_he(enc_header.clone(), ciphertext.clone(), nonce.clone(), AD), Some((7, b"typing".to_vec())));
+
+        // The message key of the same position does not decrypt the ephemeral message
+        let (mk, _) = alice.kdf_ck(alice.state.ck_s.unwrap());
+        assert!(aead_decrypt(mk.unwrap(), &ciphertext, &nonce, &alice.concat(AD, alice.header(alice.state.dh_s.as_ref().unwrap(), 0, 0))).is_err());
+
+        // The session is not moved forward
+        let (enc_header_message, (ciphertext_message, nonce_message)) = alice.encrypt_he(b"hello", AD);
+        assert_eq!(bob.decrypt_he(enc_header_message, ciphertext_message, nonce_message, AD).unwrap(), b"hello");
+
+        // The ephemeral message sent before is now behind the receiving chain
+        assert_eq!(bob.decrypt_ephemeral_he(enc_header, ciphertext, nonce, AD), None);
+        let (enc_header, (ciphertext, nonce)) = alice.encrypt_ephemeral_he(b"typing", AD, 8).unwrap();
+        assert_eq!(bob.decrypt_ephemeral_he(enc_header, ciphertext, nonce, AD), Some((8, b"typing".to_vec())));
+    }
+
+    #[test]
+    fn forged_message_does_not_change_the_session() {
+        let (mut alice, mut bob) = sessions();
+        let (enc_header, (mut ciphertext, nonce)) = alice.encrypt_he(b"hello", AD);
+        ciphertext[0] ^= 1;
+        assert!(bob.decrypt_he(enc_header.clone(), ciphertext.clone(), nonce.clone(), AD).is_err());
+        assert!(bob.decrypt_he(enc_header.clone(), ciphertext, vec![0; 3], AD).is_err());
+        assert!(bob.decrypt_he((vec![0; 3], vec![0; 3]), vec![], vec![], AD).is_err());
+        assert!(bob.state.ck_r.is_none());
+
+        // The next messages are still read, the one forged is skipped
+        let (enc_header, (ciphertext, nonce)) = alice.encrypt_he(b"world", AD);
+        assert_eq!(bob.decrypt_he(enc_header, ciphertext, nonce, AD).unwrap(), b"world");
+    }
+
+    #[test]
+    fn forged_ephemeral_message() {
+        let (alice, bob) = sessions();
+        let (enc_header, (mut ciphertext, nonce)) = alice.encrypt_ephemeral_he(b"typing", AD, 7).unwrap();
+        ciphertext[0] ^= 1;
+        assert_eq!(bob.decrypt_ephemeral_he(enc_header.clone(), ciphertext, nonce.clone(), AD), None);
+        assert_eq!(bob.decrypt_ephemeral_he(enc_header, vec![], nonce, AD), None);
+        assert_eq!(bob.decrypt_ephemeral_he((vec![0; 40], vec![0; 12]), vec![0; 40], vec![0; 12], AD), None);
+
+        // The receiver can not send before the first message
+        assert!(bob.encrypt_ephemeral_he(b"typing", AD, 7).is_none());
+    }
+}
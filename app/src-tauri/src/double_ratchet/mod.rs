@@ -1,3 +1,4 @@
 pub mod double_ratchet;
 pub mod state;
-pub mod aead;
\ No newline at end of file
+pub mod aead;
+pub mod padding;
\ No newline at end of file
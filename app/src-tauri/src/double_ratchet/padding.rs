@@ -0,0 +1,117 @@
+//! Padding of the plaintexts encrypted with the double ratchet, so the length of a ciphertext does not reveal the length of the message
+//!
+//! A padded plaintext starts with the version of the padding scheme. The plaintexts sent before the padding are UTF-8 text or JSON,
+//! which never start with a UTF-8 continuation byte *(0x80 to 0xBF)*: they are returned unchanged.
+
+const PADDING_V1: u8 = 0x81;
+const PADDING_MARKER: u8 = 0x80; // Followed by zeros *(ISO/IEC 7816-4)*
+const MIN_PADDED_LEN: usize = 128; // Most messages are short and share this size
+
+/// Pad the plaintext before its encryption *(version 1: at least 128 bytes, then Padmé)*
+///
+/// # Arguments
+///
+/// * `plaintext` (&\[u8\]): Plaintext
+///
+/// # Output
+///
+/// * `padded` (Vec\<u8\>): Version, plaintext, marker and zeros
+pub fn pad(plaintext: &[u8]) -> Vec<u8> {
+    let len: usize = padded_len(1 + plaintext.len() + 1);
+    let mut padded: Vec<u8> = Vec::with_capacity(len);
+    padded.push(PADDING_V1);
+    padded.extend_from_slice(plaintext);
+    padded.push(PADDING_MARKER);
+    padded.resize(len, 0);
+    padded
+}
+
+/// Remove the padding after the decryption
+///
+/// # Arguments
+///
+/// * `padded` (Vec\<u8\>): Decrypted plaintext
+///
+/// # Output
+///
+/// * `plaintext` (Vec\<u8\>): Plaintext, unchanged if it was not padded
+pub fn unpad(padded: Vec<u8>) -> Vec<u8> {
+    if padded.first() != Some(&PADDING_V1) {
+        return padded
+    }
+    match padded.iter().rposition(|byte| *byte != 0) {
+        Some(marker) if marker > 0 && padded[marker] == PADDING_MARKER => padded[1..marker].to_vec(),
+        _ => padded,
+    }
+}
+
+/// Padmé length of `len` bytes *(at most 12% of overhead, leaks O(log log len) bits of the length)*
+fn padded_len(len: usize) -> usize {
+    let len: usize = len.max(MIN_PADDED_LEN);
+    let e: u32 = usize::BITS - 1 - len.leading_zeros(); // floor(log2(len))
+    let s: u32 = u32::BITS - e.leading_zeros(); // floor(log2(e)) + 1
+    let mask: usize = (1 << (e - s)) - 1;
+    (len + mask) & !mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::double_ratchet::double_ratchet::DoubleRatchetHE;
+    use std::collections::BTreeSet;
+    use x25519_dalek::{PublicKey, StaticSecret};
+
+    const AD: &[u8] = b"alice and bob";
+
+    fn sessions() -> (DoubleRatchetHE, DoubleRatchetHE) {
+        let bob_private_key: StaticSecret = StaticSecret::from([1; 32]);
+        let bob_public_key: PublicKey = PublicKey::from(&bob_private_key);
+        let mut alice: DoubleRatchetHE = DoubleRatchetHE::new();
+        let mut bob: DoubleRatchetHE = DoubleRatchetHE::new();
+        alice.init_sender_he([2; 32], bob_public_key, [3; 32], [4; 32]);
+        bob.init_receiver_he([2; 32], (bob_private_key, bob_public_key), [3; 32], [4; 32]);
+        (alice, bob)
+    }
+
+    #[test]
+    fn ciphertext_sizes_in_buckets() {
+        let (mut alice, _) = sessions();
+        let mut sizes: BTreeSet<usize> = BTreeSet::new();
+        for len in (0..=4000).step_by(20) {
+            let (_, (ciphertext, _)) = alice.encrypt_he(&vec![b'a'; len], AD);
+            let size: usize = ciphertext.len() - 16; // AES-GCM-SIV tag
+            assert!(size <= (len + 2).max(MIN_PADDED_LEN) * 112 / 100);
+            sizes.insert(size);
+        }
+        // 201 lengths, 68 sizes
+        assert_eq!(sizes.len(), 68);
+        assert_eq!(sizes.first(), Some(&MIN_PADDED_LEN));
+        for size in sizes {
+            assert_eq!(padded_len(size), size);
+        }
+        // All the short messages have the same size
+        let (_, (hello, _)) = alice.encrypt_he(b"hello", AD);
+        let (_, (longer, _)) = alice.encrypt_he(&[b'a'; 120], AD);
+        assert_eq!(hello.len(), longer.len());
+    }
+
+    #[test]
+    fn padding_round_trip() {
+        for plaintext in [&b""[..], b"hello", &[0; 300], &[PADDING_MARKER, 0, 0], &[PADDING_V1; 127]] {
+            let padded: Vec<u8> = pad(plaintext);
+            assert_eq!(padded.len(), padded_len(padded.len()));
+            assert_eq!(unpad(padded), plaintext);
+        }
+    }
+
+    #[test]
+    fn decrypt_padded_and_unpadded() {
+        let (mut alice, mut bob) = sessions();
+        let (enc_header, (ciphertext, nonce)) = alice.encrypt_he(br#"{"text":"hello"}"#, AD);
+        assert_eq!(bob.decrypt_he(enc_header, ciphertext, nonce, AD).unwrap(), br#"{"text":"hello"}"#);
+
+        // Sent by the clients written before the padding
+        assert_eq!(unpad(br#"{"text":"hello"}"#.to_vec()), br#"{"text":"hello"}"#);
+        assert_eq!(unpad("élan".as_bytes().to_vec()), "élan".as_bytes());
+    }
+}
@@ -7,23 +7,32 @@ mod communication;
 mod double_ratchet;
 mod x3dh;
 mod database;
+#[cfg(feature = "opaque")]
+mod opaque;
 
 use std::env;
 use lazy_static::lazy_static;
 use tauri::Manager;
 use serde::{Deserialize, Serialize};
 use hash::get_hash;
-use tcp_client::{MiniSignalClient, Action, ServerResponse};
+use mini_signal_protocol::{normalize_username, validate_username, Action, Capability, Envelope, ErrorCode, Response, SenderCertificate};
+use tcp_client::{MiniSignalClient, ClientError};
+use futures_util::StreamExt;
+use tokio_tungstenite::tungstenite::Message as PushMessage;
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 use ed25519_dalek::ed25519::SignatureBytes;
 use ed25519_dalek::{Signature, VerifyingKey};
 use once_cell::sync::Lazy;
 use x25519_dalek::PublicKey;
 use crate::communication::client::Client;
+use crate::communication::content::{Content, ReceiptKind, Typing};
+use crate::communication::sealed_sender::{derive_access_key, generate_profile_key, seal, unseal, SealedSender};
 use crate::communication::key_collection::ServerKeyCollection;
 use crate::communication::message::{Ciphertext, HeaderHE, Message};
+use crate::database::{double_ratchet_database, message_database};
 use crate::database::double_ratchet_database::DoubleRatchetDatabase;
-use crate::database::message_database::MessageDatabase;
+use crate::database::message_database::{MessageDatabase, MessageStatus};
 
 // run the following command to avoid the app to reload when interacting with the database: cargo tauri dev --no-watch
 // (probably because we modify MESSAGE_DATABASE that make the app to reload when it's on the dev mode)
@@ -47,6 +56,10 @@ static DOUBLE_RATCHET_DATABASE: Lazy<Arc<Mutex<Option<DoubleRatchetDatabase>>>>
 
 static DOUBLE_RATCHET_CLIENT: Mutex<Option<Client>> = Mutex::new(None);
 
+static SEALED_SENDER: Mutex<Option<SealedSender>> = Mutex::new(None); // None if the server does not support sealed sender
+
+static UNREADABLE_MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new()); // GUIDs of the messages that could not be decrypted, reported once
+
 fn initialize_database(username: &str) {
     let mut message_database = MESSAGE_DATABASE.lock().unwrap();
     if message_database.is_none() {
@@ -60,8 +73,13 @@ fn initialize_database(username: &str) {
 
 // TODO create a get client result (remove code duplication)
 
-#[tauri::command]
-async fn verify_credential(username: &str, password: &str) -> Result<bool, String> {
+/// Log in to the server and store the session token
+///
+/// # Output
+///
+/// * `success` (Result\<bool, String\>): `false` if the credentials are not valid
+#[cfg(not(feature = "opaque"))]
+async fn log_in(username: &str, password: &str) -> Result<bool, String> {
     let post_info = TCP_CLIENT.post(Action::LogIn {
         username: username.to_string(),
         password: get_hash(&password.to_string()),
@@ -70,90 +88,281 @@ async fn verify_credential(username: &str, password: &str) -> Result<bool, Strin
     match post_info {
         Ok(info) => {
             match TCP_CLIENT.get_result(info).await {
-                Ok(ServerResponse::ResponseStatus { success }) => {
-                    if success {
-                        initialize_database(username);
-                        let mut double_ratchet_database_guard = DOUBLE_RATCHET_DATABASE.lock().unwrap();
-                        if let Some(mut double_ratchet_database) = double_ratchet_database_guard.take() {
-                            let current_client: Client = double_ratchet_database.load_client(username)
-                                .expect("Double ratchet collection raised an error");
-
-                            *DOUBLE_RATCHET_CLIENT.lock().unwrap() = Some(current_client);
-                            *double_ratchet_database_guard = Some(double_ratchet_database);
-                        } else {
-                            // Should not happen, because database is initialized when log in
-                            return Err("Double ratchet database not initialized".to_string());
-                        }
-                    }
-                    Ok(success)
+                Ok(Response::Session { token, expires_at: _ }) => {
+                    TCP_CLIENT.set_session_token(Some(token));
+                    Ok(true)
                 },
+                Err(ClientError::Server(ErrorCode::InvalidCredentials, _)) => Ok(false),
                 Err(error) => Err(format!("Error during login: {}", error)),
-                _ => Ok(false),
+                Ok(server_response) => Err(format!("Error during login (bad server response): {:?}", server_response)),
             }
         },
         Err(error) => Err(format!("Error during login (post_info): {}", error)),
     }
 }
 
-#[tauri::command]
-async fn register(username: &str, password: &str) -> Result<bool, String> {
-    let post_info = TCP_CLIENT.post(Action::NewUser {
+/// Log in to the server with OPAQUE *(the password never leaves the client)* and store the session token
+///
+/// # Output
+///
+/// * `success` (Result\<bool, String\>): `false` if the credentials are not valid
+#[cfg(feature = "opaque")]
+async fn log_in(username: &str, password: &str) -> Result<bool, String> {
+    let password_hash: String = get_hash(&password.to_string());
+    let (login_state, credential_request) = opaque::login_start(password_hash.as_bytes())
+        .map_err(|error| format!("Error during login (OPAQUE): {:?}", error))?;
+
+    let post_info = TCP_CLIENT.post(Action::OpaqueLogInStart {
+        username: username.to_string(),
+        credential_request: credential_request,
+    }).await.map_err(|error| format!("Error during login (post_info): {}", error))?;
+
+    let (login_id, credential_response) = match TCP_CLIENT.get_result(post_info).await {
+        Ok(Response::OpaqueLogIn { login_id, credential_response }) => (login_id, credential_response),
+        Err(error) => return Err(format!("Error during login: {}", error)),
+        Ok(server_response) => return Err(format!("Error during login (bad server response): {:?}", server_response)),
+    };
+
+    // Fails if the password is wrong (the server cannot tell the difference with a user that does not exist)
+    let credential_finalization: Vec<u8> = match opaque::login_finish(login_state, password_hash.as_bytes(), &credential_response) {
+        Ok((credential_finalization, _export_key)) => credential_finalization,
+        Err(_) => return Ok(false),
+    };
+
+    let post_info = TCP_CLIENT.post(Action::OpaqueLogInFinish {
+        login_id: login_id,
+        credential_finalization: credential_finalization,
+    }).await.map_err(|error| format!("Error during login (post_info): {}", error))?;
+
+    match TCP_CLIENT.get_result(post_info).await {
+        Ok(Response::Session { token, expires_at: _ }) => {
+            TCP_CLIENT.set_session_token(Some(token));
+            Ok(true)
+        },
+        Err(ClientError::Server(ErrorCode::InvalidCredentials, _)) => Ok(false),
+        Err(error) => Err(format!("Error during login: {}", error)),
+        Ok(server_response) => Err(format!("Error during login (bad server response): {:?}", server_response)),
+    }
+}
+
+/// Create the account on the server and publish the X3DH keys at once
+///
+/// # Output
+///
+/// * `success` (Result\<bool, String\>): `false` if the username is already used
+#[cfg(not(feature = "opaque"))]
+async fn create_account(username: &str, password: &str, key_collection_for_server: &ServerKeyCollection) -> Result<bool, String> {
+    let post_info = TCP_CLIENT.post(Action::Register {
         username: username.to_string(),
         password: get_hash(&password.to_string()),
+        ik: key_collection_for_server.get_ik().to_bytes(),
+        spk: key_collection_for_server.get_spk().to_bytes(),
+        opk_bundle: key_collection_for_server.get_opk_bundle_bytes(),
+        signature: key_collection_for_server.get_signature_to_bytes(),
+        verifying_key: key_collection_for_server.get_verifying_key().to_bytes(),
     }).await;
 
     match post_info {
         Ok(info) => {
             match TCP_CLIENT.get_result(info).await {
-                Ok(ServerResponse::ResponseStatus { success }) => {
-                    if success {
-                        // TODO create a client database protected by the same password to enter to the server
-                        let _ = TCP_CLIENT.post(Action::LogIn { // Need to be log in to send the X3DH information
-                            username: username.to_string(),
-                            password: get_hash(&password.to_string()),
-                        }).await; // TODO can handle if the login work or not
-
-                        // TODO the X3DH can be better handle (for example if the user is well register but the X3DH fail, the user will never be able to register
-                        let mut current_client: Client = Client::new(username.to_string());
-                        let key_collection_for_server: ServerKeyCollection = current_client.get_server_keys();
-                        let post_x3dh_info = TCP_CLIENT.post(Action::PublishX3DHInformation {
-                            ik: key_collection_for_server.get_ik().to_bytes(),
-                            spk: key_collection_for_server.get_spk().to_bytes(),
-                            opk_bundle: key_collection_for_server.get_opk_bundle_bytes(),
-                            signature: key_collection_for_server.get_signature_to_bytes(),
-                            verifying_key: key_collection_for_server.get_verifying_key().to_bytes() }).await;
-
-                        let x3dh_res = match post_x3dh_info {
-                            Ok(info) => {
-                                match TCP_CLIENT.get_result(info).await {
-                                    Ok(ServerResponse::ResponseStatus { success }) => {
-                                        // Store the client information on the client side
-                                        let mut temp_double_ratchet_database: DoubleRatchetDatabase = DoubleRatchetDatabase::new(username).unwrap();
-                                        temp_double_ratchet_database.insert_client(current_client).expect("Error when inserting a new client");
-
-                                        Ok(success)
-                                    },
-                                    Err(error) => Err(format!("Error during X3DH publication: {}", error)),
-                                    Ok(test) => Ok(false),
-                                }
-                            },
-                            Err(error) => Err(format!("Error during X3DH publication: {}", error)),
-                        };
-                        let _ = TCP_CLIENT.post(Action::LogOut).await; // TODO can handle if the logout work or not
-                        if x3dh_res.is_ok() {
-                            return Ok(x3dh_res.unwrap())
-                        }
-                    }
-                    Ok(success)
-                },
+                Ok(Response::ResponseStatus { success }) => Ok(success),
+                Err(ClientError::Server(ErrorCode::UserExists, _)) => Ok(false),
                 Err(error) => Err(format!("Error during register: {}", error)),
-                _ => Ok(false),
+                Ok(server_response) => Err(format!("Error during register (bad server response): {:?}", server_response)),
             }
         },
         Err(error) => Err(format!("Error during register (post_info): {}", error)),
     }
 }
 
+/// Create the account on the server with OPAQUE *(the server never learns the password)* and publish the X3DH keys at once
+///
+/// # Output
+///
+/// * `success` (Result\<bool, String\>): `false` if the username is already used
+#[cfg(feature = "opaque")]
+async fn create_account(username: &str, password: &str, key_collection_for_server: &ServerKeyCollection) -> Result<bool, String> {
+    let password_hash: String = get_hash(&password.to_string());
+    let (registration_state, registration_request) = opaque::registration_start(password_hash.as_bytes())
+        .map_err(|error| format!("Error during register (OPAQUE): {:?}", error))?;
+
+    let post_info = TCP_CLIENT.post(Action::OpaqueRegisterStart {
+        username: username.to_string(),
+        registration_request: registration_request,
+    }).await.map_err(|error| format!("Error during register (post_info): {}", error))?;
+
+    let registration_response: Vec<u8> = match TCP_CLIENT.get_result(post_info).await {
+        Ok(Response::OpaqueRegistration { registration_response }) => registration_response,
+        Err(ClientError::Server(ErrorCode::UserExists, _)) => return Ok(false),
+        Err(error) => return Err(format!("Error during register: {}", error)),
+        Ok(server_response) => return Err(format!("Error during register (bad server response): {:?}", server_response)),
+    };
+
+    let (registration_upload, _export_key) = opaque::registration_finish(registration_state, password_hash.as_bytes(), &registration_response)
+        .map_err(|error| format!("Error during register (OPAQUE): {:?}", error))?;
+
+    let post_info = TCP_CLIENT.post(Action::OpaqueRegisterFinish {
+        username: username.to_string(),
+        registration_upload: registration_upload,
+        ik: key_collection_for_server.get_ik().to_bytes(),
+        spk: key_collection_for_server.get_spk().to_bytes(),
+        opk_bundle: key_collection_for_server.get_opk_bundle_bytes(),
+        signature: key_collection_for_server.get_signature_to_bytes(),
+        verifying_key: key_collection_for_server.get_verifying_key().to_bytes(),
+    }).await.map_err(|error| format!("Error during register (post_info): {}", error))?;
+
+    match TCP_CLIENT.get_result(post_info).await {
+        Ok(Response::ResponseStatus { success }) => Ok(success),
+        Err(ClientError::Server(ErrorCode::UserExists, _)) => Ok(false),
+        Err(error) => Err(format!("Error during register: {}", error)),
+        Ok(server_response) => Err(format!("Error during register (bad server response): {:?}", server_response)),
+    }
+}
+
+/// Publish new X3DH keys for a user registered without keys *(recovery of a registration done with NewUser)*
+///
+/// The keys are stored on the client side only if the server accepted them.
+async fn publish_x3dh_keys(username: &str, double_ratchet_database: &mut DoubleRatchetDatabase) -> Result<(), String> {
+    let current_client: Client = Client::new(username.to_string());
+    let key_collection_for_server: ServerKeyCollection = current_client.get_server_keys();
+    let post_x3dh_info = TCP_CLIENT.post(Action::PublishX3DHInformation {
+        ik: key_collection_for_server.get_ik().to_bytes(),
+        spk: key_collection_for_server.get_spk().to_bytes(),
+        opk_bundle: key_collection_for_server.get_opk_bundle_bytes(),
+        signature: key_collection_for_server.get_signature_to_bytes(),
+        verifying_key: key_collection_for_server.get_verifying_key().to_bytes() }).await;
+
+    match post_x3dh_info {
+        Ok(info) => {
+            match TCP_CLIENT.get_result(info).await {
+                Ok(Response::ResponseStatus { success: true }) => {
+                    double_ratchet_database.insert_client(current_client).expect("Error when inserting a new client");
+                    Ok(())
+                },
+                Err(ClientError::Server(ErrorCode::KeysAlreadyPublished, _)) => Err("The X3DH keys of this account are not stored on this device".to_string()),
+                Err(error) => Err(format!("Error during X3DH publication: {}", error)),
+                Ok(server_response) => Err(format!("Error during X3DH publication (bad server response): {:?}", server_response)),
+            }
+        },
+        Err(error) => Err(format!("Error during X3DH publication (post_info): {}", error)),
+    }
+}
+
+/// Log in and load the sessions of the user
+///
+/// # Output
+///
+/// * `username` (Result\<Option\<String\>, String\>): Canonical form of the username, None if the credentials are not valid
+#[tauri::command]
+async fn verify_credential(username: &str, password: &str) -> Result<Option<String>, String> {
+    let username: &str = &normalize_username(username);
+    TCP_CLIENT.hello().await.map_err(|error| format!("Error when connecting to the server: {}", error))?;
+    let success: bool = log_in(username, password).await?;
+
+    if success {
+        initialize_database(username);
+        let double_ratchet_database_option = DOUBLE_RATCHET_DATABASE.lock().unwrap().take();
+        if let Some(mut double_ratchet_database) = double_ratchet_database_option {
+            // Account created without X3DH keys (registration interrupted)
+            let keys_result: Result<(), String> = if double_ratchet_database.client_exist(username).unwrap() {
+                Ok(())
+            } else {
+                publish_x3dh_keys(username, &mut double_ratchet_database).await
+            };
+
+            if keys_result.is_ok() {
+                let current_client: Client = double_ratchet_database.load_client(username)
+                    .expect("Double ratchet collection raised an error");
+                *DOUBLE_RATCHET_CLIENT.lock().unwrap() = Some(current_client);
+            }
+            *DOUBLE_RATCHET_DATABASE.lock().unwrap() = Some(double_ratchet_database);
+            keys_result?;
+        } else {
+            // Should not happen, because database is initialized when log in
+            return Err("Double ratchet database not initialized".to_string());
+        }
+
+        if let Err(error) = enable_sealed_sender(username).await {
+            println!("{}", error); // The messages are sent identified
+        }
+    }
+    Ok(success.then(|| username.to_string()))
+}
+
+/// Publish the delivery access key of the user and get a sender certificate, to send and receive sealed messages
+///
+/// The profile key is generated at the first login *(also for the accounts created before sealed sender)*.
+async fn enable_sealed_sender(username: &str) -> Result<(), String> {
+    if !TCP_CLIENT.has_capability(Capability::SealedSender) {
+        return Ok(())
+    }
+
+    let profile_key: [u8; 32] = match DOUBLE_RATCHET_DATABASE.lock().unwrap().as_mut() {
+        Some(double_ratchet_database) => match double_ratchet_database.get_profile_key(username).expect("Profile key selection in database raised an error") {
+            Some(profile_key) => profile_key,
+            None => {
+                let profile_key: [u8; 32] = generate_profile_key();
+                double_ratchet_database.insert_profile_key(username, profile_key).expect("Profile key insertion in database raised an error");
+                profile_key
+            },
+        },
+        // Should not happen, because database is initialized when log in
+        None => return Err("Double ratchet database not initialized".to_string()),
+    };
+
+    let post_info = TCP_CLIENT.post(Action::SetDeliveryAccessKey { access_key: derive_access_key(&profile_key) }).await;
+    match post_info {
+        Ok(info) => {
+            match TCP_CLIENT.get_result(info).await {
+                Ok(Response::ResponseStatus { success: true }) => {},
+                Err(error) => return Err(format!("Error when setting the delivery access key: {}", error)),
+                Ok(server_response) => return Err(format!("Error when setting the delivery access key (bad server response): {:?}", server_response)),
+            }
+        },
+        Err(error) => return Err(format!("Error when setting the delivery access key (post_info): {}", error)),
+    }
+
+    let (certificate, server_key): (SenderCertificate, VerifyingKey) = get_sender_certificate().await?;
+    *SEALED_SENDER.lock().unwrap() = Some(SealedSender::new(profile_key, certificate, server_key));
+    Ok(())
+}
+
+async fn get_sender_certificate() -> Result<(SenderCertificate, VerifyingKey), String> {
+    let post_info = TCP_CLIENT.post(Action::GetSenderCertificate).await;
+
+    match post_info {
+        Ok(info) => {
+            match TCP_CLIENT.get_result(info).await {
+                Ok(Response::SenderCertificate { certificate, server_key }) => match VerifyingKey::from_bytes(&server_key) {
+                    Ok(server_key) => Ok((certificate, server_key)),
+                    Err(_) => Err("Error when collecting the sender certificate (invalid server key)".to_string()),
+                },
+                Err(error) => Err(format!("Error when collecting the sender certificate: {}", error)),
+                Ok(server_response) => Err(format!("Error when collecting the sender certificate (bad server response): {:?}", server_response)),
+            }
+        },
+        Err(error) => Err(format!("Error when collecting the sender certificate (post_info): {}", error)),
+    }
+}
+
+#[tauri::command]
+async fn register(username: &str, password: &str) -> Result<bool, String> {
+    let username: &str = &validate_username(username).map_err(|error| format!("{}", error))?;
+    TCP_CLIENT.hello().await.map_err(|error| format!("Error when connecting to the server: {}", error))?;
+    // TODO create a client database protected by the same password to enter to the server
+    let current_client: Client = Client::new(username.to_string());
+    let key_collection_for_server: ServerKeyCollection = current_client.get_server_keys();
+
+    let success: bool = create_account(username, password, &key_collection_for_server).await?;
+
+    if success {
+        // Store the client information on the client side
+        let mut temp_double_ratchet_database: DoubleRatchetDatabase = DoubleRatchetDatabase::new(username).unwrap();
+        temp_double_ratchet_database.insert_client(current_client).expect("Error when inserting a new client");
+    }
+    Ok(success)
+}
+
 #[tauri::command]
 async fn log_out() -> Result<(), String> {
     {
@@ -172,6 +381,8 @@ async fn log_out() -> Result<(), String> {
     }
 
     let result = TCP_CLIENT.post(Action::LogOut).await;
+    TCP_CLIENT.set_session_token(None);
+    *SEALED_SENDER.lock().unwrap() = None;
 
     match result {
         Ok(_) => Ok(()),
@@ -179,6 +390,70 @@ async fn log_out() -> Result<(), String> {
     }
 }
 
+#[tauri::command]
+async fn delete_account(username: &str) -> Result<bool, String> {
+    let username: &str = &normalize_username(username);
+    let post_info = TCP_CLIENT.post(Action::DeleteAccount).await;
+
+    let success: bool = match post_info {
+        Ok(info) => {
+            match TCP_CLIENT.get_result(info).await {
+                Ok(Response::ResponseStatus { success }) => success,
+                Err(error) => return Err(format!("Error when deleting the account: {}", error)),
+                Ok(server_response) => return Err(format!("Error when deleting the account (bad server response): {:?}", server_response)),
+            }
+        },
+        Err(error) => return Err(format!("Error when deleting the account (post_info): {}", error)),
+    };
+
+    if success {
+        TCP_CLIENT.set_session_token(None);
+        *SEALED_SENDER.lock().unwrap() = None;
+        // Close the local databases before removing them
+        *DOUBLE_RATCHET_CLIENT.lock().unwrap() = None;
+        *DOUBLE_RATCHET_DATABASE.lock().unwrap() = None;
+        *MESSAGE_DATABASE.lock().unwrap() = None;
+
+        for path in [double_ratchet_database::get_database_path(username), message_database::get_database_path(username)] {
+            match std::fs::remove_file(&path) {
+                Ok(()) => {},
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {},
+                Err(error) => return Err(format!("Error when deleting {}: {}", path, error)),
+            }
+        }
+    }
+
+    Ok(success)
+}
+
+/// Change the password of the current user *(every other session of the user is closed by the server)*
+///
+/// The credential sent to the server is re-derived from the new password.
+/// The local databases are not encrypted yet, so no other key material depends on the password.
+#[tauri::command]
+async fn change_password(old_password: &str, new_password: &str) -> Result<bool, String> {
+    if cfg!(feature = "opaque") {
+        return Err("Changing the password is not supported with OPAQUE yet".to_string());
+    }
+
+    let post_info = TCP_CLIENT.post(Action::ChangePassword {
+        old: get_hash(&old_password.to_string()),
+        new: get_hash(&new_password.to_string()),
+    }).await;
+
+    match post_info {
+        Ok(info) => {
+            match TCP_CLIENT.get_result(info).await {
+                Ok(Response::ResponseStatus { success }) => Ok(success),
+                Err(ClientError::Server(ErrorCode::InvalidCredentials, _)) => Ok(false),
+                Err(error) => Err(format!("Error when changing the password: {}", error)),
+                Ok(server_response) => Err(format!("Error when changing the password (bad server response): {:?}", server_response)),
+            }
+        },
+        Err(error) => Err(format!("Error when changing the password (post_info): {}", error)),
+    }
+}
+
 #[tauri::command]
 async fn get_all_users() -> Result<Vec<String>, String> {
     let post_info = TCP_CLIENT.post(Action::GetAllUsers).await;
@@ -186,7 +461,7 @@ async fn get_all_users() -> Result<Vec<String>, String> {
     match post_info {
         Ok(info) => {
             match TCP_CLIENT.get_result(info).await {
-                Ok(ServerResponse::UserList { result }) => Ok(result),
+                Ok(Response::UserList { result }) => Ok(result),
                 Err(error) => Err(format!("Error when collecting all the users: {}", error)),
                 Ok(server_response) => Err(format!("Error when collecting all the users (bad server response): {:?}", server_response)),
             }
@@ -195,45 +470,387 @@ async fn get_all_users() -> Result<Vec<String>, String> {
     }
 }
 
+/// Messages sent by the server, once decrypted *(see `read_server_messages`)*
+struct ServerMessages {
+    plaintext_messages: Vec<String>,
+    message_ids: Vec<i64>, // Ids of the messages to acknowledge
+    delivered: Vec<(String, Vec<String>)>, // (sender, GUIDs) of the delivery receipts to send
+    status_updates: Vec<(String, MessageStatus)>, // (GUID, status) of the messages sent, changed by the receipts received
+    unreadable: Vec<String>, // Errors of the messages that could not be decrypted, not reported yet
+}
+
+/// Decrypt the messages sent by the server *(`GetMessages` or push)* and store them in the message database
+///
+/// The receipts are applied to the messages sent, and are not shown. A message that can not be decrypted is not acknowledged:
+/// the session is not changed, and the server expires the message *(the sender is told)*.
+///
+/// # Arguments
+///
+/// * `username_receiver` (&str): Username of the current user
+/// * `messages` (Vec\<Envelope\>): Messages of the server response
+///
+/// # Output
+///
+/// * `server_messages` (ServerMessages): Decrypted messages, ids to acknowledge and receipts
+fn read_server_messages(username_receiver: &str, mut messages: Vec<Envelope>) -> ServerMessages {
+    // Decrypted in the order the server received them
+    messages.sort_by_key(|message| message.seq);
+    let mut double_ratchet_client_guard = DOUBLE_RATCHET_CLIENT.lock().unwrap();
+    let mut plaintext_messages: Vec<String> = Vec::new();
+    let mut message_ids: Vec<i64> = Vec::new();
+    let mut profile_keys: Vec<(String, [u8; 32])> = Vec::new();
+    let mut delivered: Vec<(String, Vec<String>)> = Vec::new();
+    let mut status_updates: Vec<(String, MessageStatus)> = Vec::new();
+    let mut unreadable: Vec<String> = Vec::new();
+    { // Acquire the lock
+        for message in messages {
+            let message_id: i64 = message.message_id;
+            if is_message_received(&message.guid) {
+                message_ids.push(message_id);
+                continue; // Already read (pushed and collected, or the acknowledgement was lost)
+            }
+
+            let (username_sender, content): (String, Content) = match open_envelope(double_ratchet_client_guard.as_mut().unwrap(), &message, false) {
+                Ok(opened) => opened,
+                Err(error) => {
+                    let mut unreadable_guard = UNREADABLE_MESSAGES.lock().unwrap();
+                    if !unreadable_guard.contains(&message.guid) {
+                        unreadable_guard.push(message.guid.clone());
+                        unreadable.push(format!("Message {} {}", message.guid, error));
+                    }
+                    continue;
+                },
+            };
+            message_ids.push(message_id);
+
+            if let Some(profile_key) = content.profile_key {
+                profile_keys.push((username_sender.clone(), profile_key));
+            }
+
+            if let Some(receipt) = content.receipt {
+                let status: MessageStatus = match receipt.kind {
+                    ReceiptKind::Delivered => MessageStatus::Delivered,
+                    ReceiptKind::Read => MessageStatus::Read,
+                };
+                if let Some(message_database) = MESSAGE_DATABASE.lock().unwrap().as_mut() {
+                    let updated_guids: Vec<String> = message_database.update_status(username_receiver, &username_sender, &receipt.guids, status)
+                        .expect("Message update in database raised an error");
+                    status_updates.extend(updated_guids.into_iter().map(|guid| (guid, status)));
+                    message_database.insert_control_message(&message.guid, username_receiver, message.seq)
+                        .expect("Control message insertion in database raised an error");
+                }
+                continue;
+            }
+
+            plaintext_messages.push(content.text.clone());
+            store_message_in_database(&username_sender, username_receiver, &content.text, Some(message_id), Some(&message.guid), Some(message.received_at), Some(message.seq), MessageStatus::Delivered).expect("Error when inserting information in the message database");
+            match delivered.iter_mut().find(|(sender, _)| *sender == username_sender) {
+                Some((_, guids)) => guids.push(message.guid),
+                None => delivered.push((username_sender, vec![message.guid])),
+            }
+        }
+    } // Release the lock
+    drop(double_ratchet_client_guard);
+
+    // Profile keys of the senders, to answer them with sealed messages
+    if let Some(double_ratchet_database) = DOUBLE_RATCHET_DATABASE.lock().unwrap().as_mut() {
+        for (username_sender, profile_key) in profile_keys {
+            double_ratchet_database.insert_profile_key(&username_sender, profile_key).expect("Profile key insertion in database raised an error");
+        }
+    }
+
+    ServerMessages { plaintext_messages, message_ids, delivered, status_updates, unreadable }
+}
+
+/// Send the delivery receipts of the messages read *(a receipt that can not be sent is lost)*
+///
+/// # Arguments
+///
+/// * `delivered` (Vec\<(String, Vec\<String\>)\>): (sender, GUIDs) of the messages
+async fn send_delivery_receipts(delivered: Vec<(String, Vec<String>)>) {
+    for (username_sender, guids) in delivered {
+        if let Err(error) = send_content(&username_sender, Content::new_receipt(ReceiptKind::Delivered, guids, None)).await {
+            println!("Delivery receipt to {} not sent: {}", username_sender, error);
+        }
+    }
+}
+
+/// Mark the messages received from `username_sender` as read, and send the read receipt *(unless disabled in the settings)*
+///
+/// Called when the conversation is shown.
+#[tauri::command]
+async fn mark_as_read(username_receiver: &str, username_sender: &str) -> Result<(), String> {
+    let username_receiver: &str = &normalize_username(username_receiver);
+    let username_sender: &str = &normalize_username(username_sender);
+
+    let (guids, read_receipts): (Vec<String>, bool) = match MESSAGE_DATABASE.lock().unwrap().as_mut() {
+        Some(message_database) => (
+            message_database.mark_read(username_sender, username_receiver).expect("Message update in database raised an error"),
+            message_database.get_read_receipts().expect("Settings selection in database raised an error"),
+        ),
+        // Should not happen, because database is initialized when log in
+        None => return Err("Database not initialized".to_string()),
+    };
+
+    if read_receipts && !guids.is_empty() {
+        send_content(username_sender, Content::new_receipt(ReceiptKind::Read, guids, None)).await?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+async fn get_read_receipts() -> Result<bool, String> {
+    match MESSAGE_DATABASE.lock().unwrap().as_ref() {
+        Some(message_database) => Ok(message_database.get_read_receipts().expect("Settings selection in database raised an error")),
+        // Should not happen, because database is initialized when log in
+        None => Err("Database not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+async fn set_read_receipts(enabled: bool) -> Result<(), String> {
+    match MESSAGE_DATABASE.lock().unwrap().as_mut() {
+        Some(message_database) => Ok(message_database.set_read_receipts(enabled).expect("Settings update in database raised an error")),
+        // Should not happen, because database is initialized when log in
+        None => Err("Database not initialized".to_string()),
+    }
+}
+
+/// Find the sender of a message *(in the sealed sender certificate if the message is sealed)* and decrypt it with the double ratchet session
+///
+/// # Arguments
+///
+/// * `double_ratchet_client` (&mut Client): Double ratchet sessions of the user
+/// * `message` (&Envelope): Message sent by the server
+/// * `ephemeral` (bool): Ephemeral message, read without moving the double ratchet forward
+///
+/// # Output
+///
+/// * `(username_sender, content)` (Result\<(String, Content), String\>): Sender and plaintext of the message
+fn open_envelope(double_ratchet_client: &mut Client, message: &Envelope, ephemeral: bool) -> Result<(String, Content), String> {
+    // The sender of a sealed message is in the certificate, sealed to the Identity Key of the user
+    let (username_sender, current_ik_sender): (String, Option<PublicKey>) = match (&message.sender, &message.sealed_sender) {
+        (Some(username_sender), _) => (username_sender.clone(), message.ik_sender.map(PublicKey::from)),
+        (None, Some(sealed_sender)) => {
+            let ik = double_ratchet_client.get_keys().get_ik();
+            let unsealed = match SEALED_SENDER.lock().unwrap().as_ref() {
+                Some(sealed_sender_keys) => unseal(sealed_sender, &ik, sealed_sender_keys.get_server_key(), message.received_at).map_err(|error| error.to_string()),
+                None => Err("sealed sender is not enabled".to_string()),
+            };
+            match unsealed {
+                Ok(certificate) => (certificate.sender, Some(PublicKey::from(certificate.ik))),
+                Err(error) => return Err(format!("can not be unsealed: {}", error)),
+            }
+        },
+        (None, None) => return Err("has no sender".to_string()),
+    };
+
+    let current_ek = message.ek_sender.map(PublicKey::from);
+    let current_opk = message.opk_used.map(PublicKey::from);
+    let current_message: Message = Message::new(HeaderHE::new(message.header_encrypted.clone(), message.header_nonce.clone()), Ciphertext::new(message.ciphertext.clone(), message.nonce.clone()), current_ek, current_opk);
+
+    if ephemeral {
+        return match double_ratchet_client.read_ephemeral_message(&username_sender, &current_message) {
+            Some(plaintext) => Ok((username_sender, Content::from_bytes(&plaintext))),
+            None => Err("can not be decrypted (no session, forged or replayed message)".to_string()),
+        }
+    }
+
+    match double_ratchet_client.read_messages(&username_sender, current_ik_sender, vec![current_message]) {
+        Ok(plaintexts) => Ok((username_sender, Content::from_bytes(plaintexts.get(0).unwrap()))),
+        Err(error) => Err(format!("can not be decrypted: {}", error)),
+    }
+}
+
+/// Decrypt an ephemeral message pushed by the server *(not stored nor acknowledged)*
+///
+/// # Output
+///
+/// * `typing` (Option\<(String, Typing)\>): Sender and typing indicator, None if the message can not be read
+fn read_ephemeral_message(message: Envelope) -> Option<(String, Typing)> {
+    let opened = open_envelope(DOUBLE_RATCHET_CLIENT.lock().unwrap().as_mut()?, &message, true);
+    match opened {
+        Ok((username_sender, Content { typing: Some(typing), .. })) => Some((username_sender, typing)),
+        Ok(_) => None, // Ephemeral content of a newer client
+        Err(error) => {
+            println!("Ephemeral message {} {}", message.guid, error);
+            None
+        },
+    }
+}
+
+/// Send a typing indicator *(ephemeral message, dropped by the server if the receiver is not connected)*
 #[tauri::command]
-async fn get_messages(username_receiver: &str) -> Result<Option<Vec<String>>, String> {
-    let post_info = TCP_CLIENT.post(Action::GetMessages).await;
+async fn send_typing(username_receiver: &str, started: bool) -> Result<(), String> {
+    let username_receiver: &str = &normalize_username(username_receiver);
+    if !TCP_CLIENT.has_capability(Capability::Ephemeral) {
+        return Ok(())
+    }
+    // The first message of a session carries the X3DH keys, it must not be dropped
+    let has_session: bool = DOUBLE_RATCHET_CLIENT.lock().unwrap().as_ref()
+        .map_or(false, |double_ratchet_client| double_ratchet_client.get_session_ik(username_receiver).is_some());
+    if !has_session {
+        return Ok(())
+    }
+
+    let typing: Typing = if started { Typing::Started } else { Typing::Stopped };
+    match post_content(username_receiver, Content::new_typing(typing), true).await? {
+        Response::ResponseStatus { .. } => Ok(()), // false if the receiver is not connected
+        server_response => Err(format!("Error when sending the typing indicator (bad server response): {:?}", server_response)),
+    }
+}
+
+/// Acknowledge the messages received, so the server deletes them
+///
+/// # Arguments
+///
+/// * `message_ids` (Vec\<i64\>): Ids of the messages read or stored in the message database
+async fn acknowledge_messages(message_ids: Vec<i64>) -> Result<(), String> {
+    let post_info = TCP_CLIENT.post(Action::AckMessages { ids: message_ids }).await;
 
     match post_info {
         Ok(info) => {
             match TCP_CLIENT.get_result(info).await {
-                Ok(ServerResponse::Messages { success, new_messages, messages }) => {
-                    if success && new_messages {
-                        let mut double_ratchet_client_guard = DOUBLE_RATCHET_CLIENT.lock().unwrap();
-                        let mut plaintext_messages: Vec<String> = Vec::new();
-                        { // Acquire the lock
-                            for message in messages.unwrap() {
-                                let current_ek = message.5.map(PublicKey::from);
-                                let current_opk = message.6.map(PublicKey::from);
-                                let current_ik_sender = message.7.map(PublicKey::from);
-                                let current_message: Message = Message::new(HeaderHE::new(message.1, message.2), Ciphertext::new(message.3, message.4), current_ek, current_opk);
-
-                                let double_ratchet_res = double_ratchet_client_guard.as_mut().unwrap().read_messages(&message.0, current_ik_sender, vec![current_message]);
-
-                                let temp = double_ratchet_res.unwrap();
-
-                                let current_plaintext_message: &Vec<u8> = temp.get(0).unwrap();
-                                plaintext_messages.push(String::from_utf8_lossy(&current_plaintext_message).to_string());
-                                // https://stackoverflow.com/questions/41034635/how-do-i-convert-between-string-str-vecu8-and-u8 (Good explanation for every kind of str conversion)
-                                store_message_in_database(&message.0, username_receiver, std::str::from_utf8(current_plaintext_message).unwrap()).expect("Error when inserting information in the message database");
-                            }
-                        } // Release the lock
-
-                        return Ok(Some(plaintext_messages))
-                    }
-                    Ok(None)
-                },
-                Err(error) => Err(format!("Error when collecting messages: {}", error)),
-                Ok(server_response) => Err(format!("Error when collection messages (bad server response): {:?}", server_response)),
+                Ok(Response::ResponseStatus { success: true }) => Ok(()),
+                Err(error) => Err(format!("Error when acknowledging the messages: {}", error)),
+                Ok(server_response) => Err(format!("Error when acknowledging the messages (bad server response): {:?}", server_response)),
             }
         },
-        Err(error) => Err(format!("Error when collection all the messages (post_info): {}", error)),
+        Err(error) => Err(format!("Error when acknowledging the messages (post_info): {}", error)),
+    }
+}
+
+/// Listen to the messages pushed by the server and emit them to the window *("new_messages" event, "message_status" for the receipts, "typing" and "messages_unreadable")*
+///
+/// Returns once the connection is open. The "push_closed" event is emitted when the connection is lost *(the window falls back to `get_messages`)*.
+#[tauri::command]
+async fn listen_messages(window: tauri::Window, username_receiver: &str) -> Result<(), String> {
+    let username_receiver: String = normalize_username(username_receiver);
+    if !TCP_CLIENT.has_capability(Capability::Push) {
+        return Err("The server does not push the messages".to_string());
     }
+    let mut push_stream = TCP_CLIENT.connect_push().await
+        .map_err(|error| format!("Error when opening the push connection: {}", error))?;
+
+    tauri::async_runtime::spawn(async move {
+        while let Some(Ok(frame)) = push_stream.next().await {
+            if let PushMessage::Text(payload) = frame {
+                match serde_json::from_str::<Response>(&payload) {
+                    Ok(Response::Messages { success: true, new_messages: true, messages: Some(messages), .. }) => {
+                        let server_messages: ServerMessages = read_server_messages(&username_receiver, messages);
+                        if !server_messages.plaintext_messages.is_empty() {
+                            window.emit("new_messages", server_messages.plaintext_messages).expect("Error when emitting the new messages");
+                        }
+                        if !server_messages.status_updates.is_empty() {
+                            window.emit("message_status", server_messages.status_updates).expect("Error when emitting the message status");
+                        }
+                        if !server_messages.unreadable.is_empty() {
+                            window.emit("messages_unreadable", server_messages.unreadable).expect("Error when emitting the unreadable messages");
+                        }
+                        if let Err(error) = acknowledge_messages(server_messages.message_ids).await {
+                            println!("{}", error); // Sent again with the next GetMessages
+                        }
+                        send_delivery_receipts(server_messages.delivered).await;
+                    },
+                    Ok(Response::Ephemeral { message }) => {
+                        if let Some(typing) = read_ephemeral_message(message) {
+                            window.emit("typing", typing).expect("Error when emitting the typing indicator");
+                        }
+                    },
+                    _ => println!("Unexpected push payload: {}", payload),
+                }
+            }
+        }
+        let _ = window.emit("push_closed", ());
+    });
+    Ok(())
+}
+
+/// Ask the server for the messages sent that expired before being received, and mark them in the message database
+///
+/// # Output
+///
+/// * `expired_messages` (Result\<Vec\<(String, String)\>, String\>): (receiver, message) of the expired messages
+#[tauri::command]
+async fn get_expired_messages(username_sender: &str) -> Result<Vec<(String, String)>, String> {
+    let username_sender: &str = &normalize_username(username_sender);
+    let post_info = TCP_CLIENT.post(Action::GetExpiredMessages).await;
+
+    let message_ids: Vec<i64> = match post_info {
+        Ok(info) => {
+            match TCP_CLIENT.get_result(info).await {
+                Ok(Response::ExpiredMessages { message_ids }) => message_ids,
+                Err(error) => return Err(format!("Error when collecting the expired messages: {}", error)),
+                Ok(server_response) => return Err(format!("Error when collecting the expired messages (bad server response): {:?}", server_response)),
+            }
+        },
+        Err(error) => return Err(format!("Error when collecting the expired messages (post_info): {}", error)),
+    };
+
+    match MESSAGE_DATABASE.lock().unwrap().as_mut() {
+        Some(message_database) => Ok(message_database.mark_expired(username_sender, &message_ids)
+            .expect("Message update in database raised an error")),
+        // Should not happen, because database is initialized when log in
+        None => Err("Database not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+async fn get_messages(window: tauri::Window, username_receiver: &str) -> Result<Option<Vec<String>>, String> {
+    let username_receiver: &str = &normalize_username(username_receiver);
+    let mut plaintext_messages: Vec<String> = Vec::new();
+    let mut after: Option<i64> = None;
+
+    // Collect the pages until the server has no more messages
+    loop {
+        let post_info = TCP_CLIENT.post(Action::GetMessages { after: after, page_size: None }).await;
+
+        match post_info {
+            Ok(info) => {
+                match TCP_CLIENT.get_result(info).await {
+                    Ok(Response::Messages { success, new_messages, messages, has_more }) => {
+                        if !(success && new_messages) {
+                            break;
+                        }
+                        let messages = messages.unwrap();
+                        after = messages.last().map(|message| message.message_id);
+
+                        let server_messages: ServerMessages = read_server_messages(username_receiver, messages);
+                        plaintext_messages.extend(server_messages.plaintext_messages);
+                        if !server_messages.status_updates.is_empty() {
+                            window.emit("message_status", server_messages.status_updates).expect("Error when emitting the message status");
+                        }
+                        if !server_messages.unreadable.is_empty() {
+                            window.emit("messages_unreadable", server_messages.unreadable).expect("Error when emitting the unreadable messages");
+                        }
+                        acknowledge_messages(server_messages.message_ids).await?;
+                        send_delivery_receipts(server_messages.delivered).await;
+                        if !has_more {
+                            break;
+                        }
+                    },
+                    Err(error) => return Err(format!("Error when collecting messages: {}", error)),
+                    Ok(server_response) => return Err(format!("Error when collection messages (bad server response): {:?}", server_response)),
+                }
+            },
+            Err(error) => return Err(format!("Error when collection all the messages (post_info): {}", error)),
+        }
+    }
+
+    // The whole mailbox has been collected, a sequence number still missing is a message lost
+    let lost_messages: u64 = match MESSAGE_DATABASE.lock().unwrap().as_mut() {
+        Some(message_database) => message_database.find_lost_messages(username_receiver)
+            .expect("Message selection in database raised an error"),
+        None => 0,
+    };
+    if lost_messages > 0 {
+        window.emit("messages_lost", lost_messages).expect("Error when emitting the lost messages");
+    }
+
+    if plaintext_messages.is_empty() {
+        return Ok(None)
+    }
+    Ok(Some(plaintext_messages))
 }
 
 async fn get_user_public_key(username: &str) -> Result<ServerKeyCollection, String> {
@@ -242,7 +859,7 @@ async fn get_user_public_key(username: &str) -> Result<ServerKeyCollection, Stri
     match post_info {
         Ok(info) => {
             match TCP_CLIENT.get_result(info).await {
-                Ok(ServerResponse::UserPublicKeys { ik, spk, opk, signature, verifying_key }) => {
+                Ok(Response::UserPublicKeys { ik, spk, opk, signature, verifying_key }) => {
                     let mut merged_signature: [u8; 64] = [0; 64]; // Initialize with zeros or any default value
                     merged_signature[0..32].copy_from_slice(&signature[0]);
                     merged_signature[32..].copy_from_slice(&signature[1]);
@@ -263,19 +880,84 @@ async fn get_user_public_key(username: &str) -> Result<ServerKeyCollection, Stri
 }
 
 
+/// Store the message, send it and return its GUID *(the message is stored as failed if it can not be sent)*
 #[tauri::command]
-async fn send_message(username_sender: &str, username_receiver: &str, message: &str) -> Result<(), String> {
+async fn send_message(username_sender: &str, username_receiver: &str, message: &str) -> Result<String, String> {
+    let username_sender: &str = &normalize_username(username_sender);
+    let username_receiver: &str = &normalize_username(username_receiver);
+    let message_id: i64 = store_message_in_database(username_sender, username_receiver, message, None, None, None, None, MessageStatus::Sending)?;
+
+    let result: Result<(i64, String, u64), String> = send_content(username_receiver, Content::new(message.to_string(), None)).await;
+
+    match MESSAGE_DATABASE.lock().unwrap().as_mut() {
+        Some(message_database) => match result {
+            Ok((server_message_id, server_guid, server_timestamp)) => {
+                message_database.mark_sent(message_id, server_message_id, &server_guid, server_timestamp)
+                    .expect("Message update in database raised an error");
+                Ok(server_guid)
+            },
+            Err(error) => {
+                message_database.mark_failed(message_id).expect("Message update in database raised an error");
+                Err(error)
+            },
+        },
+        // Should not happen, because database is initialized when log in
+        None => Err("Database not initialized".to_string()),
+    }
+}
+
+/// Send the content *(message or receipt)* and return the id, GUID and reception time given by the server
+async fn send_content(username_receiver: &str, content: Content) -> Result<(i64, String, u64), String> {
+    match post_content(username_receiver, content, false).await? {
+        Response::MessageSent { message_id, guid, received_at } => Ok((message_id, guid, received_at)),
+        server_response => Err(format!("Error when collecting sending message status (bad server response): {:?}", server_response)),
+    }
+}
+
+/// Encrypt the content with the double ratchet session of the receiver and send it
+///
+/// The profile key of the user is added to the content. A receipt or a typing indicator is only sent over an existing session.
+///
+/// # Arguments
+///
+/// * `username_receiver` (&str): Username of the receiver
+/// * `content` (Content): Plaintext of the message
+/// * `ephemeral` (bool): Only pushed if the receiver is connected *(typing indicators)*
+///
+/// # Output
+///
+/// * `response` (Result\<Response, String\>): `MessageSent`, or `ResponseStatus` for an ephemeral message
+async fn post_content(username_receiver: &str, mut content: Content, ephemeral: bool) -> Result<Response, String> {
+    // The keys of the receiver are only fetched to start the session: each request takes one of its One Time Pre Keys,
+    // and an identified request before each sealed message would link the sender to the receiver
+    let session_ik: Option<PublicKey> = DOUBLE_RATCHET_CLIENT.lock().unwrap().as_ref().unwrap().get_session_ik(username_receiver);
+    let (ik_receiver, receiver_public_keys): (PublicKey, Option<ServerKeyCollection>) = match session_ik {
+        Some(ik) => (ik, None),
+        None if content.is_control() => return Err(format!("No session with {}, the control message is not sent", username_receiver)),
+        None => {
+            let receiver_public_keys: ServerKeyCollection = get_user_public_key(username_receiver).await?;
+            (receiver_public_keys.get_ik(), Some(receiver_public_keys))
+        },
+    };
+
     // Encrypt the message using double ratchet
-    let receiver_public_keys: ServerKeyCollection = get_user_public_key(username_receiver).await.unwrap();
     let double_ratchet_res;
-
     let mut current_ik_sender: Option<[u8;32]> = None;
     { // Acquire the lock
         let mut double_ratchet_client_guard = DOUBLE_RATCHET_CLIENT.lock().unwrap();
-        if !double_ratchet_client_guard.as_mut().unwrap().get_communication().contains_key(username_receiver) {
+        if session_ik.is_none() {
             current_ik_sender = Some(double_ratchet_client_guard.as_mut().unwrap().get_server_keys().get_ik().to_bytes());
         }
-        double_ratchet_res = double_ratchet_client_guard.as_mut().unwrap().send_message(&username_receiver.to_string(), message.as_bytes(), &receiver_public_keys).unwrap();
+        content.profile_key = SEALED_SENDER.lock().unwrap().as_ref().map(SealedSender::get_profile_key);
+        double_ratchet_res = if ephemeral {
+            // Dropped by the server if the receiver is not connected, the double ratchet is not moved forward
+            let ciphertext = double_ratchet_client_guard.as_mut().unwrap().send_ephemeral_message(username_receiver, &content.to_bytes())
+                .ok_or(format!("No session with {}, the ephemeral message is not sent", username_receiver))?;
+            (None, ciphertext)
+        } else {
+            double_ratchet_client_guard.as_mut().unwrap().send_message(&username_receiver.to_string(), &content.to_bytes(), receiver_public_keys.as_ref())
+                .map_err(|error| format!("Error when encrypting the message: {}", error))?
+        };
 
     } // Release the lock
 
@@ -286,68 +968,112 @@ async fn send_message(username_sender: &str, username_receiver: &str, message: &
         current_opk = opk.map(|pk| pk.to_bytes());
     }
 
-    let post_info = TCP_CLIENT.post(Action::SendMessage {
-        username_receiver: username_receiver.to_string(),
-        header_encrypted: double_ratchet_res.1.0.get_ciphertext(),
-        header_nonce: double_ratchet_res.1.0.get_nonce(),
-        ciphertext: double_ratchet_res.1.1.get_ciphertext(),
-        nonce: double_ratchet_res.1.1.get_nonce(),
-        ek_sender: current_ek,
-        opk_used: current_opk,
-        ik_sender: current_ik_sender
-    }).await;
+    // Sealed if the receiver gave its profile key, identified otherwise (or if the receiver refuses the access key)
+    let mut sealed_result: Option<Result<Response, ClientError>> = None;
+    if let Some((access_key, sealed_sender)) = get_sealed_sender(username_receiver, &ik_receiver).await {
+        let post_info = TCP_CLIENT.post_unidentified(Action::SendSealedMessage {
+            username_receiver: username_receiver.to_string(),
+            access_key: access_key,
+            sealed_sender: sealed_sender,
+            header_encrypted: double_ratchet_res.1.0.get_ciphertext(),
+            header_nonce: double_ratchet_res.1.0.get_nonce(),
+            ciphertext: double_ratchet_res.1.1.get_ciphertext(),
+            nonce: double_ratchet_res.1.1.get_nonce(),
+            ek_sender: current_ek,
+            opk_used: current_opk,
+            ephemeral: ephemeral,
+        }).await;
 
-    match post_info {
-        Ok(info) => {
-            match TCP_CLIENT.get_result(info).await {
-                Ok(ServerResponse::ResponseStatus { success }) => {
-                    if !success {
-                        //todo!();
-                        println!("TODO, implement an error in the success when sending a message: {}", success);
-                        // Signal the user that the message has not been sent (modify the database to store a bool if the message has been sent or not)
-                    }
-                },
-                Err(error) => return Err(format!("Error when sending message: {}", error)),
-                Ok(server_response) => return Err(format!("Error when collecting sending message status (bad server response): {:?}", server_response)),
+        sealed_result = match post_info {
+            Ok(info) => match TCP_CLIENT.get_result(info).await {
+                Err(ClientError::Server(ErrorCode::AccessDenied, _)) => None,
+                result => Some(result),
+            },
+            Err(error) => return Err(format!("Error when sending messages (post_info): {}", error)),
+        };
+    }
+
+    let result: Result<Response, ClientError> = match sealed_result {
+        Some(result) => result,
+        None => {
+            let post_info = TCP_CLIENT.post(Action::SendMessage {
+                username_receiver: username_receiver.to_string(),
+                header_encrypted: double_ratchet_res.1.0.get_ciphertext(),
+                header_nonce: double_ratchet_res.1.0.get_nonce(),
+                ciphertext: double_ratchet_res.1.1.get_ciphertext(),
+                nonce: double_ratchet_res.1.1.get_nonce(),
+                ek_sender: current_ek,
+                opk_used: current_opk,
+                ik_sender: current_ik_sender,
+                ephemeral: ephemeral,
+            }).await;
+
+            match post_info {
+                Ok(info) => TCP_CLIENT.get_result(info).await,
+                Err(error) => return Err(format!("Error when sending messages (post_info): {}", error)),
             }
         },
-        Err(error) => return Err(format!("Error when sending messages (post_info): {}", error)),
     };
 
-    // Store the message in the client database
-    /*
-    let mut message_database_guard = MESSAGE_DATABASE.lock().unwrap();
-    if let Some(mut message_database) = message_database_guard.take() {
-        message_database.insert_message(username_sender, username_receiver, message)
-            .expect("Message insertion in database raised an error");
+    result.map_err(|error| format!("Error when sending message: {}", error))
+}
 
-        *message_database_guard = Some(message_database);
-    } else {
-        // Should not happen, because database is initialized when log in
-        return Err("Database not initialized".to_string());
-    }*/
-    store_message_in_database(username_sender, username_receiver, message).expect("Error when inserting information in the message database");
+/// Return the delivery access key of the receiver and the sealed sender certificate, None if the message must be sent identified
+///
+/// The sender certificate is renewed when it has expired.
+///
+/// # Arguments
+///
+/// * `username_receiver` (&str): Username of the receiver *(its profile key is known once it has sent a message)*
+/// * `ik_receiver` (&PublicKey): Identity Key of the receiver
+async fn get_sealed_sender(username_receiver: &str, ik_receiver: &PublicKey) -> Option<([u8; 16], Vec<u8>)> {
+    let profile_key: [u8; 32] = DOUBLE_RATCHET_DATABASE.lock().unwrap().as_ref()?.get_profile_key(username_receiver)
+        .expect("Profile key selection in database raised an error")?;
 
+    let now: u64 = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    let certificate_valid: bool = SEALED_SENDER.lock().unwrap().as_ref()?.is_certificate_valid(now);
+    if !certificate_valid {
+        match get_sender_certificate().await {
+            Ok((certificate, server_key)) => SEALED_SENDER.lock().unwrap().as_mut()?.set_certificate(certificate, server_key),
+            Err(error) => {
+                println!("{}", error);
+                return None
+            },
+        }
+    }
 
-    Ok(())
+    let certificate: SenderCertificate = SEALED_SENDER.lock().unwrap().as_ref()?.get_certificate().clone();
+    let sealed_sender: Vec<u8> = seal(&certificate, &DOUBLE_RATCHET_CLIENT.lock().unwrap().as_ref()?.get_keys().get_ik(), ik_receiver);
+    Some((derive_access_key(&profile_key), sealed_sender))
 }
 
-fn store_message_in_database(username_sender: &str, username_receiver: &str, message: &str) -> Result<(), String> {
+fn store_message_in_database(username_sender: &str, username_receiver: &str, message: &str, server_message_id: Option<i64>,
+                             server_guid: Option<&str>, server_timestamp: Option<u64>, server_seq: Option<u64>, status: MessageStatus) -> Result<i64, String> {
     let mut message_database_guard = MESSAGE_DATABASE.lock().unwrap();
     if let Some(mut message_database) = message_database_guard.take() {
-        message_database.insert_message(username_sender, username_receiver, message)
+        let message_id: i64 = message_database.insert_message(username_sender, username_receiver, message, server_message_id, server_guid, server_timestamp, server_seq, status)
             .expect("Message insertion in database raised an error");
 
         *message_database_guard = Some(message_database);
-        Ok(())
+        Ok(message_id)
     } else {
         // Should not happen, because database is initialized when log in
         return Err("Database not initialized".to_string());
     }
 }
 
+/// Check if the message with the corresponding server GUID has already been read *(message sent again by the server)*
+fn is_message_received(server_guid: &str) -> bool {
+    match MESSAGE_DATABASE.lock().unwrap().as_ref() {
+        Some(message_database) => message_database.is_received(server_guid)
+            .expect("Message selection in database raised an error"),
+        None => false,
+    }
+}
+
 #[tauri::command]
-async fn load_messages(username_receiver: &str) -> Result<Vec<(String, String, String)>, String> {
+async fn load_messages(username_receiver: &str) -> Result<Vec<(String, String, String, Option<u64>, MessageStatus, Option<String>)>, String> {
+    let username_receiver: &str = &normalize_username(username_receiver);
     let mut database_guard = MESSAGE_DATABASE.lock().unwrap();
     if let Some(database) = database_guard.as_mut() {
         let res = database.get_messages_with(username_receiver)
@@ -364,7 +1090,7 @@ async fn load_messages(username_receiver: &str) -> Result<Vec<(String, String, S
 async fn main() -> Result<(), reqwest::Error> {
     //env::set_var("RUST_BACKTRACE", "1");
     tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![verify_credential, register, log_out, get_all_users, get_messages, send_message, load_messages])
+        .invoke_handler(tauri::generate_handler![verify_credential, register, log_out, delete_account, change_password, get_all_users, get_messages, listen_messages, get_expired_messages, send_message, load_messages, mark_as_read, get_read_receipts, set_read_receipts, send_typing])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 
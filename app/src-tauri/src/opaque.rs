@@ -0,0 +1,85 @@
+use opaque_ke::{CipherSuite, ClientLogin, ClientLoginFinishParameters, ClientRegistration, ClientRegistrationFinishParameters,
+                CredentialResponse, RegistrationResponse};
+use opaque_ke::errors::ProtocolError;
+use rand::rngs::OsRng;
+
+/// OPAQUE cipher suite, must be the same on the server side
+pub struct DefaultCipherSuite;
+
+impl CipherSuite for DefaultCipherSuite {
+    type OprfCs = opaque_ke::Ristretto255;
+    type KeGroup = opaque_ke::Ristretto255;
+    type KeyExchange = opaque_ke::key_exchange::tripledh::TripleDh;
+    type Ksf = opaque_ke::ksf::Identity; // The password is already stretched with Argon2id (hash::get_hash)
+}
+
+/// First step of the registration
+///
+/// # Arguments
+///
+/// * `password` (&\[u8\]): Password
+///
+/// # Output
+///
+/// * `(state, registration_request)` (Result\<(ClientRegistration\<DefaultCipherSuite\>, Vec\<u8\>), ProtocolError\>): State to keep for the last step and message for the server
+pub fn registration_start(password: &[u8]) -> Result<(ClientRegistration<DefaultCipherSuite>, Vec<u8>), ProtocolError> {
+    let result = ClientRegistration::<DefaultCipherSuite>::start(&mut OsRng, password)?;
+
+    Ok((result.state, result.message.serialize().to_vec()))
+}
+
+/// Last step of the registration
+///
+/// # Arguments
+///
+/// * `state` (ClientRegistration\<DefaultCipherSuite\>): State returned by `registration_start`
+/// * `password` (&\[u8\]): Password
+/// * `registration_response` (&\[u8\]): Registration response of the server
+///
+/// # Output
+///
+/// * `(registration_upload, export_key)` (Result\<(Vec\<u8\>, Vec\<u8\>), ProtocolError\>): Message for the server and key only known by the client
+pub fn registration_finish(state: ClientRegistration<DefaultCipherSuite>, password: &[u8], registration_response: &[u8]) -> Result<(Vec<u8>, Vec<u8>), ProtocolError> {
+    let result = state.finish(
+        &mut OsRng,
+        password,
+        RegistrationResponse::deserialize(registration_response)?,
+        ClientRegistrationFinishParameters::default())?;
+
+    Ok((result.message.serialize().to_vec(), result.export_key.to_vec()))
+}
+
+/// First step of the login
+///
+/// # Arguments
+///
+/// * `password` (&\[u8\]): Password
+///
+/// # Output
+///
+/// * `(state, credential_request)` (Result\<(ClientLogin\<DefaultCipherSuite\>, Vec\<u8\>), ProtocolError\>): State to keep for the last step and message for the server
+pub fn login_start(password: &[u8]) -> Result<(ClientLogin<DefaultCipherSuite>, Vec<u8>), ProtocolError> {
+    let result = ClientLogin::<DefaultCipherSuite>::start(&mut OsRng, password)?;
+
+    Ok((result.state, result.message.serialize().to_vec()))
+}
+
+/// Last step of the login *(fails if the password is wrong)*
+///
+/// # Arguments
+///
+/// * `state` (ClientLogin\<DefaultCipherSuite\>): State returned by `login_start`
+/// * `password` (&\[u8\]): Password
+/// * `credential_response` (&\[u8\]): Credential response of the server
+///
+/// # Output
+///
+/// * `(credential_finalization, export_key)` (Result\<(Vec\<u8\>, Vec\<u8\>), ProtocolError\>): Message for the server and key only known by the client *(same as the registration one, can seed the local database encryption)*
+pub fn login_finish(state: ClientLogin<DefaultCipherSuite>, password: &[u8], credential_response: &[u8]) -> Result<(Vec<u8>, Vec<u8>), ProtocolError> {
+    let result = state.finish(
+        password,
+        CredentialResponse::deserialize(credential_response)?,
+        ClientLoginFinishParameters::default())?;
+
+    Ok((result.message.serialize().to_vec(), result.export_key.to_vec()))
+}
@@ -1,101 +1,204 @@
-use serde::{Serialize, Deserialize};
-use reqwest::{Client, Error};
-
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(rename_all = "lowercase", tag = "action")]
-pub enum Action {
-    NewUser {
-        username: String,
-        password: String,
-    },
-    LogIn {
-        username: String,
-        password: String,
-    },
-    LogOut, // Replace logout with tcp connection stopped
-    GetAllUsers,
-    GetMessages,
-    PublishX3DHInformation { // Sent when the user is created (Client to the Server)
-    ik: [u8; 32],
-        spk: [u8; 32],
-        opk_bundle: Vec<[u8; 32]>,
-        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
-        verifying_key: [u8; 32],
-    },
-    UpdateX3DHSignedPreKey {
-        spk: [u8; 32],
-        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
-        verifying_key: [u8; 32],
-    },
-    SupplyX3DHOneTimePreKeyBundle {
-        opk_bundle: Vec<[u8; 32]>,
-    },
-    GetUserPublicKeys { // Client to the Server (handle user does not exist)
-        username: String,
-    },
-    SendMessage {
-        username_receiver: String,
-        header_encrypted: Vec<u8>,
-        header_nonce: Vec<u8>,
-        ciphertext: Vec<u8>,
-        nonce: Vec<u8>,
-        ek_sender: Option<[u8;32]>,
-        opk_used: Option<[u8;32]>,
-        ik_sender: Option<[u8;32]>
-    },
+use mini_signal_protocol::{Action, Capability, Encoding, ErrorCode, Request, Response, CAPABILITIES_HEADER};
+use reqwest::{Client, Error, Url};
+use std::fmt;
+use std::sync::Mutex;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+/// WebSocket connection used by the server to push the messages
+pub type PushStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+const CLIENT_CAPABILITIES: &[Capability] = &[Capability::Push, Capability::Acks, Capability::Cbor, Capability::SealedSender, Capability::Ephemeral];
+
+const DEFAULT_SERVER_URL: &str = "https://127.0.0.1:6379"; // Default listen address of the server
+
+#[derive(Debug)]
+pub enum ClientError {
+    Http(Error), // The server is not reachable or its response is not valid
+    Decode(String), // The body of the response can not be decoded
+    Server(ErrorCode, String), // Response::Error of the server (code, message of the server)
+    Status(reqwest::StatusCode), // HTTP error without a response of the server (request too large, response not encodable)
+    Config(String), // Invalid configuration of the client (MINI_SIGNAL_SERVER_URL)
 }
 
-#[derive(Debug, Deserialize)]
-pub enum ServerResponse {
-    UserList { result: Vec<String> },
-    ResponseStatus { success: bool },
-    UserPublicKeys {
-        ik: [u8; 32],
-        spk: [u8; 32],
-        opk: Option<[u8; 32]>,
-        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
-        verifying_key: [u8; 32],
-    },
-    Messages {
-        success: bool,
-        new_messages: bool,
-        messages: Option<Vec<(String, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Option<[u8;32]>, Option<[u8;32]>, Option<[u8;32]>)>>,
-    },
+impl fmt::Display for ClientError {
+    /// Message shown to the user
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Http(error) => write!(f, "{}", error),
+            ClientError::Decode(error) => write!(f, "Invalid response of the server: {}", error),
+            ClientError::Server(code, message) => match code {
+                ErrorCode::Unauthenticated => write!(f, "Your session has expired, please log in again"),
+                ErrorCode::InvalidCredentials => write!(f, "Invalid username or password"),
+                ErrorCode::InvalidUsername => write!(f, "Invalid username: {}", message),
+                ErrorCode::UserExists => write!(f, "This username is already used"),
+                ErrorCode::UnknownRecipient => write!(f, "This user does not exist"),
+                ErrorCode::NoKeysPublished => write!(f, "The keys of this account have not been published"),
+                ErrorCode::KeysAlreadyPublished => write!(f, "The keys of this account are already published"),
+                ErrorCode::AccessDenied => write!(f, "This user does not accept sealed messages from you"),
+                ErrorCode::RateLimited => write!(f, "{}", message), // Tells when to try again
+                ErrorCode::UpgradeRequired => write!(f, "This version of mini-signal is no longer supported by the server, please update the app"),
+                ErrorCode::InvalidRequest | ErrorCode::Unknown => write!(f, "Request refused by the server: {}", message),
+                ErrorCode::Internal => write!(f, "The server could not handle the request, try again later"),
+            },
+            ClientError::Status(status) => write!(f, "The server answered with the status {}", status),
+            ClientError::Config(error) => write!(f, "Invalid configuration: {}", error),
+        }
+    }
+}
+
+impl From<Error> for ClientError {
+    fn from(error: Error) -> Self {
+        ClientError::Http(error)
+    }
 }
 
 pub struct MiniSignalClient {
+    server_url: Url, // Requests of the client
+    push_url: Url, // WebSocket connection of the pushed messages (`/ws` of the server)
     client: Client,
+    unidentified_client: Client, // Own connections, the sealed messages are not sent over the connection of the session
+    session_token: Mutex<Option<String>>, // Sent with every request once logged in
+    capabilities: Mutex<Vec<Capability>>, // Negotiated with the server (see `hello`)
+}
+
+/// Parse the URL of the server and derive the URL of its WebSocket connection *(wss://.../ws)*
+///
+/// # Output
+///
+/// * `(server_url, push_url)` (Result\<(Url, Url), String\>): URL of the requests and of the pushed messages
+fn server_urls(server_url: &str) -> Result<(Url, Url), String> {
+    let server_url: Url = Url::parse(server_url).map_err(|error| format!("MINI_SIGNAL_SERVER_URL \"{}\": {}", server_url, error))?;
+    let push_scheme: &str = match server_url.scheme() {
+        "https" => "wss",
+        "http" => "ws",
+        scheme => return Err(format!("MINI_SIGNAL_SERVER_URL must start with https:// or http://, not {}://", scheme)),
+    };
+    let mut push_url: Url = server_url.join("ws").map_err(|error| error.to_string())?;
+    push_url.set_scheme(push_scheme).map_err(|()| format!("Invalid WebSocket scheme {}", push_scheme))?;
+    Ok((server_url, push_url))
 }
 
 impl MiniSignalClient {
-    pub fn new() -> Result<Self, reqwest::Error> {
-        let client = Client::builder()
+    /// Client of the server at the URL of the environment variable `MINI_SIGNAL_SERVER_URL` *(`https://127.0.0.1:6379` by default)*
+    pub fn new() -> Result<Self, ClientError> {
+        let server_url: String = std::env::var("MINI_SIGNAL_SERVER_URL").unwrap_or_else(|_| DEFAULT_SERVER_URL.to_string());
+        let (server_url, push_url) = server_urls(&server_url).map_err(ClientError::Config)?;
+        let build_client = || Client::builder()
             .danger_accept_invalid_certs(true) // For testing purpose (For production use a Valid TLS Certificate)
             .use_native_tls()
-            .build()?;
-        Ok(MiniSignalClient { client })
+            .build();
+        Ok(MiniSignalClient { server_url, push_url, client: build_client()?, unidentified_client: build_client()?, session_token: Mutex::new(None), capabilities: Mutex::new(Vec::new()) })
+    }
+
+    /// Store the session token returned by the server after a successful `LogIn`
+    pub fn set_session_token(&self, token: Option<String>) {
+        *self.session_token.lock().unwrap() = token;
+    }
+
+    /// Check that the server supports the protocol version of the client and negotiate the capabilities
+    ///
+    /// The requests are encoded with CBOR once negotiated, unless the environment variable `MINI_SIGNAL_JSON` is set *(to read them while debugging)*.
+    pub async fn hello(&self) -> Result<(), ClientError> {
+        let mut capabilities: Vec<Capability> = CLIENT_CAPABILITIES.to_vec();
+        if std::env::var_os("MINI_SIGNAL_JSON").is_some() {
+            capabilities.retain(|capability| *capability != Capability::Cbor);
+        }
+        let response = self.post(Action::Hello { capabilities }).await?;
+        match self.get_result(response).await? {
+            Response::Hello { capabilities, .. } => {
+                *self.capabilities.lock().unwrap() = capabilities;
+                Ok(())
+            },
+            response => Err(ClientError::Server(ErrorCode::Unknown, format!("Unexpected answer to Hello: {:?}", response))),
+        }
+    }
+
+    /// Check if the capability has been negotiated with the server
+    pub fn has_capability(&self, capability: Capability) -> bool {
+        self.capabilities.lock().unwrap().contains(&capability)
+    }
+
+    /// Encoding of the requests and responses *(JSON until CBOR has been negotiated)*
+    fn encoding(&self) -> Encoding {
+        if self.has_capability(Capability::Cbor) { Encoding::Cbor } else { Encoding::Json }
     }
 
     pub async fn post(&self, data: Action) -> Result<reqwest::Response, Error> {
+        let session_token: Option<String> = self.session_token.lock().unwrap().clone();
+        self.send(&self.client, Request::new(session_token, data)).await
+    }
+
+    /// Send the request without the session token *(SendSealedMessage, the server must not learn who sends it)*
+    pub async fn post_unidentified(&self, data: Action) -> Result<reqwest::Response, Error> {
+        self.send(&self.unidentified_client, Request::new(None, data)).await
+    }
+
+    async fn send(&self, client: &Client, request: Request) -> Result<reqwest::Response, Error> {
+        let encoding: Encoding = self.encoding();
+        let body: Vec<u8> = encoding.encode(&request).expect("Error when encoding the request");
+
         // Send a POST request to the server
-        let response = self.client
-            .post("https://0.0.0.0:6379")
-            .json(&data)
+        let response = client
+            .post(self.server_url.clone())
+            .header("content-type", encoding.content_type())
+            .header("accept", encoding.content_type())
+            .body(body)
             .send()
             .await?;
 
         Ok(response)
     }
 
-    pub async fn get_result(&self, response: reqwest::Response) -> Result<ServerResponse, Error> {
-        // Ensure the server returned a success status code (2xx)
+    /// Open the WebSocket connection used by the server to push the messages *(requires a session token)*
+    pub async fn connect_push(&self) -> Result<PushStream, tungstenite::Error> {
+        let mut request = self.push_url.as_str().into_client_request()?;
+        let session_token: Option<String> = self.session_token.lock().unwrap().clone();
+        if let Some(token) = session_token {
+            request.headers_mut().insert("authorization", HeaderValue::from_str(&format!("Bearer {}", token)).expect("Invalid session token"));
+        }
+        // The server only pushes the ephemeral messages if the client can read them
+        let capabilities: String = serde_json::to_string(&*self.capabilities.lock().unwrap()).expect("Error when encoding the capabilities");
+        request.headers_mut().insert(CAPABILITIES_HEADER, HeaderValue::from_str(&capabilities).expect("Invalid capabilities"));
+
+        let connector = native_tls::TlsConnector::builder()
+            .danger_accept_invalid_certs(true) // For testing purpose (For production use a Valid TLS Certificate)
+            .build()
+            .map_err(|error| tungstenite::Error::Tls(error.into()))?;
+        let (stream, _) = connect_async_tls_with_config(request, None, false, Some(Connector::NativeTls(connector))).await?;
+        Ok(stream)
+    }
+
+    /// Read the response of the server *(`Response::Error` is returned as `ClientError::Server`)*
+    pub async fn get_result(&self, response: reqwest::Response) -> Result<Response, ClientError> {
+        // The errors of the server are answered with a success status code (2xx) and Response::Error
         if !response.status().is_success() {
-            eprintln!("Server returned an error: {:?}", response);
-        } else {
+            return Err(ClientError::Status(response.status()))
+        }
 
+        // Encoding of the response given by the server
+        let encoding: Encoding = Encoding::from_content_type(response.headers().get("content-type").and_then(|value| value.to_str().ok()));
+        let body = response.bytes().await?;
+        match encoding.decode::<Response>(&body).map_err(|error| ClientError::Decode(error.to_string()))? {
+            Response::Error { code, message } => Err(ClientError::Server(code, message)),
+            result => Ok(result),
         }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let result: ServerResponse = response.json().await?;
-        Ok(result)
+    #[test]
+    fn push_url_of_the_server() {
+        let (server_url, push_url) = server_urls(DEFAULT_SERVER_URL).unwrap();
+        assert_eq!((server_url.as_str(), push_url.as_str()), ("https://127.0.0.1:6379/", "wss://127.0.0.1:6379/ws"));
+        let (_, push_url) = server_urls("http://chat.example.org/signal/").unwrap();
+        assert_eq!(push_url.as_str(), "ws://chat.example.org/signal/ws");
+        assert!(server_urls("ftp://chat.example.org").is_err());
+        assert!(server_urls("chat.example.org:6379").is_err());
     }
-}
\ No newline at end of file
+}
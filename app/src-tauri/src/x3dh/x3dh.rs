@@ -15,6 +15,7 @@ use ed25519_dalek::{Signature, SigningKey, Signer, VerifyingKey, Verifier};
 #[derive(PartialEq, Debug)]
 pub enum X3DHError {
     SignatureInvalid,
+    PublicKeysAbsent,
 }
 
 const F: [u8; 32] = [0xFF; 32];
@@ -215,6 +216,7 @@ impl fmt::Display for X3DHError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             X3DHError::SignatureInvalid => write!(f, "Verification of the signature failed"),
+            X3DHError::PublicKeysAbsent => write!(f, "The public keys of the receiver are needed to start the session"),
         }
     }
 }
\ No newline at end of file
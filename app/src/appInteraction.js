@@ -112,7 +112,7 @@ function updateMessageBtnColor() {
 
 // JS for Tauri app
 const { invoke } = window.__TAURI__.tauri;
-const { TauriEvent } = window.__TAURI__.event;
+const { TauriEvent, listen } = window.__TAURI__.event;
 const { appWindow } = window.__TAURI__.window;
 
 // TODO add the message gathering
@@ -122,13 +122,27 @@ async function load_messages() {
     let messages = await invoke("load_messages", {usernameReceiver: receiver});
     messages.forEach(function (m) {
         if (m[0] === localStorage.getItem('username')) {
-            create_new_message_div(true, m[2]);
+            create_new_message_div(true, m[2], m[3], m[4], m[5]);
         } else {
-            create_new_message_div(false, m[2]);
+            create_new_message_div(false, m[2], m[3]);
         }
     })
+    await mark_as_read();
 }
 
+// Messages of the selected conversation shown, the read receipt is sent (unless disabled)
+async function mark_as_read() {
+    let selected = document.getElementById("selected");
+    if (selected) {
+        await invoke("mark_as_read", { usernameReceiver: localStorage.getItem('username'), usernameSender: selected.getElementsByTagName("p")[0].innerText });
+    }
+}
+
+const readReceiptsCheckbox = document.getElementById("readReceipts");
+readReceiptsCheckbox.addEventListener("change", async () => {
+    await invoke("set_read_receipts", { enabled: readReceiptsCheckbox.checked });
+});
+
 window.onload = async function () {
     let username = localStorage.getItem('username');
     document.querySelector('.column.left header .circle .userFirstLetter').innerText = username.charAt(0).toUpperCase();
@@ -141,11 +155,87 @@ window.onload = async function () {
         currentFriendName.textContent = userPreviouslySelected.getElementsByTagName("p")[0].textContent;
     }
 
+    readReceiptsCheckbox.checked = await invoke("get_read_receipts");
+
     // Load the message already send
     await load_messages();
 }
 
-let interval_get_message = setInterval(get_messages, 5000); // Call each second
+let interval_get_message = null; // Only used when the push connection is not available
+
+// The server pushes the new messages, get_messages collects the ones received while offline
+listen("new_messages", async (event) => {
+    event.payload.forEach(function (m) {
+        create_new_message_div(false, m)
+    })
+    typingIndicator.innerText = "";
+    await mark_as_read();
+});
+
+const typingIndicator = document.getElementById("typingIndicator");
+let typingIndicatorTimeout = null;
+
+// Typing indicators of the contact selected: (sender, started or stopped)
+listen("typing", (event) => {
+    let selected = document.getElementById("selected");
+    if (!selected || selected.getElementsByTagName("p")[0].innerText !== event.payload[0]) {
+        return;
+    }
+    clearTimeout(typingIndicatorTimeout);
+    if (event.payload[1] === "started") {
+        typingIndicator.innerText = "typing...";
+        // The stop is lost if the contact closes the app
+        typingIndicatorTimeout = setTimeout(() => { typingIndicator.innerText = ""; }, 15000);
+    } else {
+        typingIndicator.innerText = "";
+    }
+});
+
+// Receipts received: (guid, status) of the messages sent
+listen("message_status", (event) => {
+    event.payload.forEach(function (m) {
+        let message = messagesWrapper.querySelector('.container[data-guid="' + m[0] + '"]');
+        if (message) {
+            message.dataset.status = m[1];
+        }
+    })
+});
+
+// Sequence numbers missing once the mailbox has been collected (expired on the server or unreadable)
+listen("messages_lost", (event) => {
+    alert(event.payload + " message(s) could not be delivered");
+});
+
+// Messages that could not be decrypted, not acknowledged (the server expires them)
+listen("messages_unreadable", (event) => {
+    alert(event.payload.join("\n"));
+});
+
+listen("push_closed", () => {
+    if (interval_get_message === null) {
+        interval_get_message = setInterval(get_messages, 5000);
+    }
+});
+
+start_message_delivery();
+
+async function start_message_delivery() {
+    try {
+        await invoke("listen_messages", { usernameReceiver: localStorage.getItem('username') });
+    } catch (error) { // Fall back to polling
+        interval_get_message = setInterval(get_messages, 5000);
+    }
+    await get_messages();
+    await get_expired_messages();
+}
+
+// Messages deleted by the server before the receiver collected them
+async function get_expired_messages() {
+    let expiredMessages = await invoke("get_expired_messages", { usernameSender: localStorage.getItem('username') });
+    expiredMessages.forEach(function (m) {
+        alert("Message to " + m[0] + " expired before being received: " + m[1]);
+    })
+}
 
 async function get_messages() {
     let messages = await invoke("get_messages", { usernameReceiver: localStorage.getItem('username') });
@@ -153,6 +243,7 @@ async function get_messages() {
         messages.forEach(function (m) {
             create_new_message_div(false, m)
         })
+        await mark_as_read();
     }
 }
 
@@ -201,8 +292,13 @@ const sendBtn = document.getElementById("btnSend");
 const messageInput = document.getElementById("message-input")
 const messagesWrapper = document.getElementById("messages");
 
-function create_new_message_div(isYourMessage, current_message) {
+// timestamp: reception by the server in seconds (undefined for the new messages and the old ones)
+// status and guid: messages sent only (sending, sent, delivered, read or failed)
+function create_new_message_div(isYourMessage, current_message, timestamp, status, guid) {
     let newMessage = document.createElement('div');
+    if (timestamp) {
+        newMessage.title = new Date(timestamp * 1000).toLocaleString();
+    }
     if (messagesWrapper.childElementCount - 1 === 0) {
         newMessage.className = "container first";
     } else {
@@ -221,22 +317,65 @@ function create_new_message_div(isYourMessage, current_message) {
 
     newMessage.removeChild(newMessage.lastChild);
 
+    // Shown by the CSS, so the search ignores it
+    if (isYourMessage && status) {
+        newMessage.dataset.status = status;
+        if (guid) {
+            newMessage.dataset.guid = guid;
+        }
+    }
+
     messagesWrapper.insertBefore(newMessage, messagesWrapper.children[messagesWrapper.childElementCount - 1]);
 }
 
+let typingReceiver = null; // Contact told that the user is typing
+let typingStopTimeout = null;
+
+messageInput.addEventListener("input", async () => {
+    clearTimeout(typingStopTimeout);
+    if (messageInput.textContent.length === 0) {
+        await stop_typing();
+        return;
+    }
+    let receiver = document.getElementById("selected").getElementsByTagName("p")[0].innerText;
+    if (typingReceiver !== receiver) {
+        await stop_typing();
+        typingReceiver = receiver;
+        await invoke("send_typing", { usernameReceiver: receiver, started: true }).catch(console.error);
+    }
+    typingStopTimeout = setTimeout(stop_typing, 5000);
+});
+
+async function stop_typing() {
+    clearTimeout(typingStopTimeout);
+    if (typingReceiver !== null) {
+        let receiver = typingReceiver;
+        typingReceiver = null;
+        await invoke("send_typing", { usernameReceiver: receiver, started: false }).catch(console.error);
+    }
+}
+
 sendBtn.addEventListener("click", async () => {
     if (messageInput.textContent.length > 0) {
+        await stop_typing();
         let sender = localStorage.getItem('username');
         let receiver = document.getElementById("selected").getElementsByTagName("p")[0].innerText;
         let current_message = messageInput.innerText;
-        await invoke("send_message", { usernameSender: sender, usernameReceiver: receiver, message: current_message });
-        create_new_message_div(true, current_message);
+        try {
+            let guid = await invoke("send_message", { usernameSender: sender, usernameReceiver: receiver, message: current_message });
+            create_new_message_div(true, current_message, undefined, "sent", guid);
+        } catch (error) { // Stored as failed
+            create_new_message_div(true, current_message, undefined, "failed");
+        }
         messageInput.textContent = "";
         searchMessage();
     }
 });
 
 async function onUserSelected(userClicked) {
+    await stop_typing();
+    clearTimeout(typingIndicatorTimeout);
+    typingIndicator.innerText = "";
     // Clean the message of the previous conversation, before loading the selected one
     messagesWrapper.querySelectorAll(".container").forEach(messageContainer => {
         messageContainer.remove();
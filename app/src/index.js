@@ -6,9 +6,10 @@ loginButton.addEventListener("click", async () => {
     if (loginForm.checkValidity()) {
         let username = document.getElementById("username").value;
         let password = document.getElementById("password").value;
-        let isValidCredential = await invoke("verify_credential", { username: username, password: password });
-        if (isValidCredential) {
-            localStorage.setItem('username', username);
+        // Canonical form of the username (null if the credentials are not valid)
+        let canonicalUsername = await invoke("verify_credential", { username: username, password: password });
+        if (canonicalUsername) {
+            localStorage.setItem('username', canonicalUsername);
             window.location.href = "main.html";
         } else {
             alert("Invalid login credential. Please try again."); // Alert not working on tauri
@@ -20,7 +20,14 @@ registerBtn.addEventListener("click", async function(event) {
     password.reportValidity();
     username.reportValidity();
     if (checkPassword() && registerForm.checkValidity()) {
-        let isValidCredential = await invoke("register", { username: username.value, password: password.value });
+        let isValidCredential;
+        try {
+            isValidCredential = await invoke("register", { username: username.value, password: password.value });
+        } catch (error) { // Username rejected by the username policy
+            event.preventDefault();
+            alert(error);
+            return;
+        }
         if (isValidCredential) {
             location.href = "index.html"
         } else {
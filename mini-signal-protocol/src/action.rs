@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use crate::version::{default_protocol_version, Capability, PROTOCOL_VERSION};
+
+/// Request sent by the client with a POST on the server
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Request {
+    #[serde(default = "default_protocol_version")]
+    pub protocol_version: u32,
+    pub session_token: Option<String>, // Required for every action except Hello, NewUser, Register, LogIn and SendSealedMessage
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+impl Request {
+    /// Request with the version of the protocol of this crate
+    pub fn new(session_token: Option<String>, action: Action) -> Self {
+        Request { protocol_version: PROTOCOL_VERSION, session_token, action }
+    }
+}
+
+/// Actions of the client *(the name of the action is sent in lowercase)*
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase", tag = "action")]
+pub enum Action {
+    Hello { // Client to the Server (first request, accepted with any protocol version)
+        capabilities: Vec<Capability>, // Capabilities supported by the client
+    },
+    NewUser { // Client to the Server (the X3DH keys must be published with PublishX3DHInformation, prefer Register)
+        username: String,
+        password: String,
+    },
+    Register { // Client to the Server (create the user and publish the X3DH keys at once)
+        username: String,
+        password: String,
+        #[serde(with = "serde_bytes")]
+        ik: [u8; 32],
+        #[serde(with = "serde_bytes")]
+        spk: [u8; 32],
+        #[serde(with = "crate::key_bytes::key_list")]
+        opk_bundle: Vec<[u8; 32]>,
+        #[serde(with = "crate::key_bytes::key_pair")]
+        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
+        #[serde(with = "serde_bytes")]
+        verifying_key: [u8; 32],
+    },
+    LogIn { // Client to the Server
+        username: String,
+        password: String,
+    },
+    LogOut, // Client to the Server
+    GetAllUsers,
+    GetMessages { // Paginated (without fields for the first page and the default page size)
+        after: Option<i64>, // Id of the last message of the previous page
+        page_size: Option<u32>,
+    },
+    AckMessages { // Delete the messages received (the messages not acknowledged are sent again)
+        ids: Vec<i64>,
+    },
+    GetExpiredMessages, // Messages sent by the user and deleted before being acknowledged by the receiver
+    PublishX3DHInformation { // Sent when the user has been created without keys (Client to the Server)
+        #[serde(with = "serde_bytes")]
+        ik: [u8; 32],
+        #[serde(with = "serde_bytes")]
+        spk: [u8; 32],
+        #[serde(with = "crate::key_bytes::key_list")]
+        opk_bundle: Vec<[u8; 32]>,
+        #[serde(with = "crate::key_bytes::key_pair")]
+        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
+        #[serde(with = "serde_bytes")]
+        verifying_key: [u8; 32],
+    },
+    UpdateX3DHSignedPreKey { // Client to the Server
+        #[serde(with = "serde_bytes")]
+        spk: [u8; 32],
+        #[serde(with = "crate::key_bytes::key_pair")]
+        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
+        #[serde(with = "serde_bytes")]
+        verifying_key: [u8; 32],
+    },
+    SupplyX3DHOneTimePreKeyBundle { // Client to the Server (Server send a response to confirm that he received the message
+        #[serde(with = "crate::key_bytes::key_list")]
+        opk_bundle: Vec<[u8; 32]>,
+    },
+    GetUserPublicKeys { // Client to the Server (handle user does not exist)
+        username: String,
+    },
+    DeleteAccount, // Client to the Server (remove every information about the user from the server)
+    ChangePassword { // Client to the Server (close every other session of the user)
+        old: String,
+        new: String,
+    },
+    SendMessage{ // Client to the Server
+        username_receiver: String,
+        #[serde(with = "serde_bytes")]
+        header_encrypted: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        header_nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        ciphertext: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(default, with = "serde_bytes")]
+        ek_sender: Option<[u8;32]>,
+        #[serde(default, with = "serde_bytes")]
+        opk_used: Option<[u8;32]>,
+        #[serde(default, with = "serde_bytes")]
+        ik_sender: Option<[u8;32]>,
+        #[serde(default)]
+        ephemeral: bool, // Only pushed to the receiver if it is connected, never queued (answered with ResponseStatus: false if not pushed)
+    },
+    GetSenderCertificate, // Client to the Server (certificate to put in the sealed messages)
+    SetDeliveryAccessKey { // Client to the Server (key derived from the profile key, given to the contacts to send sealed messages)
+        #[serde(with = "serde_bytes")]
+        access_key: [u8; 16],
+    },
+    SendSealedMessage { // Client to the Server (sent without session token, the sender stays unknown to the server)
+        username_receiver: String,
+        #[serde(with = "serde_bytes")]
+        access_key: [u8; 16], // Delivery access key of the receiver
+        #[serde(with = "serde_bytes")]
+        sealed_sender: Vec<u8>, // SenderCertificate encrypted to the Identity Key of the receiver
+        #[serde(with = "serde_bytes")]
+        header_encrypted: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        header_nonce: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        ciphertext: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        nonce: Vec<u8>,
+        #[serde(default, with = "serde_bytes")]
+        ek_sender: Option<[u8;32]>,
+        #[serde(default, with = "serde_bytes")]
+        opk_used: Option<[u8;32]>,
+        #[serde(default)]
+        ephemeral: bool,
+    },
+    #[cfg(feature = "opaque")]
+    OpaqueRegisterStart { // Client to the Server (OPAQUE registration, first step)
+        username: String,
+        #[serde(with = "serde_bytes")]
+        registration_request: Vec<u8>,
+    },
+    #[cfg(feature = "opaque")]
+    OpaqueRegisterFinish { // Client to the Server (OPAQUE registration, last step, the X3DH keys are published at the same time)
+        username: String,
+        #[serde(with = "serde_bytes")]
+        registration_upload: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        ik: [u8; 32],
+        #[serde(with = "serde_bytes")]
+        spk: [u8; 32],
+        #[serde(with = "crate::key_bytes::key_list")]
+        opk_bundle: Vec<[u8; 32]>,
+        #[serde(with = "crate::key_bytes::key_pair")]
+        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
+        #[serde(with = "serde_bytes")]
+        verifying_key: [u8; 32],
+    },
+    #[cfg(feature = "opaque")]
+    OpaqueLogInStart { // Client to the Server (OPAQUE login, first step)
+        username: String,
+        #[serde(with = "serde_bytes")]
+        credential_request: Vec<u8>,
+    },
+    #[cfg(feature = "opaque")]
+    OpaqueLogInFinish { // Client to the Server (OPAQUE login, last step)
+        login_id: String,
+        #[serde(with = "serde_bytes")]
+        credential_finalization: Vec<u8>,
+    },
+}
+// Do we need a LogOut or the server can now if the host is not reachable (try twice and if not, then wait the next connection)
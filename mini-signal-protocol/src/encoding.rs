@@ -0,0 +1,72 @@
+use std::fmt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub const JSON_CONTENT_TYPE: &str = "application/json";
+pub const CBOR_CONTENT_TYPE: &str = "application/cbor";
+
+/// Encoding of the body of the requests and responses, chosen with the `Content-Type` and `Accept` headers
+///
+/// With CBOR, the keys and the ciphertexts are byte strings instead of arrays of numbers *(JSON stays the default, to debug with curl)*.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+}
+
+#[derive(Debug)]
+pub struct EncodingError(String);
+
+impl Encoding {
+    /// Encoding of a body with this `Content-Type` *(JSON if the header is missing or unknown)*
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        match content_type {
+            Some(content_type) if media_type(content_type).eq_ignore_ascii_case(CBOR_CONTENT_TYPE) => Encoding::Cbor,
+            _ => Encoding::Json,
+        }
+    }
+
+    /// Encoding of the response for this `Accept` header *(CBOR only if the client asks for it)*
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        match accept {
+            Some(accept) if accept.split(',').any(|media_range| media_type(media_range).eq_ignore_ascii_case(CBOR_CONTENT_TYPE)) => Encoding::Cbor,
+            _ => Encoding::Json,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => JSON_CONTENT_TYPE,
+            Encoding::Cbor => CBOR_CONTENT_TYPE,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, EncodingError> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|error| EncodingError(error.to_string())),
+            Encoding::Cbor => {
+                let mut body: Vec<u8> = Vec::new();
+                ciborium::into_writer(value, &mut body).map_err(|error| EncodingError(error.to_string()))?;
+                Ok(body)
+            },
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, EncodingError> {
+        match self {
+            Encoding::Json => serde_json::from_slice(body).map_err(|error| EncodingError(error.to_string())),
+            Encoding::Cbor => ciborium::from_reader(body).map_err(|error| EncodingError(error.to_string())),
+        }
+    }
+}
+
+/// Media type without its parameters *(e.g. "; charset=utf-8")*
+fn media_type(value: &str) -> &str {
+    value.split(';').next().unwrap_or("").trim()
+}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
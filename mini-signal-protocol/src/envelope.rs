@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+/// Encrypted message delivered by the server *(answer to GetMessages or pushed over the WebSocket connection)*
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Envelope {
+    pub message_id: i64, // Id to acknowledge (AckMessages), also returned to the sender by SendMessage
+    pub guid: String, // Unique id given by the server when it received the message (the same for every delivery)
+    pub received_at: u64, // Reception by the server (seconds since the UNIX epoch)
+    pub seq: u64, // Position in the mailbox of the receiver (1, 2, 3... a missing number is a message lost)
+    pub sender: Option<String>, // None for a sealed message (the sender is in sealed_sender)
+    #[serde(default, with = "serde_bytes")]
+    pub sealed_sender: Option<Vec<u8>>, // SenderCertificate encrypted to the Identity Key of the receiver
+    #[serde(with = "serde_bytes")]
+    pub header_encrypted: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub header_nonce: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub ciphertext: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub nonce: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    pub ek_sender: Option<[u8; 32]>, // X3DH keys of the first message of a session
+    #[serde(default, with = "serde_bytes")]
+    pub opk_used: Option<[u8; 32]>,
+    #[serde(default, with = "serde_bytes")]
+    pub ik_sender: Option<[u8; 32]>,
+    pub delivery_attempts: u32, // More than 1 if the message was sent before without being acknowledged
+}
+
+impl Envelope {
+    /// Number of bytes of encrypted data and keys *(used for the byte budget of a GetMessages page)*
+    pub fn encrypted_len(&self) -> usize {
+        self.header_encrypted.len() + self.header_nonce.len() + self.ciphertext.len() + self.nonce.len() + self.sealed_sender.as_ref().map_or(0, Vec::len) + 3 * 32
+    }
+}
@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+/// Reason of a failed request, sent to the client with `Response::Error` *(the codes are part of the protocol, never rename one)*
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Unauthenticated, // No session token, or the session expired or has been revoked
+    InvalidCredentials, // Wrong username or password
+    InvalidUsername, // Refused by the username policy
+    UserExists,
+    UnknownRecipient, // The user does not exist or has not published its X3DH keys
+    NoKeysPublished, // The current user must publish its X3DH keys first
+    KeysAlreadyPublished,
+    RateLimited, // Too many failed logins (the message tells when to try again)
+    AccessDenied, // Sealed message refused (unknown receiver or wrong delivery access key, the server does not tell which)
+    UpgradeRequired, // The protocol version of the client is no longer supported
+    InvalidRequest, // Valid JSON but refused (message to oneself, invalid OPAQUE message...)
+    Internal, // Database error, the details are only in the server logs
+    #[serde(other)]
+    Unknown, // Code added by a newer server (never sent, read by an older client)
+}
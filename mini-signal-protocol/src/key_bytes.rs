@@ -0,0 +1,33 @@
+// `#[serde(with = ...)]` helpers for the keys that are not a single array *(`serde_bytes` only handles one byte array)*
+//
+// Each key is a byte string with CBOR, and stays an array of numbers with JSON.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::{ByteArray, Bytes};
+
+/// List of keys *(`Vec<[u8; 32]>`, e.g. the one-time prekeys)*
+pub mod key_list {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(keys: &[[u8; 32]], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(keys.iter().map(|key| Bytes::new(key)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<[u8; 32]>, D::Error> {
+        Ok(Vec::<ByteArray<32>>::deserialize(deserializer)?.into_iter().map(ByteArray::into_array).collect())
+    }
+}
+
+/// Pair of 32 bytes *(`[[u8; 32]; 2]`, the Ed25519 signature of the signed prekey: [r_bytes, s_bytes])*
+pub mod key_pair {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(pair: &[[u8; 32]; 2], serializer: S) -> Result<S::Ok, S::Error> {
+        [Bytes::new(&pair[0]), Bytes::new(&pair[1])].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[[u8; 32]; 2], D::Error> {
+        let [first, second] = <[ByteArray<32>; 2]>::deserialize(deserializer)?;
+        Ok([first.into_array(), second.into_array()])
+    }
+}
@@ -0,0 +1,24 @@
+// Wire types of the mini-signal protocol and the username policy, shared by the server and the client (app)
+//
+// A request is a JSON object: the action name in the "action" field, the fields of the action, the session token and the protocol version.
+// A response is a JSON object with a single key, the name of the response.
+// The same objects can be encoded with CBOR instead of JSON (see Encoding).
+
+mod action;
+mod encoding;
+mod envelope;
+mod error;
+mod key_bytes;
+mod response;
+mod sealed;
+mod username;
+mod version;
+
+pub use action::{Action, Request};
+pub use encoding::{Encoding, EncodingError, CBOR_CONTENT_TYPE, JSON_CONTENT_TYPE};
+pub use envelope::Envelope;
+pub use error::ErrorCode;
+pub use response::Response;
+pub use sealed::SenderCertificate;
+pub use username::{normalize_username, validate_username, UsernameError};
+pub use version::{Capability, CAPABILITIES_HEADER, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+use crate::envelope::Envelope;
+use crate::error::ErrorCode;
+use crate::sealed::SenderCertificate;
+use crate::version::Capability;
+
+/// Responses of the server
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum Response {
+    Hello { // Server to the Client (answer to Hello)
+        protocol_version: u32, // Version of the server
+        min_protocol_version: u32, // Oldest version accepted by the server
+        capabilities: Vec<Capability>, // Supported by the client and the server
+    },
+    UserList { result: Vec<String> },
+    ResponseStatus { success: bool },
+    Error { // Server to the Client (the action failed, see ErrorCode)
+        code: ErrorCode,
+        message: String, // For the logs and the developers, the client shows its own message for each code
+    },
+    Session { // Server to the Client (sent after a successful LogIn)
+        token: String,
+        expires_at: u64, // Seconds since the UNIX epoch
+    },
+    MessageSent { // Server to the Client (answer to SendMessage and SendSealedMessage)
+        message_id: i64,
+        guid: String, // Same as the Envelope of the receiver
+        received_at: u64,
+    },
+    SenderCertificate { // Server to the Client (answer to GetSenderCertificate)
+        certificate: SenderCertificate,
+        #[serde(with = "serde_bytes")]
+        server_key: [u8; 32], // Ed25519 key of the server, to verify the certificates of the sealed messages
+    },
+    ExpiredMessages { // Server to the Client (answer to GetExpiredMessages)
+        message_ids: Vec<i64>, // Ids returned by SendMessage
+    },
+    UserPublicKeys { // Server to the Client
+        #[serde(with = "serde_bytes")]
+        ik: [u8; 32],
+        #[serde(with = "serde_bytes")]
+        spk: [u8; 32],
+        #[serde(default, with = "serde_bytes")]
+        opk: Option<[u8; 32]>,
+        #[serde(with = "crate::key_bytes::key_pair")]
+        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
+        #[serde(with = "serde_bytes")]
+        verifying_key: [u8; 32],
+    },
+    Messages { // Also pushed over the WebSocket connection (one message) when the receiver is connected
+        success: bool,
+        new_messages: bool,
+        messages: Option<Vec<Envelope>>,
+        has_more: bool, // Other messages are waiting, send GetMessages with the id of the last message
+    },
+    Ephemeral { // Pushed over the WebSocket connection only (ephemeral message, not stored: no message id nor sequence number)
+        message: Envelope,
+    },
+    #[cfg(feature = "opaque")]
+    OpaqueRegistration { // Server to the Client (answer to OpaqueRegisterStart)
+        #[serde(with = "serde_bytes")]
+        registration_response: Vec<u8>,
+    },
+    #[cfg(feature = "opaque")]
+    OpaqueLogIn { // Server to the Client (answer to OpaqueLogInStart)
+        login_id: String,
+        #[serde(with = "serde_bytes")]
+        credential_response: Vec<u8>,
+    },
+}
@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+/// Identity of the sender, issued and signed by the server *(Ed25519)* for the sealed sender messages
+///
+/// The certificate is encrypted to the identity key of the receiver inside the envelope, so only the receiver learns who sent the message.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SenderCertificate {
+    pub sender: String,
+    #[serde(with = "serde_bytes")]
+    pub ik: [u8; 32], // Identity Key of the sender (the same as the one published with the X3DH keys)
+    pub expires_at: u64, // Seconds since the UNIX epoch
+    #[serde(with = "serde_bytes")]
+    pub signature: [u8; 64], // Signature of the server over signed_bytes()
+}
+
+impl SenderCertificate {
+    /// Bytes signed by the server *(length of the username, username, identity key, expiration)*
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` (&str): Username of the sender
+    /// * `ik` (&\[u8; 32\]): Identity Key of the sender
+    /// * `expires_at` (u64): Expiration of the certificate
+    ///
+    /// # Output
+    ///
+    /// * Vec\<u8\>
+    pub fn signed_bytes(sender: &str, ik: &[u8; 32], expires_at: u64) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(4 + sender.len() + 32 + 8);
+        bytes.extend_from_slice(&(sender.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(sender.as_bytes());
+        bytes.extend_from_slice(ik);
+        bytes.extend_from_slice(&expires_at.to_be_bytes());
+        bytes
+    }
+}
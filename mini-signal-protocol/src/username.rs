@@ -0,0 +1,61 @@
+use std::fmt;
+use unicode_normalization::UnicodeNormalization;
+
+const MIN_USERNAME_LENGTH: usize = 3;
+const MAX_USERNAME_LENGTH: usize = 32;
+
+#[derive(Debug)]
+pub enum UsernameError {
+    TooShort,
+    TooLong,
+    InvalidCharacter(char),
+}
+
+/// Return the canonical form of a username *(Unicode NFKC then lowercase)*
+///
+/// Two usernames with the same canonical form are the same user *(e.g. "Bob", "bob" and "ｂｏｂ")*.
+///
+/// # Arguments
+///
+/// * `username` (&str): Username typed by the user
+pub fn normalize_username(username: &str) -> String {
+    username.trim().nfkc().collect::<String>().to_lowercase()
+}
+
+/// Normalize the username and check that it follows the username policy *(checked by the server, and by the client before sending it)*
+///
+/// Policy: between 3 and 32 characters among `a-z`, `0-9`, `_`, `-` and `.` *(after normalization)*.
+///
+/// # Arguments
+///
+/// * `username` (&str): Username typed by the user
+///
+/// # Output
+///
+/// * `username` (Result\<String, UsernameError\>): Canonical form of the username
+pub fn validate_username(username: &str) -> Result<String, UsernameError> {
+    let normalized_username: String = normalize_username(username);
+    let length: usize = normalized_username.chars().count();
+
+    if length < MIN_USERNAME_LENGTH {
+        return Err(UsernameError::TooShort)
+    }
+    if length > MAX_USERNAME_LENGTH {
+        return Err(UsernameError::TooLong)
+    }
+    if let Some(character) = normalized_username.chars().find(|c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || ['_', '-', '.'].contains(c))) {
+        return Err(UsernameError::InvalidCharacter(character))
+    }
+
+    Ok(normalized_username)
+}
+
+impl fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UsernameError::TooShort => write!(f, "Username must have at least {} characters", MIN_USERNAME_LENGTH),
+            UsernameError::TooLong => write!(f, "Username must have at most {} characters", MAX_USERNAME_LENGTH),
+            UsernameError::InvalidCharacter(character) => write!(f, "Username cannot contain '{}' (only a-z, 0-9, '_', '-' and '.')", character),
+        }
+    }
+}
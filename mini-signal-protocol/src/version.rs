@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+
+/// Version of the protocol implemented by this crate, sent with every request
+///
+/// * 1: first version *(the requests without `protocol_version`)*
+/// * 2: `Hello` handshake with the capabilities
+/// * 3: envelopes stamped by the server *(guid, reception time and sequence number)*
+/// * 4: sealed sender *(`Envelope::sender` is null for the sealed messages)*
+/// * 5: ephemeral messages *(pushed with `Response::Ephemeral`)*
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// Oldest version still accepted by the server, an older client gets the `upgrade_required` error
+///
+/// A client older than 4 cannot read the envelopes of the sealed messages *(null `sender`)*. The ephemeral messages of
+/// version 5 are only pushed to the clients that negotiated `Capability::Ephemeral`, so version 4 is still accepted.
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+
+/// Optional features, negotiated with `Hello` *(the server answers the capabilities supported by both sides)*
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    Push, // Messages pushed over the WebSocket connection (/ws)
+    Acks, // Messages kept until AckMessages, sent again otherwise
+    Cbor, // Requests and responses encoded with CBOR (see Encoding)
+    SealedSender, // Messages sent without the identity of the sender (SendSealedMessage)
+    Ephemeral, // Ephemeral messages pushed to the connected receivers (Response::Ephemeral)
+    #[serde(other)]
+    Unknown, // Capability of a newer version (ignored)
+}
+
+/// Header of the WebSocket upgrade request *(/ws)* with the capabilities negotiated with `Hello`, as a JSON array
+///
+/// The server only pushes `Response::Ephemeral` over the connections that declared `Capability::Ephemeral`.
+pub const CAPABILITIES_HEADER: &str = "x-capabilities";
+
+pub(crate) fn default_protocol_version() -> u32 {
+    1 // Sent by the clients written before the versioning
+}
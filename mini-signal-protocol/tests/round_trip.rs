@@ -0,0 +1,193 @@
+use mini_signal_protocol::{Action, Capability, Encoding, Envelope, ErrorCode, Request, Response, SenderCertificate, PROTOCOL_VERSION};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::Debug;
+
+fn round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: T) {
+    for encoding in [Encoding::Json, Encoding::Cbor] {
+        let body: Vec<u8> = encoding.encode(&value).unwrap();
+        let decoded: T = encoding.decode(&body).unwrap();
+        assert_eq!(decoded, value, "{:?}: {:?}", encoding, body);
+    }
+}
+
+fn envelope(message_id: i64) -> Envelope {
+    Envelope {
+        message_id,
+        guid: format!("{:032x}", message_id),
+        received_at: 1_700_000_000 + message_id as u64,
+        seq: message_id as u64,
+        sender: Some("alice".to_string()),
+        sealed_sender: None,
+        header_encrypted: vec![1; 64],
+        header_nonce: vec![2; 24],
+        ciphertext: vec![3; 128],
+        nonce: vec![4; 24],
+        ek_sender: Some([5; 32]),
+        opk_used: None,
+        ik_sender: Some([6; 32]),
+        delivery_attempts: 2,
+    }
+}
+
+#[test]
+fn requests_round_trip() {
+    let actions: Vec<Action> = vec![
+        Action::Hello { capabilities: vec![Capability::Push, Capability::Acks] },
+        Action::UpdateX3DHSignedPreKey { spk: [1; 32], signature: [[2; 32], [3; 32]], verifying_key: [4; 32] },
+        Action::SupplyX3DHOneTimePreKeyBundle { opk_bundle: vec![[1; 32], [2; 32], [3; 32]] },
+        Action::Register { username: "bob".to_string(), password: "hash".to_string(), ik: [1; 32], spk: [2; 32], opk_bundle: vec![[3; 32], [4; 32]], signature: [[5; 32], [6; 32]], verifying_key: [7; 32] },
+        Action::LogIn { username: "bob".to_string(), password: "hash".to_string() },
+        Action::LogOut,
+        Action::GetMessages { after: Some(42), page_size: None },
+        Action::AckMessages { ids: vec![1, 2, 3] },
+        Action::SendMessage { username_receiver: "alice".to_string(), header_encrypted: vec![1, 2], header_nonce: vec![3], ciphertext: vec![4, 5, 6], nonce: vec![7], ek_sender: Some([8; 32]), opk_used: None, ik_sender: None, ephemeral: false },
+        Action::SendMessage { username_receiver: "alice".to_string(), header_encrypted: vec![1, 2], header_nonce: vec![3], ciphertext: vec![4, 5, 6], nonce: vec![7], ek_sender: None, opk_used: None, ik_sender: None, ephemeral: true },
+        Action::GetSenderCertificate,
+        Action::SetDeliveryAccessKey { access_key: [9; 16] },
+    ];
+    for action in actions {
+        round_trip(Request::new(Some("token".to_string()), action));
+    }
+    round_trip(Request::new(None, Action::GetAllUsers));
+    round_trip(Request::new(None, Action::SendSealedMessage { username_receiver: "alice".to_string(), access_key: [1; 16], sealed_sender: vec![2; 180], header_encrypted: vec![3; 64], header_nonce: vec![4], ciphertext: vec![5; 32], nonce: vec![6], ek_sender: None, opk_used: Some([7; 32]), ephemeral: false }));
+}
+
+#[test]
+fn responses_round_trip() {
+    let responses: Vec<Response> = vec![
+        Response::Hello { protocol_version: 3, min_protocol_version: 1, capabilities: vec![Capability::Push] },
+        Response::ResponseStatus { success: true },
+        Response::Error { code: ErrorCode::UnknownRecipient, message: "carol has not published its X3DH keys".to_string() },
+        Response::Session { token: "token".to_string(), expires_at: 1_700_000_000 },
+        Response::MessageSent { message_id: 7, guid: "0123456789abcdef0123456789abcdef".to_string(), received_at: 1_700_000_000 },
+        Response::UserPublicKeys { ik: [1; 32], spk: [2; 32], opk: None, signature: [[3; 32], [4; 32]], verifying_key: [5; 32] },
+        Response::Messages { success: true, new_messages: true, messages: Some(vec![envelope(1), envelope(2)]), has_more: true },
+        Response::Messages { success: true, new_messages: false, messages: None, has_more: false },
+        Response::Messages { success: true, new_messages: true, messages: Some(vec![Envelope { sender: None, sealed_sender: Some(vec![8; 180]), ik_sender: None, ..envelope(3) }]), has_more: false },
+        Response::Ephemeral { message: Envelope { message_id: 0, seq: 0, delivery_attempts: 1, ..envelope(4) } },
+        Response::SenderCertificate { certificate: SenderCertificate { sender: "bob".to_string(), ik: [1; 32], expires_at: 1_700_086_400, signature: [2; 64] }, server_key: [3; 32] },
+    ];
+    for response in responses {
+        round_trip(response);
+    }
+}
+
+#[test]
+fn wire_format() {
+    // The action name is lowercase and flattened with the session token
+    let request: Request = Request::new(None, Action::AckMessages { ids: vec![1] });
+    assert_eq!(serde_json::to_string(&request).unwrap(), format!(r#"{{"protocol_version":{},"session_token":null,"action":"ackmessages","ids":[1]}}"#, PROTOCOL_VERSION));
+
+    // The bytes are arrays of numbers in JSON
+    let response: Response = Response::MessageSent { message_id: 3, guid: "00ff".to_string(), received_at: 10 };
+    assert_eq!(serde_json::to_string(&response).unwrap(), r#"{"MessageSent":{"message_id":3,"guid":"00ff","received_at":10}}"#);
+    let action: Action = Action::UpdateX3DHSignedPreKey { spk: [1; 32], signature: [[2; 32], [3; 32]], verifying_key: [4; 32] };
+    assert!(serde_json::to_string(&action).unwrap().starts_with(r#"{"action":"updatex3dhsignedprekey","spk":[1,1,1,"#));
+
+    let response: Response = Response::Error { code: ErrorCode::RateLimited, message: "later".to_string() };
+    assert_eq!(serde_json::to_string(&response).unwrap(), r#"{"Error":{"code":"rate_limited","message":"later"}}"#);
+}
+
+#[test]
+fn unknown_error_code() {
+    // A code added by a newer server is still readable
+    let response: Response = serde_json::from_str(r#"{"Error":{"code":"quota_exceeded","message":"Too many messages"}}"#).unwrap();
+    assert_eq!(response, Response::Error { code: ErrorCode::Unknown, message: "Too many messages".to_string() });
+}
+
+#[test]
+fn unversioned_request() {
+    // Sent by the clients written before the versioning
+    let request: Request = serde_json::from_str(r#"{"session_token":null,"action":"getallusers"}"#).unwrap();
+    assert_eq!(request.protocol_version, 1);
+    assert_eq!(request.action, Action::GetAllUsers);
+}
+
+#[test]
+fn unknown_capability() {
+    let action: Action = serde_json::from_str(r#"{"action":"hello","capabilities":["push","stories"]}"#).unwrap();
+    assert_eq!(action, Action::Hello { capabilities: vec![Capability::Push, Capability::Unknown] });
+}
+
+#[test]
+fn message_not_ephemeral_by_default() {
+    // Sent by the clients written before the ephemeral messages
+    let action: Action = serde_json::from_str(r#"{"action":"sendmessage","username_receiver":"alice","header_encrypted":[1],"header_nonce":[2],"ciphertext":[3],"nonce":[4]}"#).unwrap();
+    assert!(matches!(action, Action::SendMessage { ephemeral: false, .. }));
+}
+
+#[test]
+fn sealed_envelope_without_sender() {
+    // The sender is null in JSON, an envelope of an older server has no sealed_sender
+    let json: String = serde_json::to_string(&Envelope { sender: None, sealed_sender: Some(vec![1]), ..envelope(1) }).unwrap();
+    assert!(json.contains(r#""sender":null,"sealed_sender":[1]"#));
+    let older: String = serde_json::to_string(&envelope(2)).unwrap().replace(r#","sealed_sender":null"#, "");
+    assert_eq!(serde_json::from_str::<Envelope>(&older).unwrap(), envelope(2));
+}
+
+#[test]
+fn certificate_signed_bytes() {
+    // Length of the username (big endian), username, identity key, expiration (big endian)
+    let bytes: Vec<u8> = SenderCertificate::signed_bytes("bob", &[1; 32], 2);
+    assert_eq!(bytes.len(), 4 + 3 + 32 + 8);
+    assert_eq!(&bytes[..7], &[0, 0, 0, 3, b'b', b'o', b'b']);
+    assert_eq!(&bytes[39..], &2u64.to_be_bytes());
+}
+
+#[test]
+fn cbor_size() {
+    // Page of 50 messages of 256 bytes
+    let response: Response = Response::Messages { success: true, new_messages: true, messages: Some((0..50).map(envelope_page).collect()), has_more: true };
+    let json: Vec<u8> = Encoding::Json.encode(&response).unwrap();
+    let cbor: Vec<u8> = Encoding::Cbor.encode(&response).unwrap();
+    println!("GetMessages page: {} bytes in JSON, {} bytes in CBOR", json.len(), cbor.len());
+    assert!(cbor.len() * 2 < json.len());
+
+    let request: Request = Request::new(Some("token".to_string()), Action::SendMessage { username_receiver: "alice".to_string(), header_encrypted: vec![200; 64], header_nonce: vec![201; 24], ciphertext: vec![202; 256], nonce: vec![203; 24], ek_sender: Some([204; 32]), opk_used: None, ik_sender: Some([205; 32]), ephemeral: false });
+    let json: Vec<u8> = Encoding::Json.encode(&request).unwrap();
+    let cbor: Vec<u8> = Encoding::Cbor.encode(&request).unwrap();
+    println!("SendMessage: {} bytes in JSON, {} bytes in CBOR", json.len(), cbor.len());
+    assert!(cbor.len() * 2 < json.len());
+}
+
+#[test]
+fn cbor_keys_are_byte_strings() {
+    // Major type 2 (byte string), 32 bytes: 0x58 0x20
+    let byte_string = |key: &ciborium::Value| matches!(key, ciborium::Value::Bytes(bytes) if bytes.len() == 32);
+    let field = |value: &ciborium::Value, name: &str| value.as_map().unwrap().iter()
+        .find(|(key, _)| key.as_text() == Some(name)).map(|(_, field)| field.clone()).unwrap();
+
+    let request: Request = Request::new(None, Action::Register { username: "bob".to_string(), password: "hash".to_string(), ik: [1; 32], spk: [2; 32], opk_bundle: vec![[3; 32], [4; 32]], signature: [[5; 32], [6; 32]], verifying_key: [7; 32] });
+    let body: Vec<u8> = Encoding::Cbor.encode(&request).unwrap();
+    let value: ciborium::Value = ciborium::from_reader(&body[..]).unwrap();
+    assert!(field(&value, "opk_bundle").as_array().unwrap().iter().all(byte_string));
+    assert!(field(&value, "signature").as_array().unwrap().iter().all(byte_string));
+    for key in [3, 4, 5, 6] {
+        assert!(body.windows(34).any(|window| window[..2] == [0x58, 0x20] && window[2..].iter().all(|byte| *byte == key)));
+    }
+
+    let response: Response = Response::UserPublicKeys { ik: [1; 32], spk: [2; 32], opk: None, signature: [[3; 32], [4; 32]], verifying_key: [5; 32] };
+    let value: ciborium::Value = ciborium::from_reader(&Encoding::Cbor.encode(&response).unwrap()[..]).unwrap();
+    let keys: ciborium::Value = field(&value, "UserPublicKeys");
+    assert!(field(&keys, "signature").as_array().unwrap().iter().all(byte_string));
+
+    // Still arrays of numbers in JSON
+    let json: String = serde_json::to_string(&request).unwrap();
+    assert!(json.contains(&format!(r#""opk_bundle":[[{}],[{}]]"#, ["3"; 32].join(","), ["4"; 32].join(","))));
+}
+
+fn envelope_page(message_id: i64) -> Envelope {
+    // Ciphertexts look random, most bytes take 3 digits in JSON
+    let bytes = |len: usize| (0..len).map(|i| (i * 97 + message_id as usize) as u8).collect::<Vec<u8>>();
+    Envelope { header_encrypted: bytes(64), header_nonce: bytes(24), ciphertext: bytes(256), nonce: bytes(24), ..envelope(message_id) }
+}
+
+#[test]
+fn content_negotiation() {
+    assert_eq!(Encoding::from_content_type(Some("application/cbor")), Encoding::Cbor);
+    assert_eq!(Encoding::from_content_type(Some("application/json; charset=utf-8")), Encoding::Json);
+    assert_eq!(Encoding::from_content_type(None), Encoding::Json);
+    assert_eq!(Encoding::from_accept(Some("application/json, application/cbor;q=0.9")), Encoding::Cbor);
+    assert_eq!(Encoding::from_accept(Some("*/*")), Encoding::Json);
+}
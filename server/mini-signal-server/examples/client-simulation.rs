@@ -1,71 +1,9 @@
 use argon2::{password_hash::{
     PasswordHasher, SaltString
 }, Argon2};
-use serde::{Serialize, Deserialize};
+use mini_signal_protocol::{Action, Request, Response};
 use reqwest::{Client, Error};
 
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(rename_all = "lowercase", tag = "action")]
-enum Action {
-    NewUser {
-        username: String,
-        password: String,
-    },
-    LogIn {
-        username: String,
-        password: String,
-    },
-    LogOut, // Replace logout with tcp connection stopped
-    GetAllUsers,
-    GetMessages,
-    PublishX3DHInformation { // Sent when the user is created (Client to the Server)
-        ik: [u8; 32],
-        spk: [u8; 32],
-        opk_bundle: Vec<[u8; 32]>,
-        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
-        verifying_key: [u8; 32],
-    },
-    UpdateX3DHSignedPreKey {
-        spk: [u8; 32],
-        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
-        verifying_key: [u8; 32],
-    },
-    SupplyX3DHOneTimePreKeyBundle {
-        opk_bundle: Vec<[u8; 32]>,
-    },
-    GetUserPublicKeys { // Client to the Server (handle user does not exist)
-        username: String,
-    },
-    SendMessage {
-        username_receiver: String,
-        header_encrypted: Vec<u8>,
-        header_nonce: Vec<u8>,
-        ciphertext: Vec<u8>,
-        nonce: Vec<u8>,
-        ek_sender: Option<[u8;32]>,
-        opk_used: Option<[u8;32]>,
-        ik_sender: Option<[u8;32]>
-    },
-}
-
-#[derive(Debug, Deserialize)]
-enum ServerResponse {
-    UserList { result: Vec<String> },
-    ResponseStatus { success: bool },
-    UserPublicKeys {
-        ik: [u8; 32],
-        spk: [u8; 32],
-        opk: Option<[u8; 32]>,
-        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
-        verifying_key: [u8; 32],
-    },
-    Messages {
-        success: bool,
-        new_messages: bool,
-        messages: Option<Vec<(String, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Option<[u8;32]>, Option<[u8;32]>, Option<[u8;32]>)>>,
-    },
-}
-
 #[tokio::main]
 async fn main() -> Result<(), reqwest::Error> {
     // Create JSON data
@@ -74,9 +12,9 @@ async fn main() -> Result<(), reqwest::Error> {
     let mock_opk_bundle: Vec<[u8; 32]> = vec!([0u8; 32], [1u8; 32], [2u8; 32], [3u8; 32], [4u8; 32]);
     let mock_signature: [[u8; 32]; 2] = [[2u8; 32], [3u8; 32]];
     let mock_verifying_key: [u8; 32] = [4u8; 32];
-    let mock_spk_update: [u8; 32] = [23u8; 32];
-    let mock_signature_update: [[u8; 32]; 2] = [[24u8; 32], [31u8; 32]];
-    let mock_verifying_key_update: [u8; 32] = [42u8; 32];
+    let _mock_spk_update: [u8; 32] = [23u8; 32];
+    let _mock_signature_update: [[u8; 32]; 2] = [[24u8; 32], [31u8; 32]];
+    let _mock_verifying_key_update: [u8; 32] = [42u8; 32];
     let mock_header_encrypted: Vec<u8> = vec![64u8; 32];
     let mock_header_nonce: Vec<u8> = vec![32u8; 32];
     let mock_ciphertext: Vec<u8> = vec![11u8; 32];
@@ -88,22 +26,22 @@ async fn main() -> Result<(), reqwest::Error> {
     // NewUser -> LogIn -> PublishX3DHInformation -> GetAllUsers -> GetUserPublicKeys (Boris) -> SendMessage (Boris) -> GetUserPublicKeys (Elliot) -> SendMessage (Elliot) -> Wait to receive the answer (Elliot) -> SupplyX3DHOneTimePreKeyBundle -> LogOut
     // NewUser -> LogIn -> PublishX3DHInformation -> GetAllUsers -> LogOut
     // Add test of multiple login
-    let simulation_boris: Vec<Action> = vec![Action::NewUser { username: "Boris".to_string(), password: get_hash(&"siroB".to_string()) },
-                                             Action::LogIn { username: "Boris".to_string(), password: get_hash(&"siroB".to_string()) },
+    let simulation_boris: Vec<Action> = vec![Action::NewUser { username: "Boris".to_string(), password: get_hash("siroB") },
+                                             Action::LogIn { username: "Boris".to_string(), password: get_hash("siroB") },
                                              Action::PublishX3DHInformation { ik: mock_ik, spk: mock_spk, opk_bundle: mock_opk_bundle.clone(), signature: mock_signature, verifying_key: mock_verifying_key },
                                              Action::GetAllUsers,
-                                             Action::GetMessages,
+                                             Action::GetMessages { after: None, page_size: None },
                                              Action::LogOut];
 
     // let random_bytes = rand::thread_rng().gen::<[u8; 32]>(); (https://qertoip.medium.com/how-to-generate-an-array-of-random-bytes-in-rust-ccf742a1afd5)
-    let simulation_jack: Vec<Action> = vec![Action::NewUser { username: "Jack".to_string(), password: get_hash(&"kcaJ".to_string()) },
-                                       Action::LogIn { username: "Jack".to_string(), password: get_hash(&"kcaJ".to_string()) },
+    let simulation_jack: Vec<Action> = vec![Action::NewUser { username: "Jack".to_string(), password: get_hash("kcaJ") },
+                                       Action::LogIn { username: "Jack".to_string(), password: get_hash("kcaJ") },
                                        Action::PublishX3DHInformation { ik: mock_ik, spk: mock_spk, opk_bundle: mock_opk_bundle.clone(), signature: mock_signature, verifying_key: mock_verifying_key },
                                        Action::GetAllUsers,
-                                       Action::GetMessages,
+                                       Action::GetMessages { after: None, page_size: None },
                                        Action::GetUserPublicKeys { username: "Jack".to_string() },
-                                       Action::SendMessage { username_receiver: "Boris".to_string(), header_encrypted: mock_header_encrypted.clone(), header_nonce: mock_header_nonce.clone(), ciphertext: mock_ciphertext.clone(), nonce: mock_ciphertext_nonce.clone(), ek_sender: mock_ek_sender.clone(), opk_used: mock_opk_used.clone(), ik_sender: mock_ik_sender.clone() },
-                                       Action::SendMessage { username_receiver: "Jack".to_string(), header_encrypted: mock_header_encrypted.clone(), header_nonce: mock_header_nonce.clone(), ciphertext: mock_ciphertext.clone(), nonce: mock_ciphertext_nonce.clone(), ek_sender: None, opk_used: None, ik_sender: None },
+                                       Action::SendMessage { username_receiver: "Boris".to_string(), header_encrypted: mock_header_encrypted.clone(), header_nonce: mock_header_nonce.clone(), ciphertext: mock_ciphertext.clone(), nonce: mock_ciphertext_nonce.clone(), ek_sender: mock_ek_sender, opk_used: mock_opk_used, ik_sender: mock_ik_sender, ephemeral: false },
+                                       Action::SendMessage { username_receiver: "Jack".to_string(), header_encrypted: mock_header_encrypted.clone(), header_nonce: mock_header_nonce.clone(), ciphertext: mock_ciphertext.clone(), nonce: mock_ciphertext_nonce.clone(), ek_sender: None, opk_used: None, ik_sender: None, ephemeral: false },
                                        //Action::SupplyX3DHOneTimePreKeyBundle { opk_bundle: vec![[73u8; 32]] },
                                        //Action::UpdateX3DHSignedPreKey { spk: mock_spk_update, signature: mock_signature_update, verifying_key: mock_verifying_key_update },
                                        // TODO add the missing actions
@@ -128,40 +66,44 @@ async fn main() -> Result<(), reqwest::Error> {
     // Send a POST request to the server
     for (client, data) in simulation {
         println!("Client X");
+        let mut session_token: Option<String> = None;
         for request_data in data {
-            let response = post(&client, request_data).await?;
-            get_result(response).await?;
+            let response = post(&client, request_data, session_token.clone()).await?;
+            if let Some(Response::Session { token, expires_at: _ }) = get_result(response).await? {
+                session_token = Some(token);
+            }
         }
     }
 
     Ok(())
 }
 
-async fn post(client: &Client, data: Action) -> Result<reqwest::Response, Error> {
+async fn post(client: &Client, data: Action, session_token: Option<String>) -> Result<reqwest::Response, Error> {
     // Send a POST request to the server
     let response = client
         .post("https://0.0.0.0:6379")
-        .json(&data)
+        .json(&Request::new(session_token, data))
         .send()
         .await?;
 
     Ok(response)
 }
 
-async fn get_result(response: reqwest::Response) -> Result<(), Error> {
+async fn get_result(response: reqwest::Response) -> Result<Option<Response>, Error> {
     // Ensure the server returned a success status code (2xx)
     if response.status().is_success() {
         // Parse the JSON response
-        let result: ServerResponse = response.json().await?;
+        let result: Response = response.json().await?;
         println!("Server response: {:?}", result);
+        return Ok(Some(result))
     } else {
         eprintln!("Server returned an error: {:?}", response);
     }
 
-    Ok(())
+    Ok(None)
 }
 
-fn get_hash(password: &String) -> String {
+fn get_hash(password: &str) -> String {
     let salt: SaltString = match SaltString::from_b64("vRpg/cByxpn6m1L0ZPF5ew") { //SaltString::generate(&mut OsRng);
         Ok(salt) => salt,
         Err(error) => panic!("{}", error),
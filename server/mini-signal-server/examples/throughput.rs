@@ -0,0 +1,104 @@
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use mini_signal_protocol::{Action, Encoding, Request, Response};
+use reqwest::Client;
+
+// Throughput of the server with many concurrent clients
+//
+// Start the server, then run: cargo run --release --example throughput -- [clients] [rounds] [json|cbor]
+// Each client registers, logs in, then sends `rounds` times: SendMessage (to the next client), GetMessages and AckMessages.
+// Run it with json then cbor to compare the encodings (latency and bytes exchanged).
+
+const SERVER_URL: &str = "https://127.0.0.1:6379";
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let clients: usize = args.next().map_or(32, |arg| arg.parse().expect("clients must be a number"));
+    let rounds: usize = args.next().map_or(50, |arg| arg.parse().expect("rounds must be a number"));
+    let encoding: Encoding = match args.next().as_deref() {
+        None | Some("json") => Encoding::Json,
+        Some("cbor") => Encoding::Cbor,
+        Some(other) => panic!("Unknown encoding {} (json or cbor)", other),
+    };
+    let bytes_exchanged: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+
+    let client: Client = Client::builder()
+        .danger_accept_invalid_certs(true) // For testing purpose (For production use a Valid TLS Certificate)
+        .use_native_tls()
+        .build()
+        .unwrap();
+
+    // Usernames unique to this run, so the benchmark can run several times against the same databases
+    let run_id: u64 = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() % 100_000;
+    let usernames: Vec<String> = (0..clients).map(|i| format!("bench{}_{}", run_id, i)).collect();
+
+    // Registration and login are not measured (Argon2 is slow on purpose)
+    let mut sessions: Vec<String> = Vec::new();
+    for (i, username) in usernames.iter().enumerate() {
+        let key: [u8; 32] = [(i % 256) as u8; 32];
+        post(&client, encoding, &bytes_exchanged, None, Action::Register { username: username.clone(), password: "password".to_string(), ik: key, spk: key, opk_bundle: vec![], signature: [key, key], verifying_key: key }).await;
+        match post(&client, encoding, &bytes_exchanged, None, Action::LogIn { username: username.clone(), password: "password".to_string() }).await {
+            Response::Session { token, .. } => sessions.push(token),
+            response => panic!("Login failed: {:?}", response),
+        }
+    }
+
+    bytes_exchanged.store(0, Ordering::Relaxed);
+    let start: Instant = Instant::now();
+    let tasks = sessions.into_iter().enumerate().map(|(i, session_token)| {
+        let client: Client = client.clone();
+        let receiver: String = usernames[(i + 1) % clients].clone();
+        let bytes_exchanged: Arc<AtomicUsize> = bytes_exchanged.clone();
+        tokio::spawn(async move {
+            let mut latencies: Vec<Duration> = Vec::new();
+            for _ in 0..rounds {
+                let request_start: Instant = Instant::now();
+                post(&client, encoding, &bytes_exchanged, Some(&session_token), Action::SendMessage { username_receiver: receiver.clone(), header_encrypted: vec![201; 64], header_nonce: vec![202; 24], ciphertext: vec![203; 256], nonce: vec![204; 24], ek_sender: None, opk_used: None, ik_sender: None, ephemeral: false }).await;
+                latencies.push(request_start.elapsed());
+
+                let request_start: Instant = Instant::now();
+                let ids: Vec<i64> = match post(&client, encoding, &bytes_exchanged, Some(&session_token), Action::GetMessages { after: None, page_size: None }).await {
+                    Response::Messages { messages, .. } => messages.unwrap_or_default().iter().map(|message| message.message_id).collect(),
+                    response => panic!("GetMessages failed: {:?}", response),
+                };
+                latencies.push(request_start.elapsed());
+
+                let request_start: Instant = Instant::now();
+                post(&client, encoding, &bytes_exchanged, Some(&session_token), Action::AckMessages { ids }).await;
+                latencies.push(request_start.elapsed());
+            }
+            latencies
+        })
+    }).collect::<Vec<_>>();
+
+    let mut latencies: Vec<Duration> = Vec::new();
+    for task in tasks {
+        latencies.extend(task.await.unwrap());
+    }
+    let elapsed: Duration = start.elapsed();
+
+    latencies.sort();
+    println!("{} clients, {} requests in {:.2?} ({:?})", clients, latencies.len(), elapsed, encoding);
+    println!("Throughput: {:.0} requests/s", latencies.len() as f64 / elapsed.as_secs_f64());
+    println!("Latency: p50 {:.2?}, p99 {:.2?}, max {:.2?}", latencies[latencies.len() / 2], latencies[latencies.len() * 99 / 100], latencies[latencies.len() - 1]);
+    println!("Bytes exchanged: {} per request (request and response bodies)", bytes_exchanged.load(Ordering::Relaxed) / latencies.len());
+}
+
+async fn post(client: &Client, encoding: Encoding, bytes_exchanged: &AtomicUsize, session_token: Option<&String>, action: Action) -> Response {
+    let body: Vec<u8> = encoding.encode(&Request::new(session_token.cloned(), action)).unwrap();
+    let response: Vec<u8> = client.post(SERVER_URL)
+        .header("content-type", encoding.content_type())
+        .header("accept", encoding.content_type())
+        .body(body.clone())
+        .send()
+        .await
+        .expect("Error when sending the request")
+        .bytes()
+        .await
+        .expect("Error when reading the response")
+        .to_vec();
+    bytes_exchanged.fetch_add(body.len() + response.len(), Ordering::Relaxed);
+    encoding.decode(&response).expect("Error when decoding the response")
+}
@@ -0,0 +1,92 @@
+use rusqlite::{CachedStatement, params, Result, Statement, Transaction, TransactionBehavior};
+use crate::database::migration::Migration;
+use crate::database::pool::SqliteConnection;
+use crate::server::rate_limit::{FAILURE_WINDOW, lockout_duration};
+
+pub const LOGIN_ATTEMPT_DATABASE_FILE: &str = "login_attempts.db";
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { description: "Create the login attempts table", apply: create_login_attempts_table },
+];
+
+/// Stored on disk, so a restart of the server does not reset the lockouts
+fn create_login_attempts_table(tx: &Transaction) -> Result<()> {
+    tx.execute("CREATE TABLE IF NOT EXISTS login_attempts (
+         attempt_key TEXT PRIMARY KEY NOT NULL,
+         failures INTEGER NOT NULL,
+         last_failure INTEGER NOT NULL,
+         locked_until INTEGER NOT NULL
+     )", ())?;
+    Ok(())
+}
+
+pub struct LoginAttemptDatabase {
+    conn: SqliteConnection
+}
+
+impl LoginAttemptDatabase {
+    pub fn new(conn: SqliteConnection) -> Self {
+        LoginAttemptDatabase { conn }
+    }
+
+    /// Return the end of the lockout of the corresponding key if it is locked
+    ///
+    /// # Arguments
+    ///
+    /// * `attempt_key` (&str): Key of the attempts *(username or source address)*
+    /// * `now` (u64): Current time *(seconds since the UNIX epoch)*
+    ///
+    /// # Output
+    ///
+    /// * `locked_until` (Option\<u64\>): None if the key is not locked
+    pub fn get_lockout(&self, attempt_key: &str, now: u64) -> Result<Option<u64>> {
+        let mut stmt: CachedStatement = self.conn.prepare_cached("SELECT locked_until FROM login_attempts WHERE attempt_key=:attempt_key")?;
+
+        let locked_until: Result<Vec<u64>> = stmt.query_map(params![attempt_key], |row| {
+            row.get(0)
+        })?.collect();
+
+        Ok(locked_until?.pop().filter(|locked_until| *locked_until > now))
+    }
+
+    /// Record a failed login and lock the key with an exponential backoff
+    ///
+    /// # Arguments
+    ///
+    /// * `attempt_key` (&str): Key of the attempts *(username or source address)*
+    /// * `now` (u64): Current time *(seconds since the UNIX epoch)*
+    pub fn record_failure(&mut self, attempt_key: &str, now: u64) -> Result<()> {
+        // Read then written, the write lock is taken at the start (see database::pool)
+        let tx: Transaction = self.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        // The failures are forgotten after FAILURE_WINDOW without failure
+        let previous_failures: u32 = {
+            let mut stmt: Statement = tx.prepare("SELECT failures, last_failure FROM login_attempts WHERE attempt_key=:attempt_key")?;
+            let previous: Result<Vec<(u32, u64)>> = stmt.query_map(params![attempt_key], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?.collect();
+
+            match previous?.pop() {
+                Some((failures, last_failure)) if now.saturating_sub(last_failure) < FAILURE_WINDOW.as_secs() => failures,
+                _ => 0,
+            }
+        };
+
+        let failures: u32 = previous_failures + 1;
+        let locked_until: u64 = now + lockout_duration(failures).as_secs();
+        tx.execute("REPLACE INTO login_attempts (attempt_key, failures, last_failure, locked_until) VALUES (?1, ?2, ?3, ?4)",
+                   params![attempt_key, failures, now, locked_until])?;
+
+        tx.commit()
+    }
+
+    /// Forget the failed logins of the corresponding key *(after a successful login)*
+    pub fn reset(&mut self, attempt_key: &str) -> Result<()> {
+        let tx: Transaction = self.conn.transaction()?;
+
+        tx.execute("DELETE FROM login_attempts WHERE attempt_key=?1",
+                   params![attempt_key])?;
+
+        tx.commit()
+    }
+}
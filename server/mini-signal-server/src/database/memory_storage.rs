@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, HashMap};
+use mini_signal_protocol::Envelope;
+use rusqlite::{ffi, Error, Result};
+use crate::database::password_database::Credential;
+use crate::database::storage::{generate_guid, AccountStore, LoginAttemptStore, MailboxStore, PrekeyStore, Storage};
+use crate::server::hash::{check_dummy_hash, check_hash, get_hash, needs_rehash};
+use crate::server::rate_limit::{FAILURE_WINDOW, lockout_duration};
+use crate::database::x3dh_keys_database::PublicKeys;
+use crate::server::time::unix_time_now;
+
+type StoredKeys = ([u8; 32], [u8; 32], [[u8; 32]; 2], [u8; 32]); // ik, spk, signature, verifying key
+
+/// Storage kept in memory *(nothing touches the file system, everything is lost when the server stops)*
+///
+/// Follows the behaviour of the sqlite databases, a duplicate username or One Time Pre Key is refused with a constraint error.
+pub struct MemoryStorage {
+    passwords: HashMap<String, String>, // (Key: username) (Value: Argon2id PHC string)
+    opaque_passwords: HashMap<String, Vec<u8>>,
+    #[cfg(feature = "opaque")]
+    opaque_server_setup: Option<Vec<u8>>,
+    server_signing_key: Option<[u8; 32]>,
+    keys: BTreeMap<String, StoredKeys>, // (Key: username)
+    opk_bundle: Vec<([u8; 32], String)>, // (opk, username) in insertion order
+    access_keys: HashMap<String, [u8; 16]>, // (Key: username) (Value: delivery access key)
+    messages: BTreeMap<i64, (String, Envelope)>, // (Key: message id) (Value: receiver, message)
+    sequences: HashMap<String, u64>, // (Key: receiver) (Value: last sequence number)
+    expired_messages: BTreeMap<i64, (String, String, u64)>, // (Key: message id) (Value: sender, receiver, expired at)
+    next_message_id: i64,
+    login_attempts: HashMap<String, (u32, u64, u64)>, // (Key: attempt key) (Value: failures, last failure, locked until)
+}
+
+/// Error returned where sqlite would refuse the row *(primary key already used)*
+fn constraint_error(message: &str) -> Error {
+    Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_CONSTRAINT), Some(message.to_string()))
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage {
+            passwords: HashMap::new(),
+            opaque_passwords: HashMap::new(),
+            #[cfg(feature = "opaque")]
+            opaque_server_setup: None,
+            server_signing_key: None,
+            keys: BTreeMap::new(),
+            opk_bundle: Vec::new(),
+            access_keys: HashMap::new(),
+            messages: BTreeMap::new(),
+            sequences: HashMap::new(),
+            expired_messages: BTreeMap::new(),
+            next_message_id: 1,
+            login_attempts: HashMap::new(),
+        }
+    }
+
+    /// Check that the One Time Pre Keys can be added to the bundle of the user *(no duplicate)*
+    fn check_opk_bundle(&self, username: &str, opk_bundle: &[[u8; 32]]) -> Result<()> {
+        for (i, opk) in opk_bundle.iter().enumerate() {
+            if opk_bundle[..i].contains(opk) || self.opk_bundle.iter().any(|(stored_opk, owner)| stored_opk == opk && owner == username) {
+                return Err(constraint_error("opk_bundle.opk, opk_bundle.username"))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AccountStore for MemoryStorage {
+    fn user_exist(&self, username: String) -> Result<bool> {
+        Ok(self.passwords.contains_key(&username) || self.opaque_passwords.contains_key(&username))
+    }
+
+    fn insert_user(&mut self, username: &str, password: &str) -> Result<()> {
+        if self.passwords.contains_key(username) {
+            return Err(constraint_error("passwords.username"))
+        }
+        self.passwords.insert(username.to_string(), get_hash(password));
+        Ok(())
+    }
+
+    fn check_password(&mut self, username: &str, password: String) -> Result<bool> {
+        let password_hash: &String = match self.passwords.get(username) {
+            Some(password_hash) => password_hash,
+            None => {
+                check_dummy_hash(&password);
+                return Ok(false)
+            },
+        };
+        if !check_hash(&password, password_hash) {
+            return Ok(false)
+        }
+
+        if needs_rehash(password_hash) {
+            self.passwords.insert(username.to_string(), get_hash(&password));
+        }
+        Ok(true)
+    }
+
+    fn change_password(&mut self, username: &str, old_password: String, new_password: &str) -> Result<bool> {
+        if !self.check_password(username, old_password)? {
+            return Ok(false)
+        }
+
+        self.passwords.insert(username.to_string(), get_hash(new_password));
+        Ok(true)
+    }
+
+    #[cfg(feature = "opaque")]
+    fn get_opaque_password_file(&self, username: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.opaque_passwords.get(username).cloned())
+    }
+
+    #[cfg(feature = "opaque")]
+    fn get_opaque_server_setup(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.opaque_server_setup.clone())
+    }
+
+    #[cfg(feature = "opaque")]
+    fn insert_opaque_server_setup(&mut self, setup: Vec<u8>) -> Result<()> {
+        if self.opaque_server_setup.is_some() {
+            return Err(constraint_error("opaque_server_setup.id"))
+        }
+        self.opaque_server_setup = Some(setup);
+        Ok(())
+    }
+
+    fn get_server_signing_key(&self) -> Result<Option<[u8; 32]>> {
+        Ok(self.server_signing_key)
+    }
+
+    fn insert_server_signing_key(&mut self, signing_key: [u8; 32]) -> Result<()> {
+        if self.server_signing_key.is_some() {
+            return Err(constraint_error("server_signing_key.id"))
+        }
+        self.server_signing_key = Some(signing_key);
+        Ok(())
+    }
+}
+
+impl PrekeyStore for MemoryStorage {
+    fn has_x3dh_keys(&self, username: &str) -> Result<bool> {
+        Ok(self.keys.contains_key(username))
+    }
+
+    fn get_all_users(&self) -> Result<Vec<String>> {
+        Ok(self.keys.keys().cloned().collect())
+    }
+
+    fn insert_x3dh_keys(&mut self, username: &str, ik: [u8; 32], spk: [u8; 32], opk_bundle: Vec<[u8; 32]>, signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()> {
+        if self.keys.contains_key(username) {
+            return Err(constraint_error("keys.username"))
+        }
+        self.check_opk_bundle(username, &opk_bundle)?;
+        self.keys.insert(username.to_string(), (ik, spk, signature, verifying_key));
+
+        self.add_opk_bundle(username, opk_bundle)
+    }
+
+    fn update_spk(&mut self, username: &str, spk: [u8;32], signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()> {
+        if let Some(keys) = self.keys.get_mut(username) {
+            keys.1 = spk;
+            keys.2 = signature;
+            keys.3 = verifying_key;
+        }
+        Ok(())
+    }
+
+    fn get_public_keys(&mut self, username: String) -> Result<PublicKeys> {
+        let (ik, spk, signature, verifying_key) = self.keys.get(&username).ok_or(Error::QueryReturnedNoRows)?;
+        let opk: Option<[u8; 32]> = self.opk_bundle.iter().find(|(_, owner)| *owner == username).map(|(opk, _)| *opk);
+
+        Ok((*ik, *spk, opk, *signature, *verifying_key))
+    }
+
+    fn get_identity_key(&self, username: &str) -> Result<Option<[u8; 32]>> {
+        Ok(self.keys.get(username).map(|(ik, _, _, _)| *ik))
+    }
+
+    fn set_access_key(&mut self, username: &str, access_key: [u8; 16]) -> Result<()> {
+        self.access_keys.insert(username.to_string(), access_key);
+        Ok(())
+    }
+
+    fn get_access_key(&self, username: &str) -> Result<Option<[u8; 16]>> {
+        Ok(self.access_keys.get(username).copied())
+    }
+
+    fn add_opk_bundle(&mut self, username: &str, opk_bundle: Vec<[u8;32]>) -> Result<()> {
+        self.check_opk_bundle(username, &opk_bundle)?;
+        self.opk_bundle.extend(opk_bundle.into_iter().map(|opk| (opk, username.to_string())));
+        Ok(())
+    }
+
+    fn delete_opk_key(&mut self, opk: [u8; 32]) -> Result<()> {
+        self.opk_bundle.retain(|(stored_opk, _)| *stored_opk != opk);
+        Ok(())
+    }
+}
+
+impl MailboxStore for MemoryStorage {
+    fn get_user_messages_page(&mut self, username_receiver: &str, after: i64, page_size: u32, byte_budget: usize) -> Result<(Vec<Envelope>, bool)> {
+        let mut messages: Vec<Envelope> = Vec::new();
+        let mut page_bytes: usize = 0;
+        let mut has_more: bool = false;
+
+        for (_, (receiver, message)) in self.messages.range_mut(after + 1..) {
+            if receiver != username_receiver {
+                continue;
+            }
+            page_bytes += message.encrypted_len();
+            if messages.len() == page_size as usize || (page_bytes > byte_budget && !messages.is_empty()) {
+                has_more = true;
+                break;
+            }
+            // Counted with this delivery
+            message.delivery_attempts += 1;
+            messages.push(message.clone());
+        }
+
+        Ok((messages, has_more))
+    }
+
+    fn add_message(&mut self, username_receiver: &str, username_sender: Option<&String>, sealed_sender: Option<Vec<u8>>,
+                   header_encrypted: Vec<u8>, header_nonce: Vec<u8>,
+                   ciphertext: Vec<u8>, nonce: Vec<u8>,
+                   ek_sender: Option<[u8;32]>, opk_used: Option<[u8;32]>, ik_sender: Option<[u8;32]>) -> Result<Envelope> {
+        let message_id: i64 = self.next_message_id;
+        self.next_message_id += 1;
+        let seq: &mut u64 = self.sequences.entry(username_receiver.to_string()).or_insert(0);
+        *seq += 1;
+
+        let message: Envelope = Envelope { message_id, guid: generate_guid(), received_at: unix_time_now(), seq: *seq, sender: username_sender.cloned(), sealed_sender, header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used, ik_sender, delivery_attempts: 0 };
+        self.messages.insert(message_id, (username_receiver.to_string(), message.clone()));
+        Ok(message)
+    }
+
+    fn mark_delivered(&mut self, message_id: i64) -> Result<()> {
+        if let Some((_, message)) = self.messages.get_mut(&message_id) {
+            message.delivery_attempts += 1;
+        }
+        Ok(())
+    }
+
+    fn acknowledge_messages(&mut self, username_receiver: &str, message_ids: &[i64]) -> Result<usize> {
+        let mut deleted: usize = 0;
+        for message_id in message_ids {
+            if self.messages.get(message_id).is_some_and(|(receiver, _)| receiver == username_receiver) {
+                self.messages.remove(message_id);
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    fn expire_messages(&mut self, received_before: u64, now: u64) -> Result<usize> {
+        self.expired_messages.retain(|_, (_, _, expired_at)| *expired_at >= received_before);
+
+        let expired_ids: Vec<i64> = self.messages.iter()
+            .filter(|(_, (_, message))| message.received_at < received_before)
+            .map(|(message_id, _)| *message_id)
+            .collect();
+        for message_id in &expired_ids {
+            let (receiver, message) = self.messages.remove(message_id).unwrap();
+            // No notice for a sealed message, its sender is unknown
+            if let Some(sender) = message.sender {
+                self.expired_messages.entry(*message_id).or_insert((sender, receiver, now));
+            }
+        }
+
+        Ok(expired_ids.len())
+    }
+
+    fn take_expired_messages(&mut self, username_sender: &str) -> Result<Vec<i64>> {
+        let message_ids: Vec<i64> = self.expired_messages.iter()
+            .filter(|(_, (sender, _, _))| sender == username_sender)
+            .map(|(message_id, _)| *message_id)
+            .collect();
+        for message_id in &message_ids {
+            self.expired_messages.remove(message_id);
+        }
+
+        Ok(message_ids)
+    }
+}
+
+impl LoginAttemptStore for MemoryStorage {
+    fn get_lockout(&mut self, attempt_key: &str, now: u64) -> Result<Option<u64>> {
+        Ok(self.login_attempts.get(attempt_key).map(|(_, _, locked_until)| *locked_until).filter(|locked_until| *locked_until > now))
+    }
+
+    fn record_failure(&mut self, attempt_key: &str, now: u64) -> Result<()> {
+        // The failures are forgotten after FAILURE_WINDOW without failure
+        let previous_failures: u32 = match self.login_attempts.get(attempt_key) {
+            Some((failures, last_failure, _)) if now.saturating_sub(*last_failure) < FAILURE_WINDOW.as_secs() => *failures,
+            _ => 0,
+        };
+
+        let failures: u32 = previous_failures + 1;
+        self.login_attempts.insert(attempt_key.to_string(), (failures, now, now + lockout_duration(failures).as_secs()));
+        Ok(())
+    }
+
+    fn reset_failures(&mut self, attempt_key: &str) -> Result<()> {
+        self.login_attempts.remove(attempt_key);
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn register_user(&mut self, username: &str, credential: Credential, ik: [u8; 32], spk: [u8; 32], opk_bundle: Vec<[u8; 32]>, signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()> {
+        // Everything is checked before the first insertion
+        let credential_exists: bool = match credential {
+            Credential::Password(_) => self.passwords.contains_key(username),
+            #[cfg(feature = "opaque")]
+            Credential::OpaquePasswordFile(_) => self.opaque_passwords.contains_key(username),
+        };
+        if credential_exists || self.keys.contains_key(username) {
+            return Err(constraint_error("username"))
+        }
+        self.check_opk_bundle(username, &opk_bundle)?;
+
+        match credential {
+            Credential::Password(password) => { self.passwords.insert(username.to_string(), get_hash(&password)); },
+            #[cfg(feature = "opaque")]
+            Credential::OpaquePasswordFile(password_file) => { self.opaque_passwords.insert(username.to_string(), password_file); },
+        }
+        self.insert_x3dh_keys(username, ik, spk, opk_bundle, signature, verifying_key)
+    }
+
+    fn delete_account(&mut self, username: &str) -> Result<bool> {
+        let existed: bool = self.passwords.remove(username).is_some() | self.opaque_passwords.remove(username).is_some();
+        self.keys.remove(username);
+        self.opk_bundle.retain(|(_, owner)| owner != username);
+        self.access_keys.remove(username);
+        self.messages.retain(|_, (receiver, _)| receiver != username);
+        self.sequences.remove(username);
+        self.expired_messages.retain(|_, (sender, receiver, _)| sender != username && receiver != username);
+
+        Ok(existed)
+    }
+}
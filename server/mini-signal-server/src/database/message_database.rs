@@ -1,76 +1,304 @@
-use rusqlite::{Connection, Result, params, Transaction, Statement};
+use mini_signal_protocol::Envelope;
+use rusqlite::{CachedStatement, Result, params, Transaction, TransactionBehavior, Statement};
+use crate::database::migration::{add_column_if_missing, Migration};
+use crate::database::pool::SqliteConnection;
+use crate::database::storage::generate_guid;
+use crate::server::time::unix_time_now;
+
+pub const MESSAGE_DATABASE_FILE: &str = "messages.db";
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { description: "Create the messages table", apply: create_messages_table },
+    Migration { description: "Count the delivery attempts of the messages", apply: add_delivery_attempts },
+    Migration { description: "Store the reception time of the messages", apply: add_received_at },
+    Migration { description: "Create the expired messages table", apply: create_expired_messages_table },
+    Migration { description: "Index the messages by receiver", apply: index_messages_by_receiver },
+    Migration { description: "Stamp the messages with a GUID and a sequence number", apply: add_guid_and_seq },
+    Migration { description: "Store the sealed sender of the messages", apply: add_sealed_sender },
+    Migration { description: "Give the space of the deleted messages back incrementally", apply: set_incremental_auto_vacuum },
+];
+
+const VACUUM_PAGES: u32 = 1000; // Free pages given back to the file system by each sweep (4 MB with pages of 4 KB)
+
+fn create_messages_table(tx: &Transaction) -> Result<()> {
+    tx.execute("CREATE TABLE IF NOT EXISTS messages (
+        message_id INTEGER PRIMARY KEY AUTOINCREMENT,
+        username_receiver TEXT NOT NULL,
+        username_sender TEXT NOT NULL,
+        header_encrypted BLOB NOT NULL,
+        header_nonce BLOB NOT NULL,
+        ciphertext BLOB NOT NULL,
+        ciphertext_nonce BLOB NOT NULL,
+        ek_sender BLOB,
+        opk_used BLOB,
+        ik_sender BLOB
+    )", ())?;
+    Ok(())
+}
+
+fn add_delivery_attempts(tx: &Transaction) -> Result<()> {
+    add_column_if_missing(tx, "messages", "delivery_attempts", "INTEGER NOT NULL DEFAULT 0")?;
+    Ok(())
+}
+
+fn add_received_at(tx: &Transaction) -> Result<()> {
+    // The retention of the messages already queued starts now
+    if add_column_if_missing(tx, "messages", "received_at", "INTEGER NOT NULL DEFAULT 0")? {
+        tx.execute("UPDATE messages SET received_at = ?1", params![unix_time_now()])?;
+    }
+    Ok(())
+}
+
+fn create_expired_messages_table(tx: &Transaction) -> Result<()> {
+    // Messages deleted by the retention before being acknowledged, kept until the sender asks for them (GetExpiredMessages)
+    tx.execute("CREATE TABLE IF NOT EXISTS expired_messages (
+        message_id INTEGER PRIMARY KEY,
+        username_sender TEXT NOT NULL,
+        username_receiver TEXT NOT NULL,
+        expired_at INTEGER NOT NULL
+    )", ())?;
+    Ok(())
+}
+
+fn index_messages_by_receiver(tx: &Transaction) -> Result<()> {
+    tx.execute("CREATE INDEX IF NOT EXISTS messages_by_receiver ON messages (username_receiver, message_id)", ())?;
+    Ok(())
+}
+
+fn add_guid_and_seq(tx: &Transaction) -> Result<()> {
+    add_column_if_missing(tx, "messages", "guid", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(tx, "messages", "seq", "INTEGER NOT NULL DEFAULT 0")?;
+    tx.execute("UPDATE messages SET guid = lower(hex(randomblob(16))) WHERE guid = ''", ())?;
+    // The messages already queued are numbered in the order of reception
+    tx.execute("UPDATE messages SET seq = (SELECT COUNT(*) FROM messages AS previous
+                WHERE previous.username_receiver = messages.username_receiver AND previous.message_id <= messages.message_id)", ())?;
+
+    // Last sequence number given to each receiver (kept when the messages are deleted)
+    tx.execute("CREATE TABLE IF NOT EXISTS sequences (
+        username_receiver TEXT PRIMARY KEY,
+        last_seq INTEGER NOT NULL
+    )", ())?;
+    tx.execute("INSERT OR IGNORE INTO sequences (username_receiver, last_seq) SELECT username_receiver, MAX(seq) FROM messages GROUP BY username_receiver", ())?;
+    Ok(())
+}
+
+fn add_sealed_sender(tx: &Transaction) -> Result<()> {
+    // The username_sender of a sealed message is '' (unknown to the server)
+    add_column_if_missing(tx, "messages", "sealed_sender", "BLOB")?;
+    Ok(())
+}
+
+fn set_incremental_auto_vacuum(tx: &Transaction) -> Result<()> {
+    // Stored in the header of the file by the VACUUM run after the migrations (see migration::migrate)
+    tx.execute_batch("PRAGMA auto_vacuum = INCREMENTAL")?;
+    Ok(())
+}
 
 pub struct MessageDatabase {
-    conn: Connection
+    conn: SqliteConnection
 }
 
 impl MessageDatabase {
-    pub fn new() -> Result<Self> {
-        let conn: Connection = Connection::open("messages.db")?;
-
-        conn.execute("CREATE TABLE IF NOT EXISTS messages (
-            message_id INTEGER PRIMARY KEY AUTOINCREMENT,
-            username_receiver TEXT NOT NULL,
-            username_sender TEXT NOT NULL,
-            header_encrypted BLOB NOT NULL,
-            header_nonce BLOB NOT NULL,
-            ciphertext BLOB NOT NULL,
-            ciphertext_nonce BLOB NOT NULL,
-            ek_sender BLOB,
-            opk_used BLOB,
-            ik_sender BLOB
-        )", ())?;
-
-        Ok(MessageDatabase { conn })
+    pub fn new(conn: SqliteConnection) -> Self {
+        MessageDatabase { conn }
     }
 
-    pub fn get_all_user_messages(&self, username_receiver: &String) -> Result<Vec<(i64, String, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Option<[u8;32]>, Option<[u8;32]>, Option<[u8;32]>)>> {
-        let mut stmt: Statement = self.conn.prepare("SELECT message_id, username_sender, header_encrypted, header_nonce, ciphertext, ciphertext_nonce, ek_sender, opk_used, ik_sender FROM messages WHERE username_receiver=?")?;
+    /// Return a page of the messages of the user not acknowledged yet, and count this delivery
+    ///
+    /// The page stops at `page_size` messages or when the encrypted messages exceed `byte_budget` bytes *(at least one message)*.
+    ///
+    /// # Arguments
+    ///
+    /// * `username_receiver` (&str): Username of the receiver
+    /// * `after` (i64): Cursor, only the messages with a greater id are returned *(id of the last message of the previous page, 0 for the first page)*
+    /// * `page_size` (u32): Maximum number of messages
+    /// * `byte_budget` (usize): Maximum size of the encrypted messages
+    ///
+    /// # Output
+    ///
+    /// * `(messages, has_more)` (Result\<(Vec\<Envelope\>, bool)\>): Messages *(with this delivery counted)* and `true` if other messages are waiting after this page
+    pub fn get_user_messages_page(&mut self, username_receiver: &str, after: i64, page_size: u32, byte_budget: usize) -> Result<(Vec<Envelope>, bool)> {
+        // Read then written, the write lock is taken at the start (see database::pool)
+        let tx: Transaction = self.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
 
-        let mut result = stmt.query_map(&[username_receiver], |row| {
-            let message_id: i64 = row.get(0)?;
-            let username_sender: String = row.get(1)?;
-            let header_encrypted: Vec<u8> = row.get(2)?;
-            let header_nonce: Vec<u8> = row.get(3)?;
-            let ciphertext: Vec<u8> = row.get(4)?;
-            let nonce: Vec<u8> = row.get(5)?;
-            let ek_sender: Option<[u8;32]> = if row.get::<usize, [u8;32]>(6).is_ok() { Some(row.get(6).unwrap()) } else { None };
-            let opk_used: Option<[u8;32]> = if row.get::<usize, [u8;32]>(7).is_ok() { Some(row.get(7).unwrap()) } else { None };
-            let ik_sender: Option<[u8;32]> = if row.get::<usize, [u8;32]>(8).is_ok() { Some(row.get(8).unwrap()) } else { None };
+        let (messages, has_more): (Vec<Envelope>, bool) = {
+            // One more message than the page size to know if there is another page
+            let mut stmt: CachedStatement = tx.prepare_cached("SELECT message_id, NULLIF(username_sender, ''), header_encrypted, header_nonce, ciphertext, ciphertext_nonce, ek_sender, opk_used, ik_sender, delivery_attempts, guid, received_at, seq, sealed_sender FROM messages WHERE username_receiver=?1 AND message_id>?2 ORDER BY message_id ASC LIMIT ?3")?;
 
-            Ok((message_id, username_sender, header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used, ik_sender))
-        })?;
+            let result = stmt.query_map(params![username_receiver, after, page_size + 1], |row| {
+                Ok(Envelope {
+                    message_id: row.get(0)?,
+                    guid: row.get(10)?,
+                    received_at: row.get(11)?,
+                    seq: row.get(12)?,
+                    sender: row.get(1)?,
+                    sealed_sender: row.get(13)?,
+                    header_encrypted: row.get(2)?,
+                    header_nonce: row.get(3)?,
+                    ciphertext: row.get(4)?,
+                    nonce: row.get(5)?,
+                    ek_sender: if row.get::<usize, [u8;32]>(6).is_ok() { Some(row.get(6).unwrap()) } else { None },
+                    opk_used: if row.get::<usize, [u8;32]>(7).is_ok() { Some(row.get(7).unwrap()) } else { None },
+                    ik_sender: if row.get::<usize, [u8;32]>(8).is_ok() { Some(row.get(8).unwrap()) } else { None },
+                    delivery_attempts: row.get::<usize, u32>(9)? + 1, // Counted with this delivery
+                })
+            })?;
 
-        let mut messages: Vec<(i64, String, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Option<[u8;32]>, Option<[u8;32]>, Option<[u8;32]>)> = Vec::new();
+            let mut messages: Vec<Envelope> = Vec::new();
+            let mut page_bytes: usize = 0;
+            let mut has_more: bool = false;
 
-        while let Some(result) = result.next() {
-            messages.push(result.unwrap());
+            for result in result {
+                let message = result?;
+                page_bytes += message.encrypted_len();
+                if messages.len() == page_size as usize || (page_bytes > byte_budget && !messages.is_empty()) {
+                    has_more = true;
+                    break;
+                }
+                messages.push(message);
+            };
+            (messages, has_more)
         };
 
-        Ok(messages)
+        {
+            let mut stmt: CachedStatement = tx.prepare_cached("UPDATE messages SET delivery_attempts = delivery_attempts + 1 WHERE message_id=?1")?;
+            for message in &messages {
+                stmt.execute(params![message.message_id])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok((messages, has_more))
     }
 
-    pub fn add_message(&mut self, username_receiver: &String, username_sender: &String,
+    /// Queue a message with the next sequence number of the receiver
+    ///
+    /// # Arguments
+    ///
+    /// * `username_sender` (Option\<&String\>): Username of the sender, None for a sealed message
+    /// * `sealed_sender` (Option\<Vec\<u8\>\>): Sender certificate encrypted to the receiver *(only for a sealed message)*
+    ///
+    /// # Output
+    ///
+    /// * `message` (Result\<Envelope\>): Message stamped by the server *(id, guid, reception time and sequence number)*, not delivered yet
+    #[allow(clippy::too_many_arguments)] // Fields of the message as sent by the client
+    pub fn add_message(&mut self, username_receiver: &str, username_sender: Option<&String>, sealed_sender: Option<Vec<u8>>,
                        header_encrypted: Vec<u8>, header_nonce: Vec<u8>,
                        ciphertext: Vec<u8>, nonce: Vec<u8>,
-                       ek_sender: Option<[u8;32]>, opk_used: Option<[u8;32]>, ik_sender: Option<[u8;32]>) -> Result<()> {
+                       ek_sender: Option<[u8;32]>, opk_used: Option<[u8;32]>, ik_sender: Option<[u8;32]>) -> Result<Envelope> {
 
         let tx: Transaction = self.conn.transaction()?;
 
-        tx.execute("INSERT INTO messages
-        (username_receiver, username_sender, header_encrypted, header_nonce, ciphertext, ciphertext_nonce, ek_sender, opk_used, ik_sender)\
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
-                   (username_receiver, username_sender, header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used, ik_sender))?;
+        let seq: u64 = tx.prepare_cached("INSERT INTO sequences (username_receiver, last_seq) VALUES (?1, 1)
+        ON CONFLICT (username_receiver) DO UPDATE SET last_seq = last_seq + 1 RETURNING last_seq")?
+            .query_row(params![username_receiver], |row| row.get(0))?;
+        let guid: String = generate_guid();
+        let received_at: u64 = unix_time_now();
 
-        tx.commit()
+        tx.prepare_cached("INSERT INTO messages
+        (username_receiver, username_sender, header_encrypted, header_nonce, ciphertext, ciphertext_nonce, ek_sender, opk_used, ik_sender, received_at, guid, seq, sealed_sender)\
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)")?
+            .execute((username_receiver, username_sender.map_or("", |sender| sender.as_str()), &header_encrypted, &header_nonce, &ciphertext, &nonce, ek_sender, opk_used, ik_sender, received_at, &guid, seq, &sealed_sender))?;
+        let message_id: i64 = tx.last_insert_rowid();
+
+        tx.commit()?;
+        Ok(Envelope { message_id, guid, received_at, seq, sender: username_sender.cloned(), sealed_sender, header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used, ik_sender, delivery_attempts: 0 })
     }
 
-    pub fn delete_message(&mut self, message_id: i64) -> Result<()> {
+    /// Count a delivery of the message *(pushed over the WebSocket connection)*
+    pub fn mark_delivered(&mut self, message_id: i64) -> Result<()> {
         let tx: Transaction = self.conn.transaction()?;
 
-        tx.execute("DELETE FROM messages WHERE message_id=?1",
-                   params![message_id])?;
+        tx.prepare_cached("UPDATE messages SET delivery_attempts = delivery_attempts + 1 WHERE message_id=?1")?
+            .execute(params![message_id])?;
 
         tx.commit()
     }
+
+    /// Delete the messages acknowledged by the receiver
+    ///
+    /// # Arguments
+    ///
+    /// * `username_receiver` (&str): Username of the receiver *(the ids of other users are ignored)*
+    /// * `message_ids` (&Vec\<i64\>): Ids of the messages received
+    ///
+    /// # Output
+    ///
+    /// * `deleted` (Result\<usize\>): Number of messages deleted
+    pub fn acknowledge_messages(&mut self, username_receiver: &str, message_ids: &[i64]) -> Result<usize> {
+        let tx: Transaction = self.conn.transaction()?;
+
+        let mut deleted: usize = 0;
+        {
+            let mut stmt: CachedStatement = tx.prepare_cached("DELETE FROM messages WHERE message_id=?1 AND username_receiver=?2")?;
+            for message_id in message_ids {
+                deleted += stmt.execute(params![message_id, username_receiver])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    /// Delete the messages received before `received_before` and keep a notice for their sender
+    ///
+    /// The notices older than `received_before` are deleted too *(the senders have had the whole retention period to ask for them)*.
+    /// No notice is kept for the sealed messages, their sender is unknown.
+    ///
+    /// # Arguments
+    ///
+    /// * `received_before` (u64): End of the retention *(seconds since the UNIX epoch)*
+    /// * `now` (u64): Current time *(seconds since the UNIX epoch)*
+    ///
+    /// # Output
+    ///
+    /// * `expired` (Result\<usize\>): Number of messages deleted
+    pub fn expire_messages(&mut self, received_before: u64, now: u64) -> Result<usize> {
+        let tx: Transaction = self.conn.transaction()?;
+
+        tx.execute("DELETE FROM expired_messages WHERE expired_at < ?1",
+                   params![received_before])?;
+        tx.execute("INSERT OR IGNORE INTO expired_messages (message_id, username_sender, username_receiver, expired_at)
+                    SELECT message_id, username_sender, username_receiver, ?2 FROM messages WHERE received_at < ?1 AND username_sender != ''",
+                   params![received_before, now])?;
+        let expired: usize = tx.execute("DELETE FROM messages WHERE received_at < ?1",
+                                        params![received_before])?;
+
+        tx.commit()?;
+        Ok(expired)
+    }
+
+    /// Return and forget the messages of the sender that expired before being acknowledged
+    ///
+    /// # Arguments
+    ///
+    /// * `username_sender` (&str): Username of the sender
+    ///
+    /// # Output
+    ///
+    /// * `message_ids` (Result\<Vec\<i64\>\>): Ids returned when the messages were sent
+    pub fn take_expired_messages(&mut self, username_sender: &str) -> Result<Vec<i64>> {
+        // Read then written, the write lock is taken at the start (see database::pool)
+        let tx: Transaction = self.conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+        let message_ids: Vec<i64> = {
+            let mut stmt: Statement = tx.prepare("SELECT message_id FROM expired_messages WHERE username_sender=?1 ORDER BY message_id ASC")?;
+            let message_ids: Result<Vec<i64>> = stmt.query_map(params![username_sender], |row| row.get(0))?.collect();
+            message_ids?
+        };
+        tx.execute("DELETE FROM expired_messages WHERE username_sender=?1",
+                   params![username_sender])?;
+
+        tx.commit()?;
+        Ok(message_ids)
+    }
+
+    /// Give the space of the deleted messages back to the file system, at most `VACUUM_PAGES` pages *(the file is not rebuilt)*
+    pub fn compact(&self) -> Result<()> {
+        // One row for each page freed
+        let mut stmt: Statement = self.conn.prepare(&format!("PRAGMA incremental_vacuum({})", VACUUM_PAGES))?;
+        let mut rows = stmt.query([])?;
+        while rows.next()?.is_some() {}
+        Ok(())
+    }
 }
\ No newline at end of file
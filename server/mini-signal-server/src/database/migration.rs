@@ -0,0 +1,200 @@
+use std::fmt;
+use std::path::Path;
+use rusqlite::{Connection, params, Result, Transaction, TransactionBehavior};
+use crate::database::{login_attempt_database, message_database, password_database, x3dh_keys_database};
+use crate::server::time::unix_time_now;
+
+/// Databases of the data directory with their migrations
+pub const DATABASES: &[(&str, &[Migration])] = &[
+    (password_database::PASSWORD_DATABASE_FILE, password_database::MIGRATIONS),
+    (x3dh_keys_database::X3DH_DATABASE_FILE, x3dh_keys_database::MIGRATIONS),
+    (message_database::MESSAGE_DATABASE_FILE, message_database::MIGRATIONS),
+    (login_attempt_database::LOGIN_ATTEMPT_DATABASE_FILE, login_attempt_database::MIGRATIONS),
+];
+
+/// Step of the schema of a database *(the version of the schema is the number of migrations applied)*
+///
+/// A migration is never modified once released, a change of the schema is a new migration at the end of the list.
+pub struct Migration {
+    pub description: &'static str,
+    pub apply: fn(&Transaction) -> Result<()>,
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sqlite(rusqlite::Error),
+    NewerSchema { database: &'static str, version: u32, supported: u32 }, // Migrated by a newer version of the server
+}
+
+/// Return the version of the schema *(0 for a database created before the migrations)*
+pub fn schema_version(conn: &Connection) -> Result<u32> {
+    let has_schema_version: bool = conn.prepare("SELECT 1 FROM sqlite_master WHERE type='table' AND name='schema_version'")?.exists([])?;
+    if !has_schema_version {
+        return Ok(0)
+    }
+    conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row| row.get(0))
+}
+
+/// Create the databases of the data directory or migrate them to the current schema
+///
+/// # Output
+///
+/// * `versions` (Result\<Vec\<(&str, u32, u32)\>, MigrationError\>): (database file, version before, version after) for each database
+pub fn migrate_data_dir(data_dir: &Path) -> std::result::Result<Vec<(&'static str, u32, u32)>, MigrationError> {
+    let mut versions: Vec<(&'static str, u32, u32)> = Vec::new();
+    for (database, migrations) in DATABASES {
+        let mut conn: Connection = Connection::open(data_dir.join(database))?;
+        let (previous_version, version) = migrate(&mut conn, database, migrations)?;
+        versions.push((database, previous_version, version));
+    }
+    Ok(versions)
+}
+
+/// Apply the migrations that have not been applied yet, in order, each one in its own transaction
+///
+/// Each version is checked again inside its transaction, so two processes can migrate the same database at the same time.
+/// The file is rebuilt *(VACUUM)* once the migrations are applied.
+///
+/// # Arguments
+///
+/// * `conn` (&mut Connection): Connection to the database
+/// * `database` (&'static str): Database file *(for the errors)*
+/// * `migrations` (&\[Migration\]): Every migration of the database, the oldest first
+///
+/// # Output
+///
+/// * `(previous_version, version)` (Result\<(u32, u32), MigrationError\>): Version before and after the migration
+pub fn migrate(conn: &mut Connection, database: &'static str, migrations: &[Migration]) -> std::result::Result<(u32, u32), MigrationError> {
+    conn.execute("CREATE TABLE IF NOT EXISTS schema_version (
+        version INTEGER PRIMARY KEY,
+        description TEXT NOT NULL,
+        applied_at INTEGER NOT NULL
+    )", ())?;
+
+    let previous_version: u32 = schema_version(conn)?;
+    if previous_version as usize > migrations.len() {
+        return Err(MigrationError::NewerSchema { database, version: previous_version, supported: migrations.len() as u32 })
+    }
+
+    for (index, migration) in migrations.iter().enumerate() {
+        let version: u32 = index as u32 + 1;
+        let tx: Transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        if schema_version(&tx)? >= version {
+            continue;
+        }
+
+        (migration.apply)(&tx)?;
+        tx.execute("INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+                   params![version, migration.description, unix_time_now()])?;
+        tx.commit()?;
+    }
+
+    // A migration can change a setting stored in the header of the file (auto_vacuum), applied when the file is rebuilt
+    if previous_version < migrations.len() as u32 {
+        conn.execute("VACUUM", ())?;
+    }
+
+    Ok((previous_version, migrations.len() as u32))
+}
+
+/// Add the column if the table does not have it *(the databases created before the migrations may already have it)*
+///
+/// # Output
+///
+/// * `added` (Result\<bool\>): `false` if the column already existed
+pub fn add_column_if_missing(tx: &Transaction, table: &str, column: &str, definition: &str) -> Result<bool> {
+    let has_column: bool = tx.prepare("SELECT 1 FROM pragma_table_info(?1) WHERE name=?2")?.exists(params![table, column])?;
+    if !has_column {
+        tx.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), ())?;
+    }
+    Ok(!has_column)
+}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(error: rusqlite::Error) -> Self {
+        MigrationError::Sqlite(error)
+    }
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrationError::Sqlite(error) => write!(f, "{}", error),
+            MigrationError::NewerSchema { database, version, supported } => write!(f, "{} has the schema version {}, newer than the server (version {}), update the server", database, version, supported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::storage::{StorageBackend, Storage};
+    use crate::database::test_data_dir;
+    use crate::server::config::StorageKind;
+    use mini_signal_protocol::Envelope;
+    use std::path::PathBuf;
+
+    /// Data directory of a server released before the migrations *(schema version 0)*
+    fn baseline_data_dir(name: &str) -> PathBuf {
+        let data_dir: PathBuf = test_data_dir(name);
+        let conn: Connection = Connection::open(data_dir.join(password_database::PASSWORD_DATABASE_FILE)).unwrap();
+        conn.execute_batch("CREATE TABLE passwords (username TEXT PRIMARY KEY NOT NULL, password TEXT NOT NULL);
+                            INSERT INTO passwords VALUES ('bob', 'hash');").unwrap();
+        let conn: Connection = Connection::open(data_dir.join(x3dh_keys_database::X3DH_DATABASE_FILE)).unwrap();
+        conn.execute_batch("CREATE TABLE keys (username TEXT NOT NULL PRIMARY KEY, ik BLOB NOT NULL, spk BLOB NOT NULL,
+                                               signature_r BLOB NOT NULL, signature_s BLOB NOT NULL, verifying_key BLOB NOT NULL);
+                            CREATE TABLE opk_bundle (opk BLOB NOT NULL, username TEXT NOT NULL,
+                                                     FOREIGN KEY(username) REFERENCES keys(username), PRIMARY KEY(opk, username));
+                            INSERT INTO keys VALUES ('bob', x'01', x'02', x'03', x'04', x'05');
+                            INSERT INTO opk_bundle VALUES (x'06', 'bob');").unwrap();
+        let conn: Connection = Connection::open(data_dir.join(message_database::MESSAGE_DATABASE_FILE)).unwrap();
+        conn.execute_batch("CREATE TABLE messages (message_id INTEGER PRIMARY KEY AUTOINCREMENT, username_receiver TEXT NOT NULL,
+                                                   username_sender TEXT NOT NULL, header_encrypted BLOB NOT NULL, header_nonce BLOB NOT NULL,
+                                                   ciphertext BLOB NOT NULL, ciphertext_nonce BLOB NOT NULL, ek_sender BLOB, opk_used BLOB, ik_sender BLOB);
+                            INSERT INTO messages (username_receiver, username_sender, header_encrypted, header_nonce, ciphertext, ciphertext_nonce)
+                            VALUES ('bob', 'alice', x'01', x'02', x'03', x'04');").unwrap();
+        data_dir
+    }
+
+    #[test]
+    fn baseline_schema_is_migrated() {
+        let data_dir: PathBuf = baseline_data_dir("baseline");
+        let versions: Vec<(&str, u32, u32)> = migrate_data_dir(&data_dir).unwrap();
+        assert_eq!(versions, DATABASES.iter().map(|(database, migrations)| (*database, 0, migrations.len() as u32)).collect::<Vec<_>>());
+        let conn: Connection = Connection::open(data_dir.join(message_database::MESSAGE_DATABASE_FILE)).unwrap();
+        assert_eq!(conn.query_row("PRAGMA auto_vacuum", [], |row| row.get::<usize, u32>(0)).unwrap(), 2); // Incremental
+
+        // The rows stored before the migrations are read by the current server
+        let storage: StorageBackend = StorageBackend::new(StorageKind::Sqlite, &data_dir, 1).unwrap();
+        storage.with_storage(|storage: &mut dyn Storage| {
+            assert!(storage.user_exist("bob".to_string()).unwrap());
+            assert!(storage.has_x3dh_keys("bob").unwrap());
+            let (messages, has_more): (Vec<Envelope>, bool) = storage.get_user_messages_page("bob", 0, 10, 1024).unwrap();
+            assert_eq!(messages.len(), 1);
+            assert_eq!((messages[0].sender.as_deref(), messages[0].ciphertext.as_slice(), messages[0].delivery_attempts), (Some("alice"), &[3][..], 1));
+            assert!(!has_more);
+        }).unwrap();
+
+        // Nothing left to apply
+        let versions: Vec<(&str, u32, u32)> = migrate_data_dir(&data_dir).unwrap();
+        assert!(versions.iter().all(|(_, previous_version, version)| previous_version == version));
+    }
+
+    #[test]
+    fn newer_schema_is_refused() {
+        let data_dir: PathBuf = test_data_dir("newer");
+        migrate_data_dir(&data_dir).unwrap();
+        let supported: u32 = password_database::MIGRATIONS.len() as u32;
+        let conn: Connection = Connection::open(data_dir.join(password_database::PASSWORD_DATABASE_FILE)).unwrap();
+        conn.execute("INSERT INTO schema_version (version, description, applied_at) VALUES (?1, 'Added by a newer server', 0)", params![supported + 1]).unwrap();
+
+        match migrate_data_dir(&data_dir) {
+            Err(MigrationError::NewerSchema { database, version, supported: server_version }) => {
+                assert_eq!((database, version, server_version), (password_database::PASSWORD_DATABASE_FILE, supported + 1, supported));
+            },
+            result => panic!("Unexpected result of the migration: {:?}", result),
+        }
+        // Not modified
+        assert_eq!(schema_version(&conn).unwrap(), supported + 1);
+    }
+}
@@ -1,3 +1,17 @@
+pub mod login_attempt_database;
+pub mod memory_storage;
 pub mod message_database;
+pub mod migration;
 pub mod password_database;
-pub mod x3dh_keys_database;
\ No newline at end of file
+pub mod pool;
+pub mod sqlite_storage;
+pub mod storage;
+pub mod x3dh_keys_database;
+/// New empty data directory for the tests of the sqlite databases
+#[cfg(test)]
+pub fn test_data_dir(name: &str) -> std::path::PathBuf {
+    let data_dir: std::path::PathBuf = std::env::temp_dir().join(format!("mini-signal-db-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&data_dir);
+    std::fs::create_dir_all(&data_dir).unwrap();
+    data_dir
+}
@@ -1,21 +1,87 @@
-use rusqlite::{Connection, params, Result, Statement, Transaction};
+use std::path::{Path, PathBuf};
+use rusqlite::{CachedStatement, Connection, params, Result, Statement, Transaction, TransactionBehavior};
+use crate::database::message_database::MESSAGE_DATABASE_FILE;
+use crate::database::migration::Migration;
+use crate::database::pool::SqliteConnection;
+use crate::database::x3dh_keys_database::X3DH_DATABASE_FILE;
+use crate::server::hash::{check_dummy_hash, check_hash, constant_time_eq, get_hash, is_legacy_hash, needs_rehash};
+use mini_signal_protocol::normalize_username;
 
-pub struct PasswordDatabase {
-    conn: Connection
+pub const PASSWORD_DATABASE_FILE: &str = "passwords.db";
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { description: "Create the passwords table", apply: create_passwords_table },
+    Migration { description: "Create the OPAQUE tables", apply: create_opaque_tables },
+    Migration { description: "Create the server signing key table", apply: create_server_signing_key_table },
+    Migration { description: "Create the pending operations table", apply: create_pending_operations_table },
+];
+
+// Operations over several databases, recorded before their first commit (a registration is a single transaction)
+const REGISTER: &str = "register";
+const DELETE: &str = "delete";
+const RENAME: &str = "rename";
+
+fn create_passwords_table(tx: &Transaction) -> Result<()> {
+    tx.execute("CREATE TABLE IF NOT EXISTS passwords (
+         username TEXT PRIMARY KEY NOT NULL,
+         password TEXT NOT NULL
+     )", ())?;
+    Ok(())
 }
 
-impl PasswordDatabase {
+fn create_opaque_tables(tx: &Transaction) -> Result<()> {
+    // OPAQUE password files (the server never receives the password of these users)
+    tx.execute("CREATE TABLE IF NOT EXISTS opaque_passwords (
+         username TEXT PRIMARY KEY NOT NULL,
+         password_file BLOB NOT NULL
+     )", ())?;
 
-    /// Create the Password database if not exists
-    pub fn new() -> Result<Self> {
-        let conn: Connection = Connection::open("passwords.db")?;
+    // OPAQUE server setup (single row)
+    tx.execute("CREATE TABLE IF NOT EXISTS opaque_server_setup (
+         id INTEGER PRIMARY KEY CHECK (id = 0),
+         setup BLOB NOT NULL
+     )", ())?;
+    Ok(())
+}
 
-        conn.execute("CREATE TABLE IF NOT EXISTS passwords (
-             username TEXT PRIMARY KEY NOT NULL,
-             password TEXT NOT NULL
-         )", ())?;
+fn create_server_signing_key_table(tx: &Transaction) -> Result<()> {
+    // Ed25519 key signing the sender certificates of the sealed messages (single row)
+    tx.execute("CREATE TABLE IF NOT EXISTS server_signing_key (
+         id INTEGER PRIMARY KEY CHECK (id = 0),
+         signing_key BLOB NOT NULL
+     )", ())?;
+    Ok(())
+}
+
+fn create_pending_operations_table(tx: &Transaction) -> Result<()> {
+    // Deleted once the last database of the operation is committed (a transaction over attached databases is not atomic with WAL)
+    tx.execute("CREATE TABLE IF NOT EXISTS pending_operations (
+         username TEXT PRIMARY KEY NOT NULL,
+         operation TEXT NOT NULL,
+         new_username TEXT
+     )", ())?;
+    Ok(())
+}
+
+/// Credential stored when a user registers
+pub enum Credential {
+    Password(String), // Hashed with Argon2id before being stored
+    #[cfg(feature = "opaque")]
+    OpaquePasswordFile(Vec<u8>),
+}
 
-        Ok(PasswordDatabase { conn })
+pub struct PasswordDatabase {
+    conn: SqliteConnection,
+    data_dir: PathBuf, // Used to attach the X3DH keys and messages databases
+}
+
+impl PasswordDatabase {
+    /// # Arguments
+    ///
+    /// * `conn` (SqliteConnection): Connection of the password database pool
+    /// * `data_dir` (&Path): Directory of the databases *(to attach the X3DH keys and messages databases)*
+    pub fn new(conn: SqliteConnection, data_dir: &Path) -> Self {
+        PasswordDatabase { conn, data_dir: data_dir.to_path_buf() }
     }
 
     /// Check if the username exist
@@ -28,7 +94,8 @@ impl PasswordDatabase {
     ///
     /// * bool
     pub fn user_exist(&self, username: String) -> Result<bool> {
-        let mut stmt: Statement = self.conn.prepare("SELECT 1 FROM passwords WHERE username=:username")?;
+        let mut stmt: CachedStatement = self.conn.prepare_cached("SELECT 1 FROM passwords WHERE username=:username
+                                                                  UNION SELECT 1 FROM opaque_passwords WHERE username=:username")?;
         let exists: bool = stmt.exists(&[(":username", username.as_str())])?;
 
         Ok(exists)
@@ -39,33 +106,547 @@ impl PasswordDatabase {
     /// # Arguments
     ///
     /// * `username` (String): Username
-    /// * `password` (String): Password *(Argon2id)*
-    pub fn insert_user(&mut self, username: &String, password: &String) -> Result<()> {
+    /// * `password` (String): Password *(hashed with Argon2id and a random salt before being stored)*
+    pub fn insert_user(&mut self, username: &str, password: &str) -> Result<()> {
         let tx: Transaction = self.conn.transaction()?;
 
-        let password_hash = password;//PasswordDatabase::get_hash(password);
+        let password_hash: String = get_hash(password);
         tx.execute("INSERT INTO passwords (username, password) VALUES (?1, ?2)",
                    (username, password_hash))?;
 
         tx.commit()
     }
 
-    /// Check if the hash input correspond to the one store
+    /// Check if the password correspond to the hash stored
+    ///
+    /// The password is rehashed when the hash stored uses outdated Argon2 parameters,
+    /// or when it has been stored before server-side hashing.
     ///
     /// # Arguments
     ///
     /// * `username` (String): Username
     /// * `password` (String): Password
-    pub fn check_password(&self, username: &String, password: String) -> Result<bool> {
-        let mut stmt: Statement = self.conn.prepare("SELECT password FROM passwords WHERE username=:username;")?;
+    pub fn check_password(&mut self, username: &str, password: String) -> Result<bool> {
+        let mut stmt: CachedStatement = self.conn.prepare_cached("SELECT password FROM passwords WHERE username=:username;")?;
 
         let req_user_password: Result<Vec<String>> = stmt.query_map(params![username], |row| {
-            Ok(row.get(0)?)
+            row.get(0)
         })?.collect();
 
         let user_password_hash: Vec<String> = req_user_password?;
-        if user_password_hash.is_empty() { return Ok(false) };
+        drop(stmt);
+        if user_password_hash.is_empty() {
+            check_dummy_hash(&password);
+            return Ok(false)
+        }
+
+        if check_hash(&password, &user_password_hash[0]) {
+            if needs_rehash(&user_password_hash[0]) {
+                self.update_password(username, &password)?;
+            }
+            return Ok(true)
+        }
+
+        // Password stored verbatim (before server-side hashing)
+        if is_legacy_hash(&user_password_hash[0]) && constant_time_eq(password.as_bytes(), user_password_hash[0].as_bytes()) {
+            self.update_password(username, &password)?;
+            return Ok(true)
+        }
+
+        Ok(false)
+    }
+
+    /// Change the password of the corresponding username if the old password is valid
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (String): Username
+    /// * `old_password` (String): Current password
+    /// * `new_password` (String): New password
+    ///
+    /// # Output
+    ///
+    /// * bool: `true` if the password has been changed
+    pub fn change_password(&mut self, username: &str, old_password: String, new_password: &str) -> Result<bool> {
+        if !self.check_password(username, old_password)? {
+            return Ok(false)
+        }
+
+        self.update_password(username, new_password)?;
+        Ok(true)
+    }
+
+    /// Replace the password hash of the corresponding username
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (String): Username
+    /// * `password` (String): New password *(hashed with Argon2id and a random salt before being stored)*
+    fn update_password(&mut self, username: &str, password: &str) -> Result<()> {
+        let tx: Transaction = self.conn.transaction()?;
+
+        let password_hash: String = get_hash(password);
+        tx.execute("UPDATE passwords SET password = ?1 WHERE username = ?2",
+                   (password_hash, username))?;
+
+        tx.commit()
+    }
 
-        Ok(password == user_password_hash[0])
+    /// Return the OPAQUE password file of the corresponding username
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (String): Username
+    ///
+    /// # Output
+    ///
+    /// * `password_file` (Option\<Vec\<u8\>\>): None if the user is not registered with OPAQUE
+    #[cfg(feature = "opaque")]
+    pub fn get_opaque_password_file(&self, username: &str) -> Result<Option<Vec<u8>>> {
+        let mut stmt: Statement = self.conn.prepare("SELECT password_file FROM opaque_passwords WHERE username=:username")?;
+
+        let password_file: Result<Vec<Vec<u8>>> = stmt.query_map(params![username], |row| {
+            row.get(0)
+        })?.collect();
+
+        Ok(password_file?.pop())
+    }
+
+    /// Return the OPAQUE server setup, None if it has not been generated yet
+    #[cfg(feature = "opaque")]
+    pub fn get_opaque_server_setup(&self) -> Result<Option<Vec<u8>>> {
+        let mut stmt: Statement = self.conn.prepare("SELECT setup FROM opaque_server_setup WHERE id = 0")?;
+
+        let setup: Result<Vec<Vec<u8>>> = stmt.query_map(params![], |row| {
+            row.get(0)
+        })?.collect();
+
+        Ok(setup?.pop())
+    }
+
+    /// Store the OPAQUE server setup *(it must never change, otherwise every OPAQUE user has to register again)*
+    #[cfg(feature = "opaque")]
+    pub fn insert_opaque_server_setup(&mut self, setup: Vec<u8>) -> Result<()> {
+        let tx: Transaction = self.conn.transaction()?;
+
+        tx.execute("INSERT INTO opaque_server_setup (id, setup) VALUES (0, ?1)",
+                   params![setup])?;
+
+        tx.commit()
     }
-}
\ No newline at end of file
+
+    /// Return the Ed25519 key signing the sender certificates, None if it has not been generated yet
+    pub fn get_server_signing_key(&self) -> Result<Option<[u8; 32]>> {
+        let mut stmt: Statement = self.conn.prepare("SELECT signing_key FROM server_signing_key WHERE id = 0")?;
+
+        let signing_key: Result<Vec<[u8; 32]>> = stmt.query_map(params![], |row| {
+            row.get(0)
+        })?.collect();
+
+        Ok(signing_key?.pop())
+    }
+
+    /// Store the Ed25519 key signing the sender certificates *(the certificates already issued are refused if it changes)*
+    pub fn insert_server_signing_key(&mut self, signing_key: [u8; 32]) -> Result<()> {
+        let tx: Transaction = self.conn.transaction()?;
+
+        tx.execute("INSERT INTO server_signing_key (id, signing_key) VALUES (0, ?1)",
+                   params![signing_key])?;
+
+        tx.commit()
+    }
+
+    /// Create the user and publish its X3DH keys *(everything is stored or nothing is)*
+    ///
+    /// The credential and the X3DH keys are written in one transaction over the attached databases. With WAL, its commit
+    /// is atomic in each database only: if the server stops between the two commits, the credential without keys is
+    /// removed at the next start *(see `complete_pending_operations`)*.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (String): Username
+    /// * `credential` (Credential): Password or OPAQUE password file
+    /// * `ik` (\[u8;32\]): Identity Key *(public key)*
+    /// * `spk` (\[u8; 32\]): Signed Pre Key *(public key)*
+    /// * `opk_bundle` (Vec\<\[u8; 32\]\>): Bundle of One Time Pre Key
+    /// * `signature` (\[\[u8;32\]; 2\]): Signature *(\[r_bytes, s_bytes\])*
+    /// * `verifying_key` (\[u8; 32\]): Verifying Key
+    #[allow(clippy::too_many_arguments)] // X3DH keys as sent by the client (see Action::Register)
+    pub fn register_user(&mut self, username: &str, credential: Credential, ik: [u8; 32], spk: [u8; 32], opk_bundle: Vec<[u8; 32]>, signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()> {
+        self.with_attached(|conn| {
+            let tx: Transaction = conn.transaction()?;
+            // Refused while another operation on the username is pending (the row does not outlive the transaction)
+            tx.execute("INSERT INTO pending_operations (username, operation) VALUES (?1, ?2)", params![username, REGISTER])?;
+            tx.execute("DELETE FROM pending_operations WHERE username = ?1", params![username])?;
+            match credential {
+                Credential::Password(password) => tx.execute("INSERT INTO passwords (username, password) VALUES (?1, ?2)",
+                                                             (username, get_hash(&password)))?,
+                #[cfg(feature = "opaque")]
+                Credential::OpaquePasswordFile(password_file) => tx.execute("INSERT INTO opaque_passwords (username, password_file) VALUES (?1, ?2)",
+                                                                            (username, password_file))?,
+            };
+            tx.execute("INSERT INTO x3dh.keys (username, ik, spk, signature_r, signature_s, verifying_key) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                       (username, ik, spk, signature[0], signature[1], verifying_key))?;
+            for opk in opk_bundle {
+                tx.execute("INSERT INTO x3dh.opk_bundle (opk, username) VALUES (?1, ?2)",
+                           (opk, username))?;
+            }
+            tx.commit()
+        })
+    }
+
+    /// Delete every information stored on the server about the corresponding username *(password, X3DH keys and queued messages)*
+    ///
+    /// The credential is deleted first with the pending deletion, so the user can no longer log in, then the X3DH keys
+    /// and the messages. If the server stops before the end, the deletion is finished at the next start.
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (String): Username
+    ///
+    /// # Output
+    ///
+    /// * bool: `true` if the user existed
+    pub fn delete_account(&mut self, username: &str) -> Result<bool> {
+        let tx: Transaction = self.conn.transaction()?;
+        let mut deleted_rows: usize = tx.execute("DELETE FROM passwords WHERE username = ?1", params![username])?;
+        deleted_rows += tx.execute("DELETE FROM opaque_passwords WHERE username = ?1", params![username])?;
+        tx.execute("INSERT OR REPLACE INTO pending_operations (username, operation) VALUES (?1, ?2)", params![username, DELETE])?;
+        tx.commit()?;
+
+        self.purge_user(username)?;
+        self.finish_operation(username)?;
+        Ok(deleted_rows > 0)
+    }
+
+    /// Rename the users registered before the username policy to their canonical form *(see mini_signal_protocol::normalize_username)*
+    ///
+    /// A user is kept unchanged when the canonical form is already taken by another user, or when it has an OPAQUE
+    /// password file *(the username is bound to the password file, the user has to register again)*.
+    /// The passwords are renamed with the pending renames, then the X3DH keys and the messages of each user.
+    ///
+    /// # Output
+    ///
+    /// * `not_migrated` (Result\<Vec\<String\>\>): Usernames that could not be renamed
+    pub fn normalize_usernames(&mut self) -> Result<Vec<String>> {
+        let (renamed, not_migrated): (Vec<(String, String)>, Vec<String>) = self.with_attached(|conn| {
+            // Read then written, the write lock is taken at the start (see database::pool)
+            let tx: Transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+            let (usernames, opaque_usernames): (Vec<String>, Vec<String>) = {
+                let mut stmt: Statement = tx.prepare("SELECT username FROM passwords UNION SELECT username FROM opaque_passwords UNION SELECT username FROM x3dh.keys")?;
+                let usernames: Result<Vec<String>> = stmt.query_map([], |row| row.get(0))?.collect();
+                let mut stmt: Statement = tx.prepare("SELECT username FROM opaque_passwords")?;
+                let opaque_usernames: Result<Vec<String>> = stmt.query_map([], |row| row.get(0))?.collect();
+                (usernames?, opaque_usernames?)
+            };
+
+            let mut taken: Vec<String> = usernames.iter().filter(|username| normalize_username(username) == **username).cloned().collect();
+            let mut renamed: Vec<(String, String)> = Vec::new();
+            let mut not_migrated: Vec<String> = Vec::new();
+            for username in usernames.iter().filter(|username| normalize_username(username) != **username) {
+                let normalized_username: String = normalize_username(username);
+                if taken.contains(&normalized_username) || opaque_usernames.contains(username) {
+                    not_migrated.push(username.clone());
+                    continue;
+                }
+
+                tx.execute("UPDATE passwords SET username = ?1 WHERE username = ?2", params![normalized_username, username])?;
+                tx.execute("INSERT INTO pending_operations (username, operation, new_username) VALUES (?1, ?2, ?3)", params![username, RENAME, normalized_username])?;
+                taken.push(normalized_username.clone());
+                renamed.push((username.clone(), normalized_username));
+            }
+
+            tx.commit()?;
+            Ok((renamed, not_migrated))
+        })?;
+
+        for (username, normalized_username) in renamed {
+            self.rename_user(&username, &normalized_username)?;
+            self.finish_operation(&username)?;
+        }
+        Ok(not_migrated)
+    }
+
+    /// Finish the operations interrupted before their last commit *(run at the start, before the first request)*
+    ///
+    /// A deletion or a rename is finished, then a registration whose X3DH keys were not committed is cancelled.
+    ///
+    /// # Output
+    ///
+    /// * `completed` (Result\<usize\>): Number of operations finished or cancelled
+    pub fn complete_pending_operations(&mut self) -> Result<usize> {
+        let pending: Vec<(String, String, Option<String>)> = {
+            let mut stmt: Statement = self.conn.prepare("SELECT username, operation, new_username FROM pending_operations")?;
+            let pending: Result<Vec<(String, String, Option<String>)>> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?.collect();
+            pending?
+        };
+
+        for (username, operation, new_username) in &pending {
+            match (operation.as_str(), new_username) {
+                // Recorded by the previous versions, before the registration was a single transaction
+                (REGISTER, _) => {
+                    let has_keys: bool = self.with_attached(|conn| conn.prepare("SELECT 1 FROM x3dh.keys WHERE username = ?1")?.exists(params![username]))?;
+                    if has_keys {
+                        self.finish_operation(username)?;
+                    } else {
+                        self.cancel_registration(username)?;
+                    }
+                },
+                (DELETE, _) => {
+                    self.purge_user(username)?;
+                    self.finish_operation(username)?;
+                },
+                (RENAME, Some(new_username)) => {
+                    self.rename_user(username, new_username)?;
+                    self.finish_operation(username)?;
+                },
+                _ => println!("Unknown pending operation \"{}\" for {}", operation, username),
+            }
+        }
+
+        // After the renames: the X3DH keys of a renamed user are under its new username
+        let cancelled: usize = self.with_attached(|conn| {
+            let tx: Transaction = conn.transaction()?;
+            let mut cancelled: usize = tx.execute("DELETE FROM passwords WHERE username NOT IN (SELECT username FROM x3dh.keys)", ())?;
+            cancelled += tx.execute("DELETE FROM opaque_passwords WHERE username NOT IN (SELECT username FROM x3dh.keys)", ())?;
+            tx.commit()?;
+            Ok(cancelled)
+        })?;
+        Ok(pending.len() + cancelled)
+    }
+
+    /// Remove the credential of a registration whose X3DH keys have not been stored
+    fn cancel_registration(&mut self, username: &str) -> Result<()> {
+        let tx: Transaction = self.conn.transaction()?;
+        tx.execute("DELETE FROM passwords WHERE username = ?1", params![username])?;
+        tx.execute("DELETE FROM opaque_passwords WHERE username = ?1", params![username])?;
+        tx.execute("DELETE FROM pending_operations WHERE username = ?1", params![username])?;
+        tx.commit()
+    }
+
+    /// Delete the X3DH keys and the messages of a deleted user *(one transaction per database, can run again)*
+    fn purge_user(&mut self, username: &str) -> Result<()> {
+        self.with_attached(|conn| {
+            let tx: Transaction = conn.transaction()?;
+            tx.execute("DELETE FROM x3dh.opk_bundle WHERE username = ?1", params![username])?;
+            tx.execute("DELETE FROM x3dh.keys WHERE username = ?1", params![username])?;
+            tx.execute("DELETE FROM x3dh.access_keys WHERE username = ?1", params![username])?;
+            tx.commit()?;
+
+            let tx: Transaction = conn.transaction()?;
+            // The messages sent by the user stay in the mailboxes of their receivers (a sealed message has no sender)
+            tx.execute("DELETE FROM mailbox.messages WHERE username_receiver = ?1", params![username])?;
+            tx.execute("DELETE FROM mailbox.expired_messages WHERE username_receiver = ?1 OR username_sender = ?1", params![username])?;
+            tx.execute("DELETE FROM mailbox.sequences WHERE username_receiver = ?1", params![username])?;
+            tx.commit()
+        })
+    }
+
+    /// Rename the X3DH keys and the messages of a renamed user *(one transaction per database, can run again)*
+    fn rename_user(&mut self, username: &str, normalized_username: &str) -> Result<()> {
+        self.with_attached(|conn| {
+            let tx: Transaction = conn.transaction()?;
+            // The One Time Pre Keys reference the keys of the user, checked at the commit once both are renamed
+            tx.pragma_update(None, "defer_foreign_keys", true)?;
+            tx.execute("UPDATE x3dh.keys SET username = ?1 WHERE username = ?2", params![normalized_username, username])?;
+            tx.execute("UPDATE x3dh.opk_bundle SET username = ?1 WHERE username = ?2", params![normalized_username, username])?;
+            tx.execute("UPDATE x3dh.access_keys SET username = ?1 WHERE username = ?2", params![normalized_username, username])?;
+            tx.commit()?;
+
+            let tx: Transaction = conn.transaction()?;
+            tx.execute("UPDATE mailbox.messages SET username_receiver = ?1 WHERE username_receiver = ?2", params![normalized_username, username])?;
+            tx.execute("UPDATE mailbox.messages SET username_sender = ?1 WHERE username_sender = ?2", params![normalized_username, username])?;
+            tx.execute("UPDATE mailbox.expired_messages SET username_receiver = ?1 WHERE username_receiver = ?2", params![normalized_username, username])?;
+            tx.execute("UPDATE mailbox.expired_messages SET username_sender = ?1 WHERE username_sender = ?2", params![normalized_username, username])?;
+            // The sequence numbers continue after the ones already given to the receiver under both names
+            tx.execute("INSERT INTO mailbox.sequences (username_receiver, last_seq) SELECT ?1, last_seq FROM mailbox.sequences WHERE username_receiver = ?2
+                        ON CONFLICT (username_receiver) DO UPDATE SET last_seq = MAX(last_seq, excluded.last_seq)", params![normalized_username, username])?;
+            tx.execute("DELETE FROM mailbox.sequences WHERE username_receiver = ?1", params![username])?;
+            tx.commit()
+        })
+    }
+
+    /// Forget the pending operation of the username once its last transaction is committed
+    fn finish_operation(&mut self, username: &str) -> Result<()> {
+        self.conn.execute("DELETE FROM pending_operations WHERE username = ?1", params![username])?;
+        Ok(())
+    }
+
+    /// Run `f` with the X3DH keys and messages databases attached *(as `x3dh` and `mailbox`)*
+    ///
+    /// With WAL, a transaction is only atomic in each database: every transaction of `f` writes to a single database.
+    /// The databases are detached on every exit path *(see `AttachedDatabases`)*.
+    fn with_attached<T>(&mut self, f: impl FnOnce(&mut Connection) -> Result<T>) -> Result<T> {
+        let mut attached: AttachedDatabases = AttachedDatabases { conn: &mut self.conn, schemas: Vec::new() };
+        attached.attach("x3dh", &self.data_dir.join(X3DH_DATABASE_FILE))?;
+        attached.attach("mailbox", &self.data_dir.join(MESSAGE_DATABASE_FILE))?;
+
+        // On an error, the databases are detached when `attached` is dropped
+        let value: T = f(attached.conn)?;
+        attached.detach()?;
+        Ok(value)
+    }
+}
+
+/// Databases attached to a pooled connection, detached when dropped
+///
+/// The connection goes back to the pool: a database left attached would make every later ATTACH fail.
+struct AttachedDatabases<'a> {
+    conn: &'a mut Connection,
+    schemas: Vec<&'static str>,
+}
+
+impl AttachedDatabases<'_> {
+    fn attach(&mut self, schema: &'static str, path: &Path) -> Result<()> {
+        self.conn.execute("ATTACH DATABASE ?1 AS ?2", params![path.to_string_lossy(), schema])?;
+        self.schemas.push(schema);
+        Ok(())
+    }
+
+    /// Detach the databases, in the reverse order of their attachment
+    fn detach(&mut self) -> Result<()> {
+        while let Some(schema) = self.schemas.last() {
+            self.conn.execute("DETACH DATABASE ?1", params![schema])?;
+            self.schemas.pop();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AttachedDatabases<'_> {
+    fn drop(&mut self) {
+        // After an error or a panic of `with_attached` (the transactions of `f` are already rolled back)
+        if let Err(error) = self.detach() {
+            eprintln!("Error when detaching the databases: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::pool::SqlitePools;
+    use crate::database::test_data_dir;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    /// Password database over a pool of a single connection, so every call reuses the same connection
+    fn test_password_db(name: &str) -> (PasswordDatabase, PathBuf) {
+        let data_dir: PathBuf = test_data_dir(name);
+        let pools: SqlitePools = SqlitePools::open(&data_dir, 1).unwrap();
+        (PasswordDatabase::new(pools.passwords.get().unwrap(), &data_dir), data_dir)
+    }
+
+    fn attached_databases(password_db: &PasswordDatabase) -> Vec<String> {
+        let mut stmt: Statement = password_db.conn.prepare("SELECT name FROM pragma_database_list").unwrap();
+        let names: Result<Vec<String>> = stmt.query_map([], |row| row.get(0)).unwrap().collect();
+        names.unwrap()
+    }
+
+    #[test]
+    fn detached_after_an_error() {
+        let (mut password_db, _) = test_password_db("error");
+        let result: Result<()> = password_db.with_attached(|conn| {
+            conn.execute("INSERT INTO x3dh.no_such_table VALUES (1)", ())?;
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(attached_databases(&password_db), vec!["main"]);
+        assert!(password_db.with_attached(|conn| conn.execute("DELETE FROM x3dh.keys", ())).is_ok());
+    }
+
+    #[test]
+    fn detached_after_a_failed_attach() {
+        let (mut password_db, data_dir) = test_password_db("attach");
+        // The messages database cannot be opened, after the X3DH keys database is attached
+        let messages_file: PathBuf = data_dir.join(MESSAGE_DATABASE_FILE);
+        std::fs::rename(&messages_file, data_dir.join("moved.db")).unwrap();
+        std::fs::create_dir(&messages_file).unwrap();
+        assert!(password_db.with_attached(|_| Ok(())).is_err());
+        assert_eq!(attached_databases(&password_db), vec!["main"]);
+
+        std::fs::remove_dir(&messages_file).unwrap();
+        std::fs::rename(data_dir.join("moved.db"), &messages_file).unwrap();
+        assert!(password_db.with_attached(|conn| conn.execute("DELETE FROM mailbox.messages", ())).is_ok());
+    }
+
+    #[test]
+    fn detached_after_a_panic() {
+        let (mut password_db, _) = test_password_db("panic");
+        let result = catch_unwind(AssertUnwindSafe(|| password_db.with_attached(|_| -> Result<()> { panic!("injected failure") })));
+        assert!(result.is_err());
+        assert_eq!(attached_databases(&password_db), vec!["main"]);
+    }
+
+    fn register(password_db: &mut PasswordDatabase, username: &str) -> Result<()> {
+        password_db.register_user(username, Credential::Password("password".to_string()), [1; 32], [2; 32], vec![[3; 32]], [[4; 32], [5; 32]], [6; 32])
+    }
+
+    /// Check the keys and the One Time Pre Keys of the user
+    fn has_keys(password_db: &mut PasswordDatabase, username: &str) -> bool {
+        password_db.with_attached(|conn| conn.prepare("SELECT 1 FROM x3dh.keys JOIN x3dh.opk_bundle USING (username) WHERE username = ?1")?.exists(params![username])).unwrap()
+    }
+
+    #[test]
+    fn normalize_usernames_collisions() {
+        let (mut password_db, _) = test_password_db("normalize");
+        for username in ["alice", "Alice", "BOB", "CAROL", "Carol"] {
+            register(&mut password_db, username).unwrap();
+        }
+
+        // "Alice" collides with "alice", and the first renamed of "CAROL" and "Carol" takes "carol"
+        let not_migrated: Vec<String> = password_db.normalize_usernames().unwrap();
+        assert_eq!(not_migrated.len(), 2);
+        assert!(not_migrated.contains(&"Alice".to_string()));
+        let carol: &str = if not_migrated.contains(&"Carol".to_string()) { "Carol" } else { "CAROL" };
+        assert!(not_migrated.contains(&carol.to_string()));
+
+        for username in ["alice", "Alice", "bob", "carol", carol] {
+            assert!(password_db.user_exist(username.to_string()).unwrap() && has_keys(&mut password_db, username), "{} not found", username);
+        }
+        for username in ["BOB", if carol == "Carol" { "CAROL" } else { "Carol" }] {
+            assert!(!password_db.user_exist(username.to_string()).unwrap() && !has_keys(&mut password_db, username), "{} not renamed", username);
+        }
+        assert!(password_db.conn.prepare("SELECT 1 FROM pending_operations").unwrap().query([]).unwrap().next().unwrap().is_none());
+        // Nothing left to rename
+        assert_eq!(password_db.normalize_usernames().unwrap(), not_migrated);
+    }
+
+    #[test]
+    fn failed_registration_stores_nothing() {
+        let (mut password_db, _) = test_password_db("register");
+        // The second One Time Pre Key fails, after the credential and the keys are written
+        let result: Result<()> = password_db.register_user("alice", Credential::Password("password".to_string()),
+                                                           [1; 32], [2; 32], vec![[3; 32], [3; 32]], [[4; 32], [5; 32]], [6; 32]);
+        assert!(result.is_err());
+        assert!(!password_db.user_exist("alice".to_string()).unwrap());
+        assert!(!has_keys(&mut password_db, "alice"));
+
+        register(&mut password_db, "alice").unwrap();
+        assert!(password_db.user_exist("alice".to_string()).unwrap());
+    }
+
+    #[test]
+    fn interrupted_operations_are_recovered() {
+        let (mut password_db, _) = test_password_db("recovery");
+        for username in ["alice", "bob", "Carol"] {
+            register(&mut password_db, username).unwrap();
+        }
+
+        // Stopped after the first commit: a registration without keys, a deletion and a rename without their keys
+        password_db.conn.execute("INSERT INTO passwords (username, password) VALUES ('dave', 'hash')", ()).unwrap();
+        password_db.conn.execute("DELETE FROM passwords WHERE username = 'bob'", ()).unwrap();
+        password_db.conn.execute("INSERT INTO pending_operations (username, operation) VALUES ('bob', ?1)", params![DELETE]).unwrap();
+        password_db.conn.execute("UPDATE passwords SET username = 'carol' WHERE username = 'Carol'", ()).unwrap();
+        password_db.conn.execute("INSERT INTO pending_operations (username, operation, new_username) VALUES ('Carol', ?1, 'carol')", params![RENAME]).unwrap();
+
+        assert_eq!(password_db.complete_pending_operations().unwrap(), 3);
+        assert!(password_db.user_exist("alice".to_string()).unwrap() && has_keys(&mut password_db, "alice"));
+        assert!(!password_db.user_exist("dave".to_string()).unwrap());
+        assert!(!has_keys(&mut password_db, "bob"));
+        assert!(password_db.user_exist("carol".to_string()).unwrap() && has_keys(&mut password_db, "carol"));
+        assert!(!has_keys(&mut password_db, "Carol"));
+        // Nothing left to complete
+        assert_eq!(password_db.complete_pending_operations().unwrap(), 0);
+    }
+}
@@ -0,0 +1,96 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use r2d2::{ManageConnection, Pool, PooledConnection};
+use rusqlite::{Connection, Result};
+use crate::database::login_attempt_database::LOGIN_ATTEMPT_DATABASE_FILE;
+use crate::database::message_database::MESSAGE_DATABASE_FILE;
+use crate::database::migration::{migrate_data_dir, MigrationError};
+use crate::database::password_database::PASSWORD_DATABASE_FILE;
+use crate::database::x3dh_keys_database::X3DH_DATABASE_FILE;
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5); // Wait for the other connections instead of failing with SQLITE_BUSY
+const STATEMENT_CACHE_CAPACITY: usize = 32; // Prepared statements kept by each connection (see `prepare_cached`)
+
+pub type SqlitePool = Pool<SqliteConnectionManager>;
+pub type SqliteConnection = PooledConnection<SqliteConnectionManager>;
+
+/// Open the connections of a pool to a sqlite database
+pub struct SqliteConnectionManager {
+    path: PathBuf,
+}
+
+impl ManageConnection for SqliteConnectionManager {
+    type Connection = Connection;
+    type Error = rusqlite::Error;
+
+    fn connect(&self) -> Result<Connection> {
+        let conn: Connection = Connection::open(&self.path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        // Safe with WAL, a power loss can only lose the last transactions, not corrupt the database
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        Ok(conn)
+    }
+
+    fn is_valid(&self, conn: &mut Connection) -> Result<()> {
+        conn.execute_batch("")
+    }
+
+    fn has_broken(&self, _conn: &mut Connection) -> bool {
+        false
+    }
+}
+
+/// Connection pools of the server databases
+///
+/// The databases use WAL, so the readers never wait for a writer. With WAL, a transaction over attached
+/// databases is atomic in each database but not across them: the operations over several databases *(delete_account,
+/// normalize_usernames)* commit one database at a time and are recorded in `pending_operations` until the last commit.
+///
+/// A transaction that reads then writes must be `Immediate`: a deferred transaction cannot take the write lock
+/// if another connection has written since its read, and fails with SQLITE_BUSY without waiting.
+pub struct SqlitePools {
+    pub passwords: SqlitePool,
+    pub x3dh_keys: SqlitePool,
+    pub messages: SqlitePool,
+    pub login_attempts: SqlitePool,
+    pub data_dir: PathBuf, // Used to attach the databases to each other
+}
+
+impl SqlitePools {
+    /// Migrate the databases to the current schema and create their connection pools
+    ///
+    /// # Arguments
+    ///
+    /// * `data_dir` (&Path): Directory of the databases
+    /// * `pool_size` (u32): Maximum number of connections to each database
+    pub fn open(data_dir: &Path, pool_size: u32) -> std::result::Result<Self, MigrationError> {
+        // Once, before the first connection of the pools, so two connections never migrate at the same time
+        for (database, previous_version, version) in migrate_data_dir(data_dir)? {
+            if previous_version != version {
+                println!("{} migrated from schema version {} to {}", database, previous_version, version);
+            }
+        }
+
+        Ok(SqlitePools {
+            passwords: create_pool(&data_dir.join(PASSWORD_DATABASE_FILE), pool_size)?,
+            x3dh_keys: create_pool(&data_dir.join(X3DH_DATABASE_FILE), pool_size)?,
+            messages: create_pool(&data_dir.join(MESSAGE_DATABASE_FILE), pool_size)?,
+            login_attempts: create_pool(&data_dir.join(LOGIN_ATTEMPT_DATABASE_FILE), pool_size)?,
+            data_dir: data_dir.to_path_buf(),
+        })
+    }
+}
+
+/// Switch the database to WAL with a first connection, then create the pool
+fn create_pool(path: &Path, pool_size: u32) -> Result<SqlitePool> {
+    let conn: Connection = Connection::open(path)?;
+    conn.pragma_update(None, "journal_mode", "WAL")?; // Stored in the database file
+    drop(conn);
+
+    // The connections are opened when they are needed
+    Ok(Pool::builder()
+        .max_size(pool_size)
+        .min_idle(Some(0))
+        .build_unchecked(SqliteConnectionManager { path: path.to_path_buf() }))
+}
@@ -0,0 +1,170 @@
+use mini_signal_protocol::Envelope;
+use rusqlite::Result;
+use crate::database::login_attempt_database::LoginAttemptDatabase;
+use crate::database::message_database::MessageDatabase;
+use crate::database::password_database::{Credential, PasswordDatabase};
+use crate::database::pool::SqlitePools;
+use crate::database::storage::{AccountStore, LoginAttemptStore, MailboxStore, PrekeyStore, Storage};
+use crate::database::x3dh_keys_database::{PublicKeys, X3DHDatabase};
+
+/// Storage in the sqlite databases of the data directory
+pub struct SqliteStorage {
+    password_db: PasswordDatabase,
+    x3dh_db: X3DHDatabase,
+    message_db: MessageDatabase,
+    attempt_db: LoginAttemptDatabase,
+}
+
+impl SqliteStorage {
+    /// Take a connection to each database from the pools *(given back when the storage is dropped)*
+    pub fn new(pools: &SqlitePools) -> std::result::Result<Self, r2d2::Error> {
+        Ok(SqliteStorage {
+            password_db: PasswordDatabase::new(pools.passwords.get()?, &pools.data_dir),
+            x3dh_db: X3DHDatabase::new(pools.x3dh_keys.get()?),
+            message_db: MessageDatabase::new(pools.messages.get()?),
+            attempt_db: LoginAttemptDatabase::new(pools.login_attempts.get()?),
+        })
+    }
+}
+
+impl AccountStore for SqliteStorage {
+    fn user_exist(&self, username: String) -> Result<bool> {
+        self.password_db.user_exist(username)
+    }
+
+    fn insert_user(&mut self, username: &str, password: &str) -> Result<()> {
+        self.password_db.insert_user(username, password)
+    }
+
+    fn check_password(&mut self, username: &str, password: String) -> Result<bool> {
+        self.password_db.check_password(username, password)
+    }
+
+    fn change_password(&mut self, username: &str, old_password: String, new_password: &str) -> Result<bool> {
+        self.password_db.change_password(username, old_password, new_password)
+    }
+
+    #[cfg(feature = "opaque")]
+    fn get_opaque_password_file(&self, username: &str) -> Result<Option<Vec<u8>>> {
+        self.password_db.get_opaque_password_file(username)
+    }
+
+    #[cfg(feature = "opaque")]
+    fn get_opaque_server_setup(&self) -> Result<Option<Vec<u8>>> {
+        self.password_db.get_opaque_server_setup()
+    }
+
+    #[cfg(feature = "opaque")]
+    fn insert_opaque_server_setup(&mut self, setup: Vec<u8>) -> Result<()> {
+        self.password_db.insert_opaque_server_setup(setup)
+    }
+
+    fn get_server_signing_key(&self) -> Result<Option<[u8; 32]>> {
+        self.password_db.get_server_signing_key()
+    }
+
+    fn insert_server_signing_key(&mut self, signing_key: [u8; 32]) -> Result<()> {
+        self.password_db.insert_server_signing_key(signing_key)
+    }
+}
+
+impl PrekeyStore for SqliteStorage {
+    fn has_x3dh_keys(&self, username: &str) -> Result<bool> {
+        self.x3dh_db.user_exist(username)
+    }
+
+    fn get_all_users(&self) -> Result<Vec<String>> {
+        self.x3dh_db.get_all_users()
+    }
+
+    fn insert_x3dh_keys(&mut self, username: &str, ik: [u8; 32], spk: [u8; 32], opk_bundle: Vec<[u8; 32]>, signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()> {
+        self.x3dh_db.insert_x3dh_keys(username, ik, spk, opk_bundle, signature, verifying_key)
+    }
+
+    fn update_spk(&mut self, username: &str, spk: [u8;32], signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()> {
+        self.x3dh_db.update_spk(username, spk, signature, verifying_key)
+    }
+
+    fn get_public_keys(&mut self, username: String) -> Result<PublicKeys> {
+        self.x3dh_db.get_public_keys(username)
+    }
+
+    fn get_identity_key(&self, username: &str) -> Result<Option<[u8; 32]>> {
+        self.x3dh_db.get_identity_key(username)
+    }
+
+    fn set_access_key(&mut self, username: &str, access_key: [u8; 16]) -> Result<()> {
+        self.x3dh_db.set_access_key(username, access_key)
+    }
+
+    fn get_access_key(&self, username: &str) -> Result<Option<[u8; 16]>> {
+        self.x3dh_db.get_access_key(username)
+    }
+
+    fn add_opk_bundle(&mut self, username: &str, opk_bundle: Vec<[u8;32]>) -> Result<()> {
+        self.x3dh_db.add_opk_bundle(username, opk_bundle)
+    }
+
+    fn delete_opk_key(&mut self, opk: [u8; 32]) -> Result<()> {
+        self.x3dh_db.delete_opk_key(opk)
+    }
+}
+
+impl MailboxStore for SqliteStorage {
+    fn get_user_messages_page(&mut self, username_receiver: &str, after: i64, page_size: u32, byte_budget: usize) -> Result<(Vec<Envelope>, bool)> {
+        self.message_db.get_user_messages_page(username_receiver, after, page_size, byte_budget)
+    }
+
+    fn add_message(&mut self, username_receiver: &str, username_sender: Option<&String>, sealed_sender: Option<Vec<u8>>,
+                   header_encrypted: Vec<u8>, header_nonce: Vec<u8>,
+                   ciphertext: Vec<u8>, nonce: Vec<u8>,
+                   ek_sender: Option<[u8;32]>, opk_used: Option<[u8;32]>, ik_sender: Option<[u8;32]>) -> Result<Envelope> {
+        self.message_db.add_message(username_receiver, username_sender, sealed_sender, header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used, ik_sender)
+    }
+
+    fn mark_delivered(&mut self, message_id: i64) -> Result<()> {
+        self.message_db.mark_delivered(message_id)
+    }
+
+    fn acknowledge_messages(&mut self, username_receiver: &str, message_ids: &[i64]) -> Result<usize> {
+        self.message_db.acknowledge_messages(username_receiver, message_ids)
+    }
+
+    /// The database is compacted when messages have been deleted
+    fn expire_messages(&mut self, received_before: u64, now: u64) -> Result<usize> {
+        let expired: usize = self.message_db.expire_messages(received_before, now)?;
+        if expired > 0 {
+            self.message_db.compact()?;
+        }
+        Ok(expired)
+    }
+
+    fn take_expired_messages(&mut self, username_sender: &str) -> Result<Vec<i64>> {
+        self.message_db.take_expired_messages(username_sender)
+    }
+}
+
+impl LoginAttemptStore for SqliteStorage {
+    fn get_lockout(&mut self, attempt_key: &str, now: u64) -> Result<Option<u64>> {
+        self.attempt_db.get_lockout(attempt_key, now)
+    }
+
+    fn record_failure(&mut self, attempt_key: &str, now: u64) -> Result<()> {
+        self.attempt_db.record_failure(attempt_key, now)
+    }
+
+    fn reset_failures(&mut self, attempt_key: &str) -> Result<()> {
+        self.attempt_db.reset(attempt_key)
+    }
+}
+
+impl Storage for SqliteStorage {
+    /// The credential and the X3DH keys are written in one transaction over the attached databases
+    fn register_user(&mut self, username: &str, credential: Credential, ik: [u8; 32], spk: [u8; 32], opk_bundle: Vec<[u8; 32]>, signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()> {
+        self.password_db.register_user(username, credential, ik, spk, opk_bundle, signature, verifying_key)
+    }
+
+    fn delete_account(&mut self, username: &str) -> Result<bool> {
+        self.password_db.delete_account(username)
+    }
+}
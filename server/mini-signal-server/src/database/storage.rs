@@ -0,0 +1,178 @@
+use std::path::Path;
+use std::sync::Mutex;
+use mini_signal_protocol::Envelope;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use rusqlite::Result;
+use crate::database::memory_storage::MemoryStorage;
+use crate::database::migration::MigrationError;
+use crate::database::password_database::Credential;
+use crate::database::pool::SqlitePools;
+use crate::database::sqlite_storage::SqliteStorage;
+use crate::database::x3dh_keys_database::PublicKeys;
+use crate::server::config::StorageKind;
+
+/// Passwords and OPAQUE password files of the users
+pub trait AccountStore {
+    /// Check if the username is registered *(password or OPAQUE password file)*
+    fn user_exist(&self, username: String) -> Result<bool>;
+
+    /// Insert a user without X3DH keys *(the password is hashed with Argon2id before being stored)*
+    fn insert_user(&mut self, username: &str, password: &str) -> Result<()>;
+
+    /// Check the password of the user *(rehash it when the Argon2 parameters changed)*
+    fn check_password(&mut self, username: &str, password: String) -> Result<bool>;
+
+    /// Change the password of the user if the old password is valid
+    ///
+    /// # Output
+    ///
+    /// * bool: `true` if the password has been changed
+    fn change_password(&mut self, username: &str, old_password: String, new_password: &str) -> Result<bool>;
+
+    /// Return the OPAQUE password file of the user, None if the user is not registered with OPAQUE
+    #[cfg(feature = "opaque")]
+    fn get_opaque_password_file(&self, username: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Return the OPAQUE server setup, None if it has not been generated yet
+    #[cfg(feature = "opaque")]
+    fn get_opaque_server_setup(&self) -> Result<Option<Vec<u8>>>;
+
+    /// Store the OPAQUE server setup *(it must never change)*
+    #[cfg(feature = "opaque")]
+    fn insert_opaque_server_setup(&mut self, setup: Vec<u8>) -> Result<()>;
+
+    /// Return the Ed25519 key signing the sender certificates, None if it has not been generated yet
+    fn get_server_signing_key(&self) -> Result<Option<[u8; 32]>>;
+
+    /// Store the Ed25519 key signing the sender certificates *(the certificates already issued are refused if it changes)*
+    fn insert_server_signing_key(&mut self, signing_key: [u8; 32]) -> Result<()>;
+}
+
+/// X3DH public keys and One Time Pre Keys of the users
+pub trait PrekeyStore {
+    /// Check if the user has published its X3DH keys
+    fn has_x3dh_keys(&self, username: &str) -> Result<bool>;
+
+    /// Return the usernames of the users that have published their X3DH keys
+    fn get_all_users(&self) -> Result<Vec<String>>;
+
+    /// Insert the X3DH keys of the user
+    fn insert_x3dh_keys(&mut self, username: &str, ik: [u8; 32], spk: [u8; 32], opk_bundle: Vec<[u8; 32]>, signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()>;
+
+    /// Replace the Signed Pre Key, Signature and Verifying Key of the user
+    fn update_spk(&mut self, username: &str, spk: [u8;32], signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()>;
+
+    /// Return (ik, spk, opk, signature, verifying_key) of the user *(the opk is not removed, see `delete_opk_key`)*
+    fn get_public_keys(&mut self, username: String) -> Result<PublicKeys>;
+
+    /// Return the Identity Key of the user, None if the user has not published its X3DH keys
+    fn get_identity_key(&self, username: &str) -> Result<Option<[u8; 32]>>;
+
+    /// Replace the delivery access key of the user *(required to send a sealed message to the user)*
+    fn set_access_key(&mut self, username: &str, access_key: [u8; 16]) -> Result<()>;
+
+    /// Return the delivery access key of the user, None if the user does not accept sealed messages
+    fn get_access_key(&self, username: &str) -> Result<Option<[u8; 16]>>;
+
+    /// Add One Time Pre Keys for the user
+    fn add_opk_bundle(&mut self, username: &str, opk_bundle: Vec<[u8;32]>) -> Result<()>;
+
+    /// Remove a One Time Pre Key once it has been given to another user
+    fn delete_opk_key(&mut self, opk: [u8; 32]) -> Result<()>;
+}
+
+/// Messages waiting for their receiver, and notices of the messages that expired before being acknowledged
+pub trait MailboxStore {
+    /// Return a page of the messages of the receiver not acknowledged yet, and count this delivery *(see MessageDatabase::get_user_messages_page)*
+    fn get_user_messages_page(&mut self, username_receiver: &str, after: i64, page_size: u32, byte_budget: usize) -> Result<(Vec<Envelope>, bool)>;
+
+    /// Queue a message and return it stamped by the server *(id, guid, reception time and next sequence number of the receiver)*
+    ///
+    /// A sealed message has no `username_sender`, only the `sealed_sender` that the receiver decrypts.
+    #[allow(clippy::too_many_arguments)] // Fields of the message as sent by the client
+    fn add_message(&mut self, username_receiver: &str, username_sender: Option<&String>, sealed_sender: Option<Vec<u8>>,
+                   header_encrypted: Vec<u8>, header_nonce: Vec<u8>,
+                   ciphertext: Vec<u8>, nonce: Vec<u8>,
+                   ek_sender: Option<[u8;32]>, opk_used: Option<[u8;32]>, ik_sender: Option<[u8;32]>) -> Result<Envelope>;
+
+    /// Count a delivery of the message *(pushed over the WebSocket connection)*
+    fn mark_delivered(&mut self, message_id: i64) -> Result<()>;
+
+    /// Delete the messages acknowledged by the receiver and return the number of messages deleted
+    fn acknowledge_messages(&mut self, username_receiver: &str, message_ids: &[i64]) -> Result<usize>;
+
+    /// Delete the messages received before `received_before`, keep a notice for their sender and return the number of messages deleted
+    fn expire_messages(&mut self, received_before: u64, now: u64) -> Result<usize>;
+
+    /// Return and forget the ids of the messages of the sender that expired before being acknowledged
+    fn take_expired_messages(&mut self, username_sender: &str) -> Result<Vec<i64>>;
+}
+
+/// Failed logins of the usernames and source addresses
+pub trait LoginAttemptStore {
+    /// Return the end of the lockout of the key, None if it is not locked
+    fn get_lockout(&mut self, attempt_key: &str, now: u64) -> Result<Option<u64>>;
+
+    /// Record a failed login and lock the key with an exponential backoff
+    fn record_failure(&mut self, attempt_key: &str, now: u64) -> Result<()>;
+
+    /// Forget the failed logins of the key *(after a successful login)*
+    fn reset_failures(&mut self, attempt_key: &str) -> Result<()>;
+}
+
+/// Everything the server stores, with the operations that span several stores
+pub trait Storage: AccountStore + PrekeyStore + MailboxStore + LoginAttemptStore {
+    /// Create the user and publish its X3DH keys *(everything is stored or nothing is)*
+    #[allow(clippy::too_many_arguments)] // X3DH keys as sent by the client (see Action::Register)
+    fn register_user(&mut self, username: &str, credential: Credential, ik: [u8; 32], spk: [u8; 32], opk_bundle: Vec<[u8; 32]>, signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()>;
+
+    /// Delete every information stored about the user *(credential, X3DH keys, delivery access key and messages received)*
+    ///
+    /// # Output
+    ///
+    /// * bool: `true` if the user existed
+    fn delete_account(&mut self, username: &str) -> Result<bool>;
+}
+
+/// Storage used by the server
+pub enum StorageBackend {
+    Sqlite(SqlitePools), // Databases of the data directory
+    Memory(Box<Mutex<MemoryStorage>>), // Lost when the server stops
+}
+
+impl StorageBackend {
+    /// # Arguments
+    ///
+    /// * `kind` (StorageKind): Backend
+    /// * `data_dir` (&Path): Directory of the sqlite databases
+    /// * `pool_size` (u32): Maximum number of connections to each sqlite database
+    pub fn new(kind: StorageKind, data_dir: &Path, pool_size: u32) -> std::result::Result<Self, MigrationError> {
+        match kind {
+            StorageKind::Sqlite => Ok(StorageBackend::Sqlite(SqlitePools::open(data_dir, pool_size)?)),
+            StorageKind::Memory => Ok(StorageBackend::Memory(Box::new(Mutex::new(MemoryStorage::new())))),
+        }
+    }
+
+    /// Run `f` with the storage *(blocking, the in-memory storage is locked until `f` returns)*
+    ///
+    /// # Output
+    ///
+    /// * `result` (Result\<T, r2d2::Error\>): Error if no database connection is available before the pool timeout
+    pub fn with_storage<T>(&self, f: impl FnOnce(&mut dyn Storage) -> T) -> std::result::Result<T, r2d2::Error> {
+        match self {
+            StorageBackend::Sqlite(pools) => {
+                let mut storage: SqliteStorage = SqliteStorage::new(pools)?;
+                Ok(f(&mut storage))
+            },
+            StorageBackend::Memory(storage) => Ok(f(&mut *storage.lock().unwrap())),
+        }
+    }
+}
+
+/// Generate the GUID of a message received by the server *(128 random bits, hex encoded)*
+pub fn generate_guid() -> String {
+    let mut guid_bytes: [u8; 16] = [0u8; 16];
+    OsRng.fill_bytes(&mut guid_bytes);
+    guid_bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -1,38 +1,65 @@
-use rusqlite::{Connection, params, Result, Statement, Transaction};
+use rusqlite::{CachedStatement, params, Result, Statement, Transaction};
+use crate::database::migration::Migration;
+use crate::database::pool::SqliteConnection;
+
+pub const X3DH_DATABASE_FILE: &str = "x3dh_keys.db";
+
+/// Public keys given to a user starting a session *(ik, spk, opk, signature, verifying_key)*
+pub type PublicKeys = ([u8; 32], [u8; 32], Option<[u8; 32]>, [[u8; 32]; 2], [u8; 32]);
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { description: "Create the keys and opk_bundle tables", apply: create_keys_tables },
+    Migration { description: "Index the One Time Pre Keys by user", apply: index_opk_bundle_by_username },
+    Migration { description: "Create the delivery access keys table", apply: create_access_keys_table },
+];
+
+fn create_keys_tables(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "create table if not exists keys (
+         username TEXT NOT NULL PRIMARY KEY,
+         ik BLOB NOT NULL,
+         spk BLOB NOT NULL,
+         signature_r BLOB NOT NULL,
+         signature_s BLOB NOT NULL,
+         verifying_key BLOB NOT NULL
+     )",
+        (),
+    )?;
+
+    tx.execute(
+        "create table if not exists opk_bundle (
+         opk BLOB NOT NULL,
+         username TEXT NOT NULL,
+         FOREIGN KEY(username) REFERENCES keys(username),
+         PRIMARY KEY(opk, username)
+     )",
+        (),
+    )?;
+    Ok(())
+}
+
+fn index_opk_bundle_by_username(tx: &Transaction) -> Result<()> {
+    // The primary key starts with the opk, the keys of a user were found with a full scan
+    tx.execute("CREATE INDEX IF NOT EXISTS opk_bundle_by_username ON opk_bundle (username)", ())?;
+    Ok(())
+}
+
+fn create_access_keys_table(tx: &Transaction) -> Result<()> {
+    // Required to send a sealed message to the user (derived from its profile key, given only to its contacts)
+    tx.execute("CREATE TABLE IF NOT EXISTS access_keys (
+         username TEXT NOT NULL PRIMARY KEY,
+         access_key BLOB NOT NULL
+     )", ())?;
+    Ok(())
+}
 
 pub struct X3DHDatabase {
-    conn: Connection,
+    conn: SqliteConnection,
 }
 
 impl X3DHDatabase {
-
-    /// Create the X3DH keys database if not exists
-    pub fn new() -> Result<Self> {
-        let conn = Connection::open("x3dh_keys.db")?;
-
-        conn.execute(
-            "create table if not exists keys (
-             username TEXT NOT NULL PRIMARY KEY,
-             ik BLOB NOT NULL,
-             spk BLOB NOT NULL,
-             signature_r BLOB NOT NULL,
-             signature_s BLOB NOT NULL,
-             verifying_key BLOB NOT NULL
-         )",
-            (),
-        )?;
-
-        conn.execute(
-            "create table if not exists opk_bundle (
-             opk BLOB NOT NULL,
-             username TEXT NOT NULL,
-             FOREIGN KEY(username) REFERENCES keys(username),
-             PRIMARY KEY(opk, username)
-         )",
-            (),
-        )?;
-
-        Ok(X3DHDatabase { conn })
+    pub fn new(conn: SqliteConnection) -> Self {
+        X3DHDatabase { conn }
     }
 
     /// Check if the username exist
@@ -44,9 +71,9 @@ impl X3DHDatabase {
     /// # Output
     ///
     /// * bool
-    pub fn user_exist(&self, username: &String) -> Result<bool> {
-        let mut stmt: Statement = self.conn.prepare("SELECT 1 FROM keys WHERE username=:username")?;
-        let exists: bool = stmt.exists(&[(":username", username.as_str())])?;
+    pub fn user_exist(&self, username: &str) -> Result<bool> {
+        let mut stmt: CachedStatement = self.conn.prepare_cached("SELECT 1 FROM keys WHERE username=:username")?;
+        let exists: bool = stmt.exists(&[(":username", username)])?;
 
         Ok(exists)
     }
@@ -60,7 +87,7 @@ impl X3DHDatabase {
         let mut stmt: Statement = self.conn.prepare("SELECT username FROM keys")?;
 
         let req_user_list: Result<Vec<String>> = stmt.query_map(params![], |row| {
-            Ok(row.get(0)?)
+            row.get(0)
         })?.collect();
 
         req_user_list
@@ -76,15 +103,18 @@ impl X3DHDatabase {
     /// * `opk_bundle` (Vec\<\[u8; 32\]\>): Bundle of One Time Pre Key
     /// * `signature` (\[\[u8;32\]; 2\]): Signature *(\[r_bytes, s_bytes\])*
     /// * `verifying_key` (\[u8; 32\]): Verifying Key
-    pub fn insert_x3dh_keys(&mut self, username: &String, ik: [u8; 32], spk: [u8; 32], opk_bundle: Vec<[u8; 32]>, signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()> {
+    pub fn insert_x3dh_keys(&mut self, username: &str, ik: [u8; 32], spk: [u8; 32], opk_bundle: Vec<[u8; 32]>, signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()> {
         let tx: Transaction = self.conn.transaction()?;
 
         tx.execute("INSERT INTO keys (username, ik, spk, signature_r, signature_s, verifying_key) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
                    (username, ik, spk, signature[0], signature[1], verifying_key))?;
+        // Same transaction, the keys are never published without their One Time Pre Keys
+        for opk in opk_bundle {
+            tx.execute("INSERT INTO opk_bundle (opk, username) VALUES (?1, ?2)",
+                       (opk, username))?;
+        }
 
-        tx.commit().expect("Should bd fine");
-
-        self.add_opk_bundle(username, opk_bundle)
+        tx.commit()
     }
 
     /// Update the Signed Pre Key, Signature and Verification Key of the corresponding `username`
@@ -95,14 +125,11 @@ impl X3DHDatabase {
     /// * `spk` (\[u8;32\]): Signed Pre Key
     /// * `signature` (\[\[u8;32\];32\]): Signature
     /// * `verifying_key` (\[u8;32\]): Verifying Key
-    pub fn update_spk(&mut self, username: &String, spk: [u8;32], signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()> {
+    pub fn update_spk(&mut self, username: &str, spk: [u8;32], signature: [[u8;32]; 2], verifying_key: [u8; 32]) -> Result<()> {
         let tx: Transaction = self.conn.transaction()?;
 
-        match tx.execute("UPDATE keys SET spk = ?1, signature_r = ?2, signature_s = ?3, verifying_key = ?4 WHERE username = ?5",
-                         params![spk, signature[0], signature[1], verifying_key, username]) {
-            Ok(updated) => println!("{} row updated", updated),
-            Err(err) => println!("update failed: {}", err),
-        }
+        tx.execute("UPDATE keys SET spk = ?1, signature_r = ?2, signature_s = ?3, verifying_key = ?4 WHERE username = ?5",
+                   params![spk, signature[0], signature[1], verifying_key, username])?;
 
         tx.commit()
     }
@@ -116,12 +143,12 @@ impl X3DHDatabase {
     /// # Output
     ///
     /// (ik_public_key, spk_public_key, opk_public_key, signature, verifying_key: (\[u8; 32\], \[u8; 32\], Option\<\[u8;32\]\>, \[\[u8; 32\]; 2\], \[u8; 32\])
-    pub fn get_public_keys(&mut self, username: String) -> Result<([u8; 32], [u8; 32], Option<[u8;32]>, [[u8; 32]; 2], [u8; 32])> {
-        let mut stmt: Statement = self.conn.prepare("SELECT ik, spk, signature_r, signature_s, verifying_key FROM keys WHERE username = ?")?;
+    pub fn get_public_keys(&mut self, username: String) -> Result<PublicKeys> {
+        let mut stmt: CachedStatement = self.conn.prepare_cached("SELECT ik, spk, signature_r, signature_s, verifying_key FROM keys WHERE username = ?")?;
 
-        let opk_key: Option<[u8; 32]> = self.get_opk_key(&username).unwrap();//self.opk_bundle_database.get_opk_key(&username, tx).unwrap(); // TODO should be better handle
+        let opk_key: Option<[u8; 32]> = self.get_opk_key(&username)?;
 
-        let mut result = stmt.query_map(&[username.as_str()], |row| {
+        let mut result = stmt.query_map([username.as_str()], |row| {
             let ik: [u8; 32] = row.get(0)?;
             let spk: [u8; 32] = row.get(1)?;
             let signature_r: [u8; 32] = row.get(2)?;
@@ -138,13 +165,51 @@ impl X3DHDatabase {
         }
     }
 
+    /// Return the Identity Key of the corresponding `username`, None if the user has not published its X3DH keys
+    pub fn get_identity_key(&self, username: &str) -> Result<Option<[u8; 32]>> {
+        let mut stmt: CachedStatement = self.conn.prepare_cached("SELECT ik FROM keys WHERE username = ?1")?;
+
+        let ik: Result<Vec<[u8; 32]>> = stmt.query_map(params![username], |row| {
+            row.get(0)
+        })?.collect();
+
+        Ok(ik?.pop())
+    }
+
+    /// Replace the delivery access key of the corresponding `username`
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (String): Username
+    /// * `access_key` (\[u8; 16\]): Delivery access key *(derived from the profile key of the user)*
+    pub fn set_access_key(&mut self, username: &str, access_key: [u8; 16]) -> Result<()> {
+        let tx: Transaction = self.conn.transaction()?;
+
+        tx.execute("INSERT INTO access_keys (username, access_key) VALUES (?1, ?2)
+                    ON CONFLICT (username) DO UPDATE SET access_key = excluded.access_key",
+                   params![username, access_key])?;
+
+        tx.commit()
+    }
+
+    /// Return the delivery access key of the corresponding `username`, None if the user does not accept sealed messages
+    pub fn get_access_key(&self, username: &str) -> Result<Option<[u8; 16]>> {
+        let mut stmt: CachedStatement = self.conn.prepare_cached("SELECT access_key FROM access_keys WHERE username = ?1")?;
+
+        let access_key: Result<Vec<[u8; 16]>> = stmt.query_map(params![username], |row| {
+            row.get(0)
+        })?.collect();
+
+        Ok(access_key?.pop())
+    }
+
     /// Add One Time Pre Key bundle for the corresponding `username`
     ///
     /// # Arguments
     ///
     /// * `username` (String): Username
     /// * `opk_bundle` (Vec\<\[u8; 32\]\>): Bundle of One Time Pre Key
-    pub fn add_opk_bundle(&mut self, username: &String, opk_bundle: Vec<[u8;32]>) -> Result<()> {
+    pub fn add_opk_bundle(&mut self, username: &str, opk_bundle: Vec<[u8;32]>) -> Result<()> {
         let tx: Transaction = self.conn.transaction()?;
 
         for opk in opk_bundle {
@@ -164,11 +229,11 @@ impl X3DHDatabase {
     /// # Output
     ///
     /// opk_bundle (Vec\<\[u8;32\]\>)
-    fn get_opk_bundle(&self, username: &String) -> Result<Vec<[u8; 32]>> {
-        let mut stmt: Statement = self.conn.prepare("SELECT opk FROM opk_bundle WHERE username=:username")?;
+    fn get_opk_bundle(&self, username: &str) -> Result<Vec<[u8; 32]>> {
+        let mut stmt: CachedStatement = self.conn.prepare_cached("SELECT opk FROM opk_bundle WHERE username=:username")?;
 
         let req_user_opk_bundle: Result<Vec<[u8; 32]>> = stmt.query_map(params![username], |row| {
-            Ok(row.get(0)?)
+            row.get(0)
         })?.collect();
 
         req_user_opk_bundle
@@ -183,8 +248,8 @@ impl X3DHDatabase {
     /// # Ouptut
     ///
     /// opk_key (\[u8; 32\])
-    fn get_opk_key(&self, username: &String) -> Result<Option<[u8; 32]>> {
-        let user_opk_bundle: Vec<[u8; 32]> = self.get_opk_bundle(username).unwrap();
+    fn get_opk_key(&self, username: &str) -> Result<Option<[u8; 32]>> {
+        let user_opk_bundle: Vec<[u8; 32]> = self.get_opk_bundle(username)?;
         if user_opk_bundle.is_empty() { return Ok(None) };
 
         Ok(Some(user_opk_bundle[0]))
@@ -193,8 +258,8 @@ impl X3DHDatabase {
     pub fn delete_opk_key(&mut self, opk: [u8; 32]) -> Result<()> {
         let tx: Transaction = self.conn.transaction()?;
 
-        tx.execute("DELETE FROM opk_bundle WHERE opk=?1",
-                   params![opk])?;
+        tx.prepare_cached("DELETE FROM opk_bundle WHERE opk=?1")?
+            .execute(params![opk])?;
 
         tx.commit()
     }
@@ -1,16 +1,30 @@
 mod server;
 mod database;
 
-use database::{message_database::MessageDatabase, password_database::PasswordDatabase, x3dh_keys_database::X3DHDatabase};
+use database::migration::migrate_data_dir;
+use database::password_database::{Credential, PasswordDatabase};
+use database::pool::SqlitePools;
+use database::storage::{generate_guid, LoginAttemptStore, Storage, StorageBackend};
+use server::config::{Cli, Command, Config};
+use server::error::{is_constraint_violation, ServerError};
+use server::rate_limit::{attempt_keys, AttemptReservation, LoginAttempts, ATTEMPT_TIMEOUT};
+use server::time::unix_time_now;
+use server::push::PushManager;
+use server::retention::run_expiry_sweeper;
+use server::sealed_sender::{check_access_key, CertificateIssuer};
+use server::session::SessionManager;
+#[cfg(feature = "opaque")]
+use server::opaque::{OpaqueServer, LOGIN_TIMEOUT};
 use std::net::SocketAddr;
-use serde::{Deserialize, Serialize};
-use warp::Filter;
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use std::path::Path;
 use std::time::Duration;
+use clap::Parser;
+use mini_signal_protocol::{normalize_username, validate_username, Action, Capability, Encoding, Envelope, ErrorCode, Request, Response, CAPABILITIES_HEADER, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use warp::{Filter, Reply};
+use futures_util::{SinkExt, StreamExt};
+use std::sync::{Arc, Mutex};
 use bytes::Bytes;
-use warp::ws::WebSocket;
-use crate::Response::ResponseStatus;
+use warp::ws::{Message, WebSocket};
 
 // https://rust-lang-nursery.github.io/rust-cookbook/database/sqlite.html
 // https://www.makeuseof.com/working-with-sql-databases-in-rust/
@@ -23,270 +37,1112 @@ use crate::Response::ResponseStatus;
 // openssl req -newkey rsa:2048 -new -nodes -x509 -days 3650 -keyout key.rsa -out cert.pem
 // https://jan.newmarch.name/NetworkProgramming/TLS/wrapper.html?general
 
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(rename_all = "lowercase", tag = "action")]
-enum Action {
-    NewUser { // Client to the Server
-        username: String,
-        password: String,
-    },
-    LogIn { // Client to the Server
-        username: String,
-        password: String,
-    },
-    LogOut, // Client to the Server
-    GetAllUsers,
-    GetMessages,
-    PublishX3DHInformation { // Sent when the user is created (Client to the Server)
-        ik: [u8; 32],
-        spk: [u8; 32],
-        opk_bundle: Vec<[u8; 32]>,
-        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
-        verifying_key: [u8; 32],
-    },
-    UpdateX3DHSignedPreKey { // Client to the Server
-        spk: [u8; 32],
-        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
-        verifying_key: [u8; 32],
-    },
-    SupplyX3DHOneTimePreKeyBundle { // Client to the Server (Server send a response to confirm that he received the message
-        opk_bundle: Vec<[u8; 32]>,
-    },
-    GetUserPublicKeys { // Client to the Server (handle user does not exist)
-        username: String,
-    },
-    SendMessage{ // Client to the Server
-        username_receiver: String,
-        header_encrypted: Vec<u8>,
-        header_nonce: Vec<u8>,
-        ciphertext: Vec<u8>,
-        nonce: Vec<u8>,
-        ek_sender: Option<[u8;32]>,
-        opk_used: Option<[u8;32]>,
-        ik_sender: Option<[u8;32]>
-    },
+impl From<ServerError> for Response {
+    fn from(error: ServerError) -> Self {
+        Response::Error { code: error.code, message: error.message }
+    }
 }
-// Do we need a LogOut or the server can now if the host is not reachable (try twice and if not, then wait the next connection)
-
-#[derive(Debug, Deserialize, Serialize)]
-enum Response {
-    UserList { result: Vec<String> },
-    ResponseStatus { success: bool },
-    UserPublicKeys { // Server to the Client
-        ik: [u8; 32],
-        spk: [u8; 32],
-        opk: Option<[u8; 32]>,
-        signature: [[u8; 32]; 2], // [r_bytes, s_bytes]
-        verifying_key: [u8; 32],
-    },
-    Messages {
-        success: bool,
-        new_messages: bool,
-        messages: Option<Vec<(String, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Option<[u8;32]>, Option<[u8;32]>, Option<[u8;32]>)>>,
-    },
+
+/// State shared between the requests
+struct ServerState {
+    sessions: Mutex<SessionManager>,
+    push: Mutex<PushManager>,
+    #[cfg(feature = "opaque")]
+    opaque: Mutex<OpaqueServer>,
+    login_attempts: LoginAttempts, // Keys of the logins in progress
+    certificates: CertificateIssuer,
+    storage: Arc<StorageBackend>,
+    config: Config,
 }
 
-type Db = Arc<Mutex<HashMap<String, String>>>;
+type State = Arc<ServerState>;
+
+const SERVER_CAPABILITIES: &[Capability] = &[Capability::Push, Capability::Acks, Capability::Cbor, Capability::SealedSender, Capability::Ephemeral];
 
 #[tokio::main]
 async fn main() {
+    let cli: Cli = Cli::parse();
+    let command: Option<Command> = cli.command;
+    let config: Config = match Config::load(cli) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Invalid configuration: {}", error);
+            std::process::exit(1);
+        },
+    };
+
+    if let Some(Command::Migrate) = command {
+        run_migrations(&config.data_dir);
+        return;
+    }
+
+    // The sqlite databases are migrated before the server starts
+    let storage: Arc<StorageBackend> = match StorageBackend::new(config.storage, &config.data_dir, config.database.pool_size) {
+        Ok(storage) => Arc::new(storage),
+        Err(error) => {
+            eprintln!("Error when opening the databases: {}", error);
+            std::process::exit(1);
+        },
+    };
+    if let StorageBackend::Sqlite(pools) = storage.as_ref() {
+        migrate_usernames(pools);
+    }
+    tokio::spawn(run_expiry_sweeper(storage.clone(), config.retention.message_retention(), config.retention.sweep_interval()));
+
     // Server
-    let db = Arc::new(Mutex::new(HashMap::new()));
+    let state: State = Arc::new(ServerState {
+        sessions: Mutex::new(SessionManager::new()),
+        push: Mutex::new(PushManager::new()),
+        #[cfg(feature = "opaque")]
+        opaque: Mutex::new(storage.with_storage(load_opaque_server).expect("No database connection available")),
+        login_attempts: LoginAttempts::new(),
+        certificates: storage.with_storage(load_certificate_issuer).expect("No database connection available"),
+        storage,
+        config,
+    });
+
+    // WebSocket connection used to push the messages, authenticated with the header "Authorization: Bearer <session token>"
+    let push_state: State = state.clone();
+    let push_endpoint = warp::path("ws")
+        .and(warp::ws())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>(CAPABILITIES_HEADER))
+        .map(move |ws: warp::ws::Ws, authorization: Option<String>, capabilities: Option<String>| {
+            let session_token: Option<String> = authorization.and_then(|value| value.strip_prefix("Bearer ").map(str::to_string));
+            let username: Option<String> = push_state.sessions.lock().unwrap().validate(session_token.as_ref());
+            // Without the header (older clients), the connection only gets the messages
+            let capabilities: Vec<Capability> = capabilities.and_then(|value| serde_json::from_str(&value).ok()).unwrap_or_default();
+            match (username, session_token) {
+                (Some(username), Some(session_token)) => {
+                    let state: State = push_state.clone();
+                    ws.on_upgrade(move |websocket| push_connection(websocket, username, session_token, capabilities, state)).into_response()
+                },
+                _ => warp::reply::with_status("Invalid session token", warp::http::StatusCode::UNAUTHORIZED).into_response(),
+            }
+        });
 
+    let endpoint_state: State = state.clone();
     let endpoint = warp::post()
-        .and(warp::body::json())
+        .and(warp::body::content_length_limit(state.config.limits.max_request_bytes))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::body::bytes())
         .and(warp::addr::remote())
-        .map(move |body, addr| warp::reply::json(&action_handler(body, addr, &db.clone())));
+        .then(move |content_type: Option<String>, accept: Option<String>, body: Bytes, addr| {
+            let state: State = endpoint_state.clone();
+            async move {
+                // JSON or CBOR, the response uses the encoding accepted by the client
+                let response_encoding: Encoding = Encoding::from_accept(accept.as_deref());
+                let request: Request = match Encoding::from_content_type(content_type.as_deref()).decode(&body) {
+                    Ok(request) => request,
+                    Err(error) => return encoded_reply(&ServerError::new(ErrorCode::InvalidRequest, format!("Invalid request: {}", error)).into(), response_encoding),
+                };
 
-    println!("Server started");
+                // The queries are blocking, they run on the blocking threads so a slow query never stalls the other connections
+                let response: Response = match tokio::task::spawn_blocking(move || {
+                    state.storage.with_storage(|storage| action_handler(request, addr, &state, storage))
+                }).await {
+                    Ok(Ok(response)) => response,
+                    Ok(Err(error)) => {
+                        println!("No database connection available: {}", error);
+                        ServerError::new(ErrorCode::Internal, "Server busy, try again later").into()
+                    },
+                    Err(error) => { // The handler panicked
+                        println!("Error when handling the request: {}", error);
+                        ServerError::internal().into()
+                    },
+                };
+                encoded_reply(&response, response_encoding)
+            }
+        });
 
-    warp::serve(endpoint)
-        .tls()
-        .cert_path("src/keys/cert.pem")
-        .key_path("src/keys/key.rsa")
-        .run(([127, 0, 0, 1], 6379)).await;
+    let routes = push_endpoint.or(endpoint);
+    // One TLS server per listen address, all sharing the same state
+    let servers = state.config.listen_addresses.iter().map(|address| {
+        println!("Server started on {}", address);
+        warp::serve(routes.clone())
+            .tls()
+            .cert_path(&state.config.tls.cert_path)
+            .key_path(&state.config.tls.key_path)
+            .run(*address)
+    });
+    futures_util::future::join_all(servers).await;
 }
 
-fn action_handler(request: Action, ip_addr: Option<SocketAddr>, db: &Db) -> Response {
-    println!("Get a request: {:?}", request);
-    println!("Ip Address: {:?} ({:?})", ip_addr.unwrap().ip(), ip_addr.unwrap());
-    println!("{:?}", db);
+/// Load the OPAQUE server setup from the password database (generated at the first start)
+#[cfg(feature = "opaque")]
+fn load_opaque_server(storage: &mut dyn Storage) -> OpaqueServer {
+    match storage.get_opaque_server_setup().expect("Error when loading the OPAQUE server setup") {
+        Some(setup) => OpaqueServer::from_bytes(&setup).expect("OPAQUE server setup corrupted"),
+        None => {
+            let opaque_server: OpaqueServer = OpaqueServer::new();
+            storage.insert_opaque_server_setup(opaque_server.get_setup_bytes()).expect("Error when storing the OPAQUE server setup");
+            opaque_server
+        },
+    }
+}
 
-    let mut password_db: PasswordDatabase = match PasswordDatabase::new() {
-        Ok(res) => res,
-        Err(error) => panic!("{}", error),
-    };
+/// Load the key signing the sender certificates from the password database (generated at the first start)
+fn load_certificate_issuer(storage: &mut dyn Storage) -> CertificateIssuer {
+    match storage.get_server_signing_key().expect("Error when loading the server signing key") {
+        Some(secret_key) => CertificateIssuer::from_bytes(&secret_key),
+        None => {
+            let certificates: CertificateIssuer = CertificateIssuer::new();
+            storage.insert_server_signing_key(certificates.get_secret_key_bytes()).expect("Error when storing the server signing key");
+            certificates
+        },
+    }
+}
 
-    let mut x3dh_db: X3DHDatabase = match X3DHDatabase::new() {
-        Ok(res) => res,
-        Err(error) => panic!("{}", error),
-    };
+/// Forward the pushed messages to the WebSocket connection until the client or the server closes it
+///
+/// # Arguments
+///
+/// * `websocket` (WebSocket): Upgraded connection
+/// * `username` (String): Username of the session
+/// * `session_token` (String): Session token used to open the connection *(the connection is closed when it is revoked)*
+/// * `capabilities` (Vec\<Capability\>): Capabilities negotiated by the client *(see CAPABILITIES_HEADER)*
+/// * `state` (State): Server state
+async fn push_connection(websocket: WebSocket, username: String, session_token: String, capabilities: Vec<Capability>, state: State) {
+    let (mut websocket_sender, mut websocket_receiver) = websocket.split();
+    let (connection_id, mut push_receiver) = state.push.lock().unwrap().connect(&username, &session_token, capabilities);
+    println!("{} connected for push delivery", username);
 
-    let mut message_db: MessageDatabase = match MessageDatabase::new() {
-        Ok(res) => res,
+    loop {
+        tokio::select! {
+            payload = push_receiver.recv() => match payload {
+                Some(payload) => {
+                    if websocket_sender.send(Message::text(payload)).await.is_err() {
+                        break;
+                    }
+                },
+                None => break, // Session revoked
+            },
+            incoming = websocket_receiver.next() => match incoming {
+                Some(Ok(message)) if !message.is_close() => {}, // The client only listens
+                _ => break,
+            },
+        }
+    }
+
+    let _ = websocket_sender.close().await;
+    state.push.lock().unwrap().disconnect(&username, connection_id);
+    println!("{} disconnected from push delivery", username);
+}
+
+/// Encode the response with the encoding asked by the client *(`Accept` header)*
+fn encoded_reply(response: &Response, encoding: Encoding) -> warp::reply::Response {
+    match encoding.encode(response) {
+        Ok(body) => warp::reply::with_header(body, "content-type", encoding.content_type()).into_response(),
+        Err(error) => {
+            println!("Error when encoding the response: {}", error);
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        },
+    }
+}
+
+/// `migrate` command: migrate the databases of the data directory in place and print their schema versions
+fn run_migrations(data_dir: &Path) {
+    match migrate_data_dir(data_dir) {
+        Ok(versions) => {
+            for (database, previous_version, version) in versions {
+                println!("{}: schema version {} -> {}", database, previous_version, version);
+            }
+        },
+        Err(error) => {
+            eprintln!("Migration failed: {}", error);
+            std::process::exit(1);
+        },
+    }
+
+    match SqlitePools::open(data_dir, 1) {
+        Ok(pools) => migrate_usernames(&pools),
+        Err(error) => {
+            eprintln!("Migration failed: {}", error);
+            std::process::exit(1);
+        },
+    }
+}
+
+/// Finish the operations interrupted by a stop of the server, then rename the users registered before the username policy
+/// *(idempotent, run at every start)*
+fn migrate_usernames(pools: &SqlitePools) {
+    let mut password_db: PasswordDatabase = match pools.passwords.get() {
+        Ok(conn) => PasswordDatabase::new(conn, &pools.data_dir),
         Err(error) => panic!("{}", error),
     };
 
-    let result = match request {
-        Action::NewUser {username, password} => {
-            let user_exist: bool = match password_db.user_exist(username.clone()) {
-                Ok(res) => res,
-                Err(error) => panic!("{}", error),
-            };
-            if user_exist {
-                return Response::ResponseStatus { success: false }
+    let completed: usize = password_db.complete_pending_operations().expect("Error when completing the pending operations");
+    if completed > 0 {
+        println!("{} interrupted operation(s) completed", completed);
+    }
+
+    let not_migrated: Vec<String> = password_db.normalize_usernames().expect("Error when normalizing the usernames");
+    for username in not_migrated {
+        println!("Username \"{}\" not normalized (canonical form already taken or OPAQUE account)", username);
+    }
+}
+
+/// Reserve the keys of a login attempt, then check that they are not locked *(see rate_limit::LoginAttempts)*
+///
+/// # Arguments
+///
+/// * `keys` (&Vec\<String\>): Keys of the attempt *(see rate_limit::attempt_keys)*
+/// * `timeout` (Duration): End of the reservation if it is kept after the request
+///
+/// # Output
+///
+/// * `reservation` (Result\<AttemptReservation, ServerError\>): Keys reserved until the result of the attempt is recorded
+fn start_login_attempt<'a, S: LoginAttemptStore + ?Sized>(state: &'a State, attempt_store: &mut S, keys: &Vec<String>, timeout: Duration) -> Result<AttemptReservation<'a>, ServerError> {
+    let reservation: AttemptReservation = state.login_attempts.reserve(keys, timeout)
+        .ok_or_else(|| ServerError::new(ErrorCode::RateLimited, "A login with this username or from this address is in progress, try again later"))?;
+    if let Some(retry_after) = get_login_lockout(attempt_store, keys)? {
+        return Err(locked_out(retry_after))
+    }
+    Ok(reservation)
+}
+
+/// Return the number of seconds before the next login attempt if the username or the source address is locked
+///
+/// # Arguments
+///
+/// * `attempt_store` (&mut S): Login attempt storage
+/// * `keys` (&Vec\<String\>): Keys of the attempts *(see rate_limit::attempt_keys)*
+fn get_login_lockout<S: LoginAttemptStore + ?Sized>(attempt_store: &mut S, keys: &Vec<String>) -> rusqlite::Result<Option<u64>> {
+    let now: u64 = unix_time_now();
+    let mut locked_until: Option<u64> = None;
+    for key in keys {
+        locked_until = locked_until.max(attempt_store.get_lockout(key, now)?);
+    }
+    Ok(locked_until.map(|locked_until| locked_until - now))
+}
+
+/// Record the result of a login attempt *(a success only resets the username, a failure locks both keys)*
+fn record_login_attempt<S: LoginAttemptStore + ?Sized>(attempt_store: &mut S, keys: &Vec<String>, success: bool) -> rusqlite::Result<()> {
+    let now: u64 = unix_time_now();
+    if success {
+        attempt_store.reset_failures(&keys[0])?;
+    } else {
+        for key in keys {
+            attempt_store.record_failure(key, now)?;
+        }
+    }
+    Ok(())
+}
+
+/// Error returned while the username or the source address is locked
+fn locked_out(retry_after: u64) -> ServerError {
+    ServerError::new(ErrorCode::RateLimited, format!("Too many failed logins, try again in {} seconds", retry_after))
+}
+
+/// Refuse the requests of a client older than the compatibility window *(`MIN_PROTOCOL_VERSION`)* or newer than the server
+///
+/// `Hello` is accepted with any version, so an older or newer client learns the versions supported by the server.
+fn check_protocol_version(protocol_version: u32, action: &Action) -> Result<(), ServerError> {
+    if matches!(action, Action::Hello { .. }) {
+        return Ok(())
+    }
+    if protocol_version < MIN_PROTOCOL_VERSION {
+        return Err(ServerError::new(ErrorCode::UpgradeRequired,
+            format!("Protocol version {} is no longer supported, update the client (the server supports versions {} to {})", protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)))
+    }
+    if protocol_version > PROTOCOL_VERSION {
+        return Err(ServerError::new(ErrorCode::InvalidRequest,
+            format!("Protocol version {} is newer than the server (versions {} to {})", protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)))
+    }
+    Ok(())
+}
+
+/// Return the username of the session, or an `Unauthenticated` error
+fn authenticated(current_user: Option<String>) -> Result<String, ServerError> {
+    current_user.ok_or_else(ServerError::unauthenticated)
+}
+
+/// Refuse the login of a username registered before the username policy that could not be renamed *(see migrate_usernames)*
+///
+/// Called once the login under the canonical username has failed, so a user whose canonical username is also the
+/// canonical form of a legacy username can still log in.
+///
+/// # Arguments
+///
+/// * `requested_username` (String): Username sent by the client
+/// * `username` (String): Canonical form of the username
+fn check_not_migrated<S: Storage + ?Sized>(storage: &mut S, requested_username: &str, username: &str) -> Result<(), ServerError> {
+    if requested_username != username && storage.user_exist(requested_username.to_string())? {
+        return Err(ServerError::new(ErrorCode::InvalidUsername, "This account was registered before the username policy and could not be renamed, register a new account"))
+    }
+    Ok(())
+}
+
+/// Check that the username follows the username policy and is not registered yet
+///
+/// # Output
+///
+/// * `username` (Result\<String, ServerError\>): Canonical form of the username
+fn check_new_username<S: Storage + ?Sized>(storage: &mut S, username: &str) -> Result<String, ServerError> {
+    let username: String = validate_username(username)
+        .map_err(|error| ServerError::new(ErrorCode::InvalidUsername, format!("{}", error)))?;
+    if storage.user_exist(username.clone())? || storage.has_x3dh_keys(&username)? {
+        return Err(ServerError::new(ErrorCode::UserExists, "This username is already used"))
+    }
+    Ok(username)
+}
+
+/// Push the message queued for the receiver when it is connected *(it stays queued until the receiver acknowledges it)*
+///
+/// # Output
+///
+/// * `response` (Result\<Response, ServerError\>): `MessageSent` for the sender
+fn deliver_message<S: Storage + ?Sized>(state: &State, storage: &mut S, username_receiver: &str, message: Envelope) -> Result<Response, ServerError> {
+    let (message_id, guid, received_at) = (message.message_id, message.guid.clone(), message.received_at);
+    let pushed_message: Envelope = Envelope { delivery_attempts: 1, ..message };
+    let payload: String = serde_json::to_string(&Response::Messages { success: true, new_messages: true, messages: Some(vec![pushed_message]), has_more: false }).expect("Error when serializing the pushed message");
+    if state.push.lock().unwrap().push(username_receiver, &payload) {
+        storage.mark_delivered(message_id)?;
+    }
+    Ok(Response::MessageSent { message_id, guid, received_at })
+}
+
+/// Push an ephemeral message to the receiver if it is connected with the `Ephemeral` capability *(never stored, dropped otherwise)*
+///
+/// # Arguments
+///
+/// * `username_receiver` (&str): Username of the receiver
+/// * `message` (Envelope): Message without id nor sequence number *(0)*
+///
+/// # Output
+///
+/// * `response` (Response): `ResponseStatus`, `false` if the receiver is not connected or can not read ephemeral messages
+fn push_ephemeral(state: &State, username_receiver: &str, message: Envelope) -> Response {
+    let payload: String = serde_json::to_string(&Response::Ephemeral { message }).expect("Error when serializing the ephemeral message");
+    Response::ResponseStatus { success: state.push.lock().unwrap().push_with_capability(username_receiver, &payload, Capability::Ephemeral) }
+}
+
+/// Execute the action of the request and answer `Response::Error` if it failed
+///
+/// # Arguments
+///
+/// * `request` (Request): Request of the client
+/// * `ip_addr` (Option\<SocketAddr\>): Source address *(used to lock the logins)*
+/// * `state` (&State): Server state
+/// * `storage` (&mut S): Storage *(sqlite databases or in-memory storage)*
+fn action_handler<S: Storage + ?Sized>(request: Request, ip_addr: Option<SocketAddr>, state: &State, storage: &mut S) -> Response {
+    match execute_action(request, ip_addr, state, storage) {
+        Ok(response) => response,
+        Err(error) => error.into(),
+    }
+}
+
+fn execute_action<S: Storage + ?Sized>(request: Request, ip_addr: Option<SocketAddr>, state: &State, storage: &mut S) -> Result<Response, ServerError> {
+    // The requests are not logged: the action and the source address would link a sealed message to its sender
+    check_protocol_version(request.protocol_version, &request.action)?;
+
+    // Username of the session, None if the client is not authenticated
+    let current_user: Option<String> = state.sessions.lock().unwrap().validate(request.session_token.as_ref());
+
+    let result = match request.action {
+        Action::Hello {capabilities} => {
+            Response::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                min_protocol_version: MIN_PROTOCOL_VERSION,
+                capabilities: SERVER_CAPABILITIES.iter().filter(|capability| capabilities.contains(capability)).copied().collect(),
             }
-            password_db.insert_user(&username, &password).expect("Database done?");
+        },
+        Action::NewUser {username, password} => {
+            let username: String = check_new_username(storage, &username)?;
+            storage.insert_user(&username, &password)?;
             Response::ResponseStatus { success: true }
         },
-        Action::LogIn {username, password} => {
-            let mut db = db.lock().unwrap();
-            if db.contains_key(&ip_addr.unwrap().to_string()) {
-                return Response::ResponseStatus { success: false }
-            }
-            let password_valid: bool = match password_db.check_password(&username, password) {
-                Ok(res) => res,
-                Err(error) => panic!("{}", error),
-            };
-            if password_valid {
-                db.insert(ip_addr.unwrap().to_string(), username.clone());
-                return Response::ResponseStatus { success: true }
+        Action::Register {username, password, ik, spk, opk_bundle, signature, verifying_key} => {
+            let username: String = check_new_username(storage, &username)?;
+            match storage.register_user(&username, Credential::Password(password), ik, spk, opk_bundle, signature, verifying_key) {
+                Ok(()) => Response::ResponseStatus { success: true },
+                // Registered by another request since the check
+                Err(error) if is_constraint_violation(&error) => return Err(ServerError::new(ErrorCode::UserExists, "This username is already used")),
+                Err(error) => return Err(error.into()),
+            }
+        },
+        Action::LogIn {username: requested_username, password} => {
+            let username: String = normalize_username(&requested_username);
+            let keys: Vec<String> = attempt_keys(&username, ip_addr);
+            let _reservation: AttemptReservation = start_login_attempt(state, storage, &keys, ATTEMPT_TIMEOUT)?;
+
+            let password_valid: bool = storage.check_password(&username, password)?;
+            record_login_attempt(storage, &keys, password_valid)?;
+            if !password_valid {
+                check_not_migrated(storage, &requested_username, &username)?;
+                return Err(ServerError::new(ErrorCode::InvalidCredentials, "Invalid username or password"))
             }
-            Response::ResponseStatus { success: false }
+            let (token, session) = state.sessions.lock().unwrap().create_session(&username);
+            Response::Session { token, expires_at: session.get_expires_at() }
         },
-        Action::LogOut {} => {
-            let mut db = db.lock().unwrap();
-            if db.remove(&ip_addr.unwrap().to_string()).is_some() {
-                return Response::ResponseStatus { success: true }
+        Action::LogOut => {
+            let token: String = request.session_token.ok_or_else(ServerError::unauthenticated)?;
+            state.push.lock().unwrap().disconnect_session(&token);
+            if !state.sessions.lock().unwrap().revoke(&token) {
+                return Err(ServerError::unauthenticated())
             }
-            Response::ResponseStatus { success: false }
+            Response::ResponseStatus { success: true }
         },
-        Action::GetAllUsers {} => {
-            let db = db.lock().unwrap();
-            if db.contains_key(&ip_addr.unwrap().to_string()) {
-                let current_username = db.get(&ip_addr.unwrap().to_string()).unwrap();
-                let mut user_list: Vec<String> = x3dh_db.get_all_users().unwrap();
-                user_list.retain(|username| username != current_username);
-                return Response::UserList { result: user_list }
-            }
-            Response::ResponseStatus { success: false }
+        Action::GetAllUsers => {
+            let current_username: String = authenticated(current_user)?;
+            let mut user_list: Vec<String> = storage.get_all_users()?;
+            user_list.retain(|username| username != &current_username);
+            Response::UserList { result: user_list }
         },
-        Action::GetMessages {} => {
-            let db = db.lock().unwrap();
-            if db.contains_key(&ip_addr.unwrap().to_string()) {
-                let current_username = db.get(&ip_addr.unwrap().to_string()).unwrap();
-                let messages: Vec<(i64, String, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Option<[u8;32]>, Option<[u8;32]>, Option<[u8;32]>)> = message_db.get_all_user_messages(&current_username.clone()).unwrap();
-                if messages.len() > 0 {
-                    let current_user_messages: Vec<(String, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Option<[u8;32]>, Option<[u8;32]>, Option<[u8;32]>)> = messages.clone()
-                        .into_iter()
-                        .map(|(id, s, he, he_n, c, c_n, ek, opk, ik_sender)| (s, he, he_n, c, c_n, ek, opk, ik_sender))
-                        .collect();
-                    for message in messages.clone() {
-                        message_db.delete_message(message.0).expect("Error delete message in message_db");
-                    }
-                    return Response::Messages { success: true, new_messages: true, messages: Some(current_user_messages) }
-                }
-                return Response::Messages { success: true, new_messages: false, messages: None }
+        Action::GetMessages { after, page_size } => {
+            let current_username: String = authenticated(current_user)?;
+            // The messages stay in the database until the client acknowledges them (AckMessages)
+            let page_size: u32 = page_size.unwrap_or(state.config.limits.default_page_size).clamp(1, state.config.limits.max_page_size);
+            let (messages, has_more) = storage.get_user_messages_page(&current_username, after.unwrap_or(0), page_size, state.config.limits.page_byte_budget)?;
+            if !messages.is_empty() {
+                return Ok(Response::Messages { success: true, new_messages: true, messages: Some(messages), has_more })
             }
-            Response::ResponseStatus { success: false }
+            Response::Messages { success: true, new_messages: false, messages: None, has_more: false }
+        },
+        Action::GetExpiredMessages => {
+            let current_username: String = authenticated(current_user)?;
+            let message_ids: Vec<i64> = storage.take_expired_messages(&current_username)?;
+            Response::ExpiredMessages { message_ids }
+        },
+        Action::AckMessages { ids } => {
+            let current_username: String = authenticated(current_user)?;
+            storage.acknowledge_messages(&current_username, &ids)?;
+            Response::ResponseStatus { success: true }
         },
         Action::PublishX3DHInformation {ik, spk, opk_bundle, signature, verifying_key} => {
-            let db = db.lock().unwrap();
-            if db.contains_key(&ip_addr.unwrap().to_string()) {
-                let current_username = db.get(&ip_addr.unwrap().to_string()).unwrap();
-
-                if x3dh_db.user_exist(current_username).unwrap() { // User has already publish information (should not change ik)
-                   return  Response::ResponseStatus { success: false }
-                }
-
-                match x3dh_db.insert_x3dh_keys(current_username, ik, spk, opk_bundle, signature, verifying_key) {
-                    Ok(()) => return Response::ResponseStatus { success: true },
-                    Err(error) => {
-                        println!("{}", error);
-                        return Response::ResponseStatus { success: false } },
-                }
+            let current_username: String = authenticated(current_user)?;
+            if storage.has_x3dh_keys(&current_username)? { // User has already publish information (should not change ik)
+                return Err(ServerError::new(ErrorCode::KeysAlreadyPublished, "The X3DH keys of this user are already published"))
             }
-
-            Response::ResponseStatus { success: false }
+            storage.insert_x3dh_keys(&current_username, ik, spk, opk_bundle, signature, verifying_key)?;
+            Response::ResponseStatus { success: true }
         },
         Action::UpdateX3DHSignedPreKey {spk, signature, verifying_key} => {
             // TODO Check if it has been updated sufficient days ago (add in the database) [Or do it every week/month at a precise date for everyone]
-            let db = db.lock().unwrap();
-            if db.contains_key(&ip_addr.unwrap().to_string()) {
-                let current_username = db.get(&ip_addr.unwrap().to_string()).unwrap();
-
-                if x3dh_db.user_exist(current_username).unwrap() { // Check if the user has sent the first X3DH keys
-                    x3dh_db.update_spk(current_username, spk, signature, verifying_key).expect("Error updating spk");
-                    return  Response::ResponseStatus { success: true }
-                }
+            let current_username: String = authenticated(current_user)?;
+            if !storage.has_x3dh_keys(&current_username)? { // Check if the user has sent the first X3DH keys
+                return Err(ServerError::new(ErrorCode::NoKeysPublished, "Publish the X3DH keys first (PublishX3DHInformation)"))
             }
-            Response::ResponseStatus { success: false }
+            storage.update_spk(&current_username, spk, signature, verifying_key)?;
+            Response::ResponseStatus { success: true }
         },
         Action::SupplyX3DHOneTimePreKeyBundle {opk_bundle} => {
-            let db = db.lock().unwrap();
-            if db.contains_key(&ip_addr.unwrap().to_string()) {
-                let current_username = db.get(&ip_addr.unwrap().to_string()).unwrap();
-
-                if x3dh_db.user_exist(current_username).unwrap() { // Check if the user has sent the first X3DH keys
-                    x3dh_db.add_opk_bundle(current_username, opk_bundle).expect("Error inserting opk bundle");
-                    return  Response::ResponseStatus { success: true }
-                }
+            let current_username: String = authenticated(current_user)?;
+            if !storage.has_x3dh_keys(&current_username)? { // Check if the user has sent the first X3DH keys
+                return Err(ServerError::new(ErrorCode::NoKeysPublished, "Publish the X3DH keys first (PublishX3DHInformation)"))
             }
-            Response::ResponseStatus { success: false }
+            storage.add_opk_bundle(&current_username, opk_bundle)?;
+            Response::ResponseStatus { success: true }
         },
         Action::GetUserPublicKeys {username} => {
-            let db = db.lock().unwrap();
-            if db.contains_key(&ip_addr.unwrap().to_string()) {
-                match x3dh_db.get_public_keys(username) {
-                    Ok((ik, spk, opk, signature, verifying_key)) => {
-                        if opk.is_some() {
-                            x3dh_db.delete_opk_key(opk.unwrap()).expect("Error when opk deleted");
-                        }
-                        return Response::UserPublicKeys {
-                            ik: ik,
-                            spk: spk,
-                            opk: opk,
-                            signature: signature,
-                            verifying_key: verifying_key,
-                        };
-                    },
-                    Err(_) => return Response::ResponseStatus { success: false }
-                }
+            authenticated(current_user)?;
+            let (ik, spk, opk, signature, verifying_key) = match storage.get_public_keys(normalize_username(&username)) {
+                Ok(public_keys) => public_keys,
+                Err(rusqlite::Error::QueryReturnedNoRows) => return Err(ServerError::new(ErrorCode::UnknownRecipient, format!("{} has not published its X3DH keys", username))),
+                Err(error) => return Err(error.into()),
+            };
+            if let Some(opk) = opk {
+                storage.delete_opk_key(opk)?;
+            }
+            Response::UserPublicKeys {
+                ik,
+                spk,
+                opk,
+                signature,
+                verifying_key,
+            }
+        },
+        Action::DeleteAccount => {
+            let current_username: String = authenticated(current_user)?;
+            let deleted: bool = storage.delete_account(&current_username)?;
+            state.sessions.lock().unwrap().revoke_user(&current_username, None);
+            state.push.lock().unwrap().disconnect_user(&current_username, None);
+            // The failed logins would lock out the next user of the username
+            for key in attempt_keys(&current_username, None) {
+                storage.reset_failures(&key)?;
+            }
+            if !deleted { // Deleted by another session at the same time
+                return Err(ServerError::unauthenticated())
+            }
+            Response::ResponseStatus { success: true }
+        },
+        Action::ChangePassword { old, new } => {
+            let current_username: String = authenticated(current_user)?;
+            // The old password is guessed like a login, with the same lockout
+            let keys: Vec<String> = attempt_keys(&current_username, ip_addr);
+            let _reservation: AttemptReservation = start_login_attempt(state, storage, &keys, ATTEMPT_TIMEOUT)?;
+            let password_changed: bool = storage.change_password(&current_username, old, &new)?;
+            record_login_attempt(storage, &keys, password_changed)?;
+            if !password_changed {
+                return Err(ServerError::new(ErrorCode::InvalidCredentials, "The old password is not valid"))
+            }
+            state.sessions.lock().unwrap().revoke_user(&current_username, request.session_token.as_ref());
+            state.push.lock().unwrap().disconnect_user(&current_username, request.session_token.as_ref());
+            Response::ResponseStatus { success: true }
+        },
+        Action::SendMessage { username_receiver, header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used, ik_sender, ephemeral } => {
+            let sender_username: String = authenticated(current_user)?;
+            let username_receiver: String = normalize_username(&username_receiver);
+            if username_receiver == sender_username {
+                return Err(ServerError::new(ErrorCode::InvalidRequest, "A message can not be sent to its sender"))
+            }
+            if !storage.has_x3dh_keys(&username_receiver)? {
+                return Err(ServerError::new(ErrorCode::UnknownRecipient, format!("{} has not published its X3DH keys", username_receiver)))
             }
 
-            Response::ResponseStatus { success: false }
+            if ephemeral {
+                let message: Envelope = Envelope { message_id: 0, guid: generate_guid(), received_at: unix_time_now(), seq: 0, sender: Some(sender_username), sealed_sender: None, header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used, ik_sender, delivery_attempts: 1 };
+                return Ok(push_ephemeral(state, &username_receiver, message))
+            }
+            let message: Envelope = storage.add_message(&username_receiver, Some(&sender_username), None, header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used, ik_sender)?;
+            deliver_message(state, storage, &username_receiver, message)?
         },
-        Action::SendMessage { username_receiver, header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used, ik_sender} => {
-            let db = db.lock().unwrap();
-            if db.contains_key(&ip_addr.unwrap().to_string()) {
-                println!("The user is connected");
-                let sender_username = db.get(&ip_addr.unwrap().to_string()).unwrap();
-                if x3dh_db.user_exist(&username_receiver).unwrap() && &username_receiver != sender_username {
-                    println!("The user exist in the X3DH database");
-                    // TODO potential upgrade: send to the user directly when she/he's connected and check that he/she received it
-                    message_db.add_message(&username_receiver, sender_username, header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used, ik_sender).expect("Add message to database failed");
-                    return Response::ResponseStatus { success: true }
-                    /*
-                    // Code snippet to upgrade the system later
-                    if !db.values().any(|username| username == &username_receiver) {
-                        message_db.add_message(&username_receiver, sender_username, header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used).expect("Add message to database failed");
-                        return Response::ResponseStatus { success: true }
-                    } else {
-                        println!("TODO: user connected and should receive your message");
-                        return Response::ResponseStatus { success: true }
-                    }*/
-                }
+        Action::GetSenderCertificate => {
+            let username: String = authenticated(current_user)?;
+            let ik: [u8; 32] = storage.get_identity_key(&username)?
+                .ok_or_else(|| ServerError::new(ErrorCode::NoKeysPublished, "Publish the X3DH keys before asking for a sender certificate"))?;
+            Response::SenderCertificate { certificate: state.certificates.issue(&username, ik), server_key: state.certificates.get_verifying_key() }
+        },
+        Action::SetDeliveryAccessKey { access_key } => {
+            let username: String = authenticated(current_user)?;
+            if !storage.has_x3dh_keys(&username)? {
+                return Err(ServerError::new(ErrorCode::NoKeysPublished, "Publish the X3DH keys before setting a delivery access key"))
+            }
+            storage.set_access_key(&username, access_key)?;
+            Response::ResponseStatus { success: true }
+        },
+        Action::SendSealedMessage { username_receiver, access_key, sealed_sender, header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used, ephemeral } => {
+            // No session, the access key proves that the sender is a contact of the receiver (the same error for an unknown receiver)
+            let username_receiver: String = normalize_username(&username_receiver);
+            if !check_access_key(storage.get_access_key(&username_receiver)?, &access_key) {
+                return Err(ServerError::new(ErrorCode::AccessDenied, "Unknown receiver or invalid delivery access key"))
+            }
+
+            if ephemeral {
+                let message: Envelope = Envelope { message_id: 0, guid: generate_guid(), received_at: unix_time_now(), seq: 0, sender: None, sealed_sender: Some(sealed_sender), header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used, ik_sender: None, delivery_attempts: 1 };
+                return Ok(push_ephemeral(state, &username_receiver, message))
+            }
+            let message: Envelope = storage.add_message(&username_receiver, None, Some(sealed_sender), header_encrypted, header_nonce, ciphertext, nonce, ek_sender, opk_used, None)?;
+            deliver_message(state, storage, &username_receiver, message)?
+        },
+        #[cfg(feature = "opaque")]
+        Action::OpaqueRegisterStart { username, registration_request } => {
+            let username: String = check_new_username(storage, &username)?;
+            match state.opaque.lock().unwrap().registration_start(&username, &registration_request) {
+                Ok(registration_response) => Response::OpaqueRegistration { registration_response },
+                Err(_) => return Err(ServerError::new(ErrorCode::InvalidRequest, "Invalid OPAQUE registration request")),
+            }
+        },
+        #[cfg(feature = "opaque")]
+        Action::OpaqueRegisterFinish { username, registration_upload, ik, spk, opk_bundle, signature, verifying_key } => {
+            let username: String = check_new_username(storage, &username)?;
+            let password_file: Vec<u8> = match state.opaque.lock().unwrap().registration_finish(&registration_upload) {
+                Ok(password_file) => password_file,
+                Err(_) => return Err(ServerError::new(ErrorCode::InvalidRequest, "Invalid OPAQUE registration upload")),
+            };
+            match storage.register_user(&username, Credential::OpaquePasswordFile(password_file), ik, spk, opk_bundle, signature, verifying_key) {
+                Ok(()) => Response::ResponseStatus { success: true },
+                Err(error) if is_constraint_violation(&error) => return Err(ServerError::new(ErrorCode::UserExists, "This username is already used")),
+                Err(error) => return Err(error.into()),
+            }
+        },
+        #[cfg(feature = "opaque")]
+        Action::OpaqueLogInStart { username: requested_username, credential_request } => {
+            let username: String = normalize_username(&requested_username);
+            // Reserved until the last step of the login
+            let reservation: AttemptReservation = start_login_attempt(state, storage, &attempt_keys(&username, ip_addr), LOGIN_TIMEOUT)?;
+            let password_file: Option<Vec<u8>> = storage.get_opaque_password_file(&username)?;
+            if password_file.is_none() {
+                check_not_migrated(storage, &requested_username, &username)?;
+            }
+            match state.opaque.lock().unwrap().login_start(&username, password_file, &credential_request) {
+                Ok((login_id, credential_response)) => {
+                    reservation.keep();
+                    Response::OpaqueLogIn { login_id, credential_response }
+                },
+                Err(_) => return Err(ServerError::new(ErrorCode::InvalidRequest, "Invalid OPAQUE credential request")),
+            }
+        },
+        #[cfg(feature = "opaque")]
+        Action::OpaqueLogInFinish { login_id, credential_finalization } => {
+            let (username, authenticated): (Option<String>, bool) = state.opaque.lock().unwrap().login_finish(&login_id, &credential_finalization);
+            if let Some(username) = &username {
+                let keys: Vec<String> = attempt_keys(username, ip_addr);
+                let recorded: rusqlite::Result<()> = record_login_attempt(storage, &keys, authenticated);
+                state.login_attempts.release(&keys);
+                recorded?;
+            }
+            match (username, authenticated) {
+                (Some(username), true) => {
+                    let (token, session) = state.sessions.lock().unwrap().create_session(&username);
+                    Response::Session { token, expires_at: session.get_expires_at() }
+                },
+                _ => return Err(ServerError::new(ErrorCode::InvalidCredentials, "Invalid username or password")),
             }
-            Response::ResponseStatus { success: false }
         },
     };
 
-    result
+    Ok(result)
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Request {
-    user: String,
-    #[serde(flatten)]
-    action: Action,
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::config::StorageKind;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static DATA_DIRS: AtomicU32 = AtomicU32::new(0);
+
+    /// New empty data directory of the sqlite databases
+    fn test_data_dir() -> PathBuf {
+        let data_dir: PathBuf = std::env::temp_dir().join(format!("mini-signal-test-{}-{}", std::process::id(), DATA_DIRS.fetch_add(1, Ordering::Relaxed)));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        std::fs::create_dir_all(&data_dir).unwrap();
+        data_dir
+    }
+
+    /// Server state over each storage backend *(in-memory, then sqlite)*
+    fn test_states() -> Vec<State> {
+        [StorageKind::Memory, StorageKind::Sqlite].into_iter().map(test_state).collect()
+    }
+
+    fn test_state(kind: StorageKind) -> State {
+        let storage: Arc<StorageBackend> = Arc::new(StorageBackend::new(kind, &test_data_dir(), 4).unwrap());
+        Arc::new(ServerState {
+            sessions: Mutex::new(SessionManager::new()),
+            push: Mutex::new(PushManager::new()),
+            #[cfg(feature = "opaque")]
+            opaque: Mutex::new(storage.with_storage(load_opaque_server).unwrap()),
+            login_attempts: LoginAttempts::new(),
+            certificates: CertificateIssuer::new(),
+            storage,
+            config: Config::default(),
+        })
+    }
+
+    fn send(state: &State, session_token: Option<&str>, action: Action) -> Response {
+        state.storage.with_storage(|storage| action_handler(Request::new(session_token.map(str::to_string), action), None, state, storage)).unwrap()
+    }
+
+    fn log_in_from(state: &State, ip_addr: &str, username: &str, password: &str) -> Response {
+        let action: Action = Action::LogIn { username: username.to_string(), password: password.to_string() };
+        state.storage.with_storage(|storage| action_handler(Request::new(None, action), Some(ip_addr.parse().unwrap()), state, storage)).unwrap()
+    }
+
+    fn register(state: &State, username: &str) -> Response {
+        send(state, None, Action::Register {
+            username: username.to_string(),
+            password: format!("{} password", username),
+            ik: [1; 32],
+            spk: [2; 32],
+            opk_bundle: vec![[3; 32], [4; 32]],
+            signature: [[5; 32], [6; 32]],
+            verifying_key: [7; 32],
+        })
+    }
+
+    fn log_in(state: &State, username: &str) -> String {
+        match send(state, None, Action::LogIn { username: username.to_string(), password: format!("{} password", username) }) {
+            Response::Session { token, .. } => token,
+            response => panic!("Unexpected answer to LogIn: {:?}", response),
+        }
+    }
+
+    fn send_message(state: &State, session_token: &str, username_receiver: &str, ciphertext: Vec<u8>) -> Response {
+        send(state, Some(session_token), Action::SendMessage {
+            username_receiver: username_receiver.to_string(),
+            header_encrypted: vec![1],
+            header_nonce: vec![2],
+            ciphertext,
+            nonce: vec![3],
+            ek_sender: None,
+            opk_used: None,
+            ik_sender: None,
+            ephemeral: false,
+        })
+    }
+
+    fn send_sealed_message(state: &State, username_receiver: &str, access_key: [u8; 16], ciphertext: Vec<u8>) -> Response {
+        send(state, None, Action::SendSealedMessage {
+            username_receiver: username_receiver.to_string(),
+            access_key,
+            sealed_sender: vec![4],
+            header_encrypted: vec![1],
+            header_nonce: vec![2],
+            ciphertext,
+            nonce: vec![3],
+            ek_sender: None,
+            opk_used: None,
+            ephemeral: false,
+        })
+    }
+
+    fn get_messages(state: &State, session_token: &str) -> Vec<Envelope> {
+        match send(state, Some(session_token), Action::GetMessages { after: None, page_size: None }) {
+            Response::Messages { messages, .. } => messages.unwrap_or_default(),
+            response => panic!("Unexpected answer to GetMessages: {:?}", response),
+        }
+    }
+
+    /// Ids of the messages of a page, and `has_more`
+    fn get_messages_page(state: &State, session_token: &str, after: Option<i64>, page_size: Option<u32>) -> (Vec<i64>, bool) {
+        match send(state, Some(session_token), Action::GetMessages { after, page_size }) {
+            Response::Messages { messages, has_more, .. } => (messages.unwrap_or_default().iter().map(|message| message.message_id).collect(), has_more),
+            response => panic!("Unexpected answer to GetMessages: {:?}", response),
+        }
+    }
+
+    fn error_code(response: Response) -> Option<ErrorCode> {
+        match response {
+            Response::Error { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn register_and_log_in() {
+        for state in test_states() {
+            assert_eq!(register(&state, "alice"), Response::ResponseStatus { success: true });
+            // The canonical form is already taken
+            assert_eq!(error_code(register(&state, " Alice")), Some(ErrorCode::UserExists));
+
+            let wrong_password: Response = send(&state, None, Action::LogIn { username: "alice".to_string(), password: "wrong".to_string() });
+            assert_eq!(error_code(wrong_password), Some(ErrorCode::InvalidCredentials));
+            let unknown_user: Response = send(&state, None, Action::LogIn { username: "bob".to_string(), password: "bob password".to_string() });
+            assert_eq!(error_code(unknown_user), Some(ErrorCode::InvalidCredentials));
+
+            // The username is normalized before the password check
+            let session: Response = send(&state, None, Action::LogIn { username: "Alice".to_string(), password: "alice password".to_string() });
+            let token: String = match session {
+                Response::Session { token, .. } => token,
+                response => panic!("Unexpected answer to LogIn: {:?}", response),
+            };
+            assert_eq!(send(&state, Some(&token), Action::GetAllUsers), Response::UserList { result: vec![] });
+        }
+    }
+
+    #[test]
+    fn protocol_versions() {
+        for state in test_states() {
+            let send_version = |protocol_version: u32, action: Action| -> Response {
+                state.storage.with_storage(|storage| action_handler(Request { protocol_version, session_token: None, action }, None, &state, storage)).unwrap()
+            };
+            let hello = || Action::Hello { capabilities: vec![Capability::Push] };
+            let log_in = || Action::LogIn { username: "alice".to_string(), password: "alice password".to_string() };
+            register(&state, "alice");
+
+            assert_eq!(error_code(send_version(MIN_PROTOCOL_VERSION - 1, log_in())), Some(ErrorCode::UpgradeRequired));
+            assert!(matches!(send_version(MIN_PROTOCOL_VERSION, log_in()), Response::Session { .. }));
+            assert_eq!(error_code(send_version(PROTOCOL_VERSION + 1, log_in())), Some(ErrorCode::InvalidRequest));
+
+            // Hello tells an older or newer client the versions supported by the server
+            for protocol_version in [1, MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+                assert_eq!(send_version(protocol_version, hello()), Response::Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    min_protocol_version: MIN_PROTOCOL_VERSION,
+                    capabilities: vec![Capability::Push],
+                });
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_username_login() {
+        for state in test_states() {
+            // Registered before the username policy, then kept unchanged (its canonical form was taken)
+            state.storage.with_storage(|storage| storage.register_user("Alice", Credential::Password("legacy password".to_string()),
+                                                                       [1; 32], [2; 32], vec![[3; 32]], [[4; 32], [5; 32]], [6; 32])).unwrap().unwrap();
+            assert_eq!(register(&state, "alice"), Response::ResponseStatus { success: true });
+
+            let legacy: Response = send(&state, None, Action::LogIn { username: "Alice".to_string(), password: "legacy password".to_string() });
+            assert_eq!(error_code(legacy), Some(ErrorCode::InvalidUsername));
+            // The user of the canonical username is not affected
+            let canonical: Response = send(&state, None, Action::LogIn { username: "Alice".to_string(), password: "alice password".to_string() });
+            assert!(matches!(canonical, Response::Session { .. }));
+        }
+    }
+
+    #[test]
+    fn login_lockout() {
+        for state in test_states() {
+            register(&state, "alice");
+            register(&state, "bob");
+            // Free failures, then the username is locked even with the right password
+            for _ in 0..4 {
+                assert_eq!(error_code(log_in_from(&state, "10.0.0.1:1000", "alice", "wrong")), Some(ErrorCode::InvalidCredentials));
+            }
+            assert_eq!(error_code(log_in_from(&state, "10.0.0.2:1000", "alice", "alice password")), Some(ErrorCode::RateLimited));
+
+            // The source address is locked for the other usernames too
+            assert_eq!(error_code(log_in_from(&state, "10.0.0.1:2000", "bob", "bob password")), Some(ErrorCode::RateLimited));
+            assert!(matches!(log_in_from(&state, "10.0.0.3:1000", "bob", "bob password"), Response::Session { .. }));
+
+            // A login is refused while an attempt with the same key is in progress
+            let keys: Vec<String> = attempt_keys("bob", None);
+            let reservation: AttemptReservation = state.login_attempts.reserve(&keys, ATTEMPT_TIMEOUT).unwrap();
+            assert_eq!(error_code(log_in_from(&state, "10.0.0.3:1000", "bob", "bob password")), Some(ErrorCode::RateLimited));
+            drop(reservation);
+            assert!(matches!(log_in_from(&state, "10.0.0.3:1000", "bob", "bob password"), Response::Session { .. }));
+        }
+    }
+
+    #[test]
+    fn login_backoff() {
+        for state in test_states() {
+            state.storage.with_storage(|storage| {
+                let key: String = "user:alice".to_string();
+                let lockouts: Vec<Option<u64>> = (0..6).map(|failure| {
+                    storage.record_failure(&key, 1000 + failure).unwrap();
+                    storage.get_lockout(&key, 1000 + failure).unwrap().map(|locked_until| locked_until - (1000 + failure))
+                }).collect();
+                assert_eq!(lockouts, vec![None, None, None, Some(30), Some(60), Some(120)]);
+
+                // Forgotten after a day without failure, or after a successful login
+                storage.record_failure(&key, 1000 + 60 * 60 * 24 + 5).unwrap();
+                assert_eq!(storage.get_lockout(&key, 1000 + 60 * 60 * 24 + 5).unwrap(), None);
+                for failure in 0..4 {
+                    storage.record_failure(&key, 2000 + failure).unwrap();
+                }
+                storage.reset_failures(&key).unwrap();
+                assert_eq!(storage.get_lockout(&key, 2003).unwrap(), None);
+            }).unwrap();
+        }
+    }
+
+    #[test]
+    fn change_password() {
+        for state in test_states() {
+            register(&state, "alice");
+            let alice_token: String = log_in(&state, "alice");
+            let other_token: String = log_in(&state, "alice");
+            let change_password = |old: &str, new: &str| send(&state, Some(&alice_token), Action::ChangePassword { old: old.to_string(), new: new.to_string() });
+
+            assert_eq!(error_code(change_password("wrong", "new password")), Some(ErrorCode::InvalidCredentials));
+            // Nothing changed
+            assert_eq!(send(&state, Some(&other_token), Action::GetAllUsers), Response::UserList { result: vec![] });
+            assert!(matches!(send(&state, None, Action::LogIn { username: "alice".to_string(), password: "alice password".to_string() }), Response::Session { .. }));
+
+            assert_eq!(change_password("alice password", "new password"), Response::ResponseStatus { success: true });
+            // The other sessions are closed, not the one that changed the password
+            assert_eq!(error_code(send(&state, Some(&other_token), Action::GetAllUsers)), Some(ErrorCode::Unauthenticated));
+            assert_eq!(send(&state, Some(&alice_token), Action::GetAllUsers), Response::UserList { result: vec![] });
+            let old_password: Response = send(&state, None, Action::LogIn { username: "alice".to_string(), password: "alice password".to_string() });
+            assert_eq!(error_code(old_password), Some(ErrorCode::InvalidCredentials));
+            assert!(matches!(send(&state, None, Action::LogIn { username: "alice".to_string(), password: "new password".to_string() }), Response::Session { .. }));
+
+            // The wrong old passwords lock the username like the failed logins
+            for _ in 0..4 {
+                assert_eq!(error_code(change_password("wrong", "other password")), Some(ErrorCode::InvalidCredentials));
+            }
+            assert_eq!(error_code(change_password("new password", "other password")), Some(ErrorCode::RateLimited));
+            let locked: Response = send(&state, None, Action::LogIn { username: "alice".to_string(), password: "new password".to_string() });
+            assert_eq!(error_code(locked), Some(ErrorCode::RateLimited));
+        }
+    }
+
+    #[test]
+    fn send_fetch_and_acknowledge() {
+        for state in test_states() {
+            register(&state, "alice");
+            register(&state, "bob");
+            let alice_token: String = log_in(&state, "alice");
+            let bob_token: String = log_in(&state, "bob");
+
+            let message_ids: Vec<i64> = [vec![10], vec![11]].into_iter().map(|ciphertext| match send_message(&state, &alice_token, "bob", ciphertext) {
+                Response::MessageSent { message_id, .. } => message_id,
+                response => panic!("Unexpected answer to SendMessage: {:?}", response),
+            }).collect();
+            assert_eq!(error_code(send_message(&state, &alice_token, "carol", vec![12])), Some(ErrorCode::UnknownRecipient));
+
+            let messages: Vec<Envelope> = get_messages(&state, &bob_token);
+            assert_eq!(messages.iter().map(|message| message.message_id).collect::<Vec<i64>>(), message_ids);
+            assert_eq!(messages.iter().map(|message| message.seq).collect::<Vec<u64>>(), vec![1, 2]);
+            assert_eq!(messages[0].sender, Some("alice".to_string()));
+            assert_eq!(messages[1].ciphertext, vec![11]);
+            assert!(get_messages(&state, &alice_token).is_empty());
+
+            // Sent again until they are acknowledged
+            assert_eq!(get_messages(&state, &bob_token).len(), 2);
+            assert_eq!(send(&state, Some(&bob_token), Action::AckMessages { ids: vec![message_ids[0]] }), Response::ResponseStatus { success: true });
+            assert_eq!(get_messages(&state, &bob_token).iter().map(|message| message.message_id).collect::<Vec<i64>>(), vec![message_ids[1]]);
+            send(&state, Some(&bob_token), Action::AckMessages { ids: vec![message_ids[1]] });
+            assert!(get_messages(&state, &bob_token).is_empty());
+        }
+    }
+
+    #[test]
+    fn message_pages() {
+        for state in test_states() {
+            for username in ["alice", "bob", "carol"] {
+                register(&state, username);
+            }
+            let alice_token: String = log_in(&state, "alice");
+            let bob_token: String = log_in(&state, "bob");
+            let carol_token: String = log_in(&state, "carol");
+
+            // The messages of carol are between the ones of bob
+            let mut message_ids: Vec<i64> = Vec::new();
+            for ciphertext in 0..5 {
+                send_message(&state, &alice_token, "carol", vec![ciphertext]);
+                match send_message(&state, &alice_token, "bob", vec![ciphertext]) {
+                    Response::MessageSent { message_id, .. } => message_ids.push(message_id),
+                    response => panic!("Unexpected answer to SendMessage: {:?}", response),
+                }
+            }
+
+            // The cursor is the id of the last message of the previous page
+            assert_eq!(get_messages_page(&state, &bob_token, None, Some(2)), (message_ids[0..2].to_vec(), true));
+            assert_eq!(get_messages_page(&state, &bob_token, Some(message_ids[1]), Some(2)), (message_ids[2..4].to_vec(), true));
+            assert_eq!(get_messages_page(&state, &bob_token, Some(message_ids[3]), Some(2)), (message_ids[4..].to_vec(), false));
+            assert_eq!(get_messages_page(&state, &bob_token, Some(message_ids[4]), Some(2)), (vec![], false));
+            // At least one message per page
+            assert_eq!(get_messages_page(&state, &bob_token, None, Some(0)), (message_ids[0..1].to_vec(), true));
+            assert_eq!(get_messages_page(&state, &carol_token, None, None).0.len(), 5);
+
+            // The page stops before the encrypted messages exceed the byte budget
+            let budget: usize = state.config.limits.page_byte_budget;
+            send(&state, Some(&bob_token), Action::AckMessages { ids: message_ids.clone() });
+            let message_ids: Vec<i64> = [budget * 2 / 5, budget * 2 / 5, budget * 2 / 5, budget * 3 / 2].into_iter().map(|len| match send_message(&state, &alice_token, "bob", vec![0; len]) {
+                Response::MessageSent { message_id, .. } => message_id,
+                response => panic!("Unexpected answer to SendMessage: {:?}", response),
+            }).collect();
+            assert_eq!(get_messages_page(&state, &bob_token, None, None), (message_ids[0..2].to_vec(), true));
+            assert_eq!(get_messages_page(&state, &bob_token, Some(message_ids[1]), None), (message_ids[2..3].to_vec(), true));
+            // A message larger than the budget is sent alone
+            assert_eq!(get_messages_page(&state, &bob_token, Some(message_ids[2]), None), (message_ids[3..].to_vec(), false));
+        }
+    }
+
+    #[test]
+    fn expiry_sweep() {
+        for state in test_states() {
+            register(&state, "alice");
+            register(&state, "bob");
+            let alice_token: String = log_in(&state, "alice");
+            let bob_token: String = log_in(&state, "bob");
+            send(&state, Some(&bob_token), Action::SetDeliveryAccessKey { access_key: [8; 16] });
+            let message_id: i64 = match send_message(&state, &alice_token, "bob", vec![10; 8000]) {
+                Response::MessageSent { message_id, .. } => message_id,
+                response => panic!("Unexpected answer to SendMessage: {:?}", response),
+            };
+            for _ in 0..100 {
+                send_sealed_message(&state, "bob", [8; 16], vec![11; 8000]);
+            }
+
+            // Only the messages received before the end of the retention are deleted
+            let now: u64 = unix_time_now();
+            assert_eq!(state.storage.with_storage(|storage| storage.expire_messages(now - 60, now)).unwrap().unwrap(), 0);
+            assert_eq!(state.storage.with_storage(|storage| storage.expire_messages(now + 1, now)).unwrap().unwrap(), 101);
+            assert!(get_messages(&state, &bob_token).is_empty());
+
+            // The sender is told once, not the unknown sender of the sealed messages
+            assert_eq!(send(&state, Some(&alice_token), Action::GetExpiredMessages), Response::ExpiredMessages { message_ids: vec![message_id] });
+            assert_eq!(send(&state, Some(&alice_token), Action::GetExpiredMessages), Response::ExpiredMessages { message_ids: vec![] });
+            assert_eq!(send(&state, Some(&bob_token), Action::GetExpiredMessages), Response::ExpiredMessages { message_ids: vec![] });
+
+            // The space of the messages is given back without rebuilding the file
+            if let StorageBackend::Sqlite(pools) = state.storage.as_ref() {
+                let conn = pools.messages.get().unwrap();
+                assert_eq!(conn.pragma_query_value(None, "auto_vacuum", |row| row.get::<_, i64>(0)).unwrap(), 2);
+                assert_eq!(conn.pragma_query_value(None, "freelist_count", |row| row.get::<_, i64>(0)).unwrap(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn ephemeral_messages() {
+        for state in test_states() {
+            register(&state, "alice");
+            register(&state, "bob");
+            let alice_token: String = log_in(&state, "alice");
+            let bob_token: String = log_in(&state, "bob");
+            let typing = |state: &State| send(state, Some(&alice_token), Action::SendMessage {
+                username_receiver: "bob".to_string(),
+                header_encrypted: vec![1],
+                header_nonce: vec![2],
+                ciphertext: vec![10],
+                nonce: vec![3],
+                ek_sender: None,
+                opk_used: None,
+                ik_sender: None,
+                ephemeral: true,
+            });
+
+            // Dropped when the receiver is not connected, or connected without the capability
+            assert_eq!(typing(&state), Response::ResponseStatus { success: false });
+            let (_, mut old_client) = state.push.lock().unwrap().connect("bob", &bob_token, vec![Capability::Push]);
+            assert_eq!(typing(&state), Response::ResponseStatus { success: false });
+            assert!(old_client.try_recv().is_err());
+
+            let (_, mut new_client) = state.push.lock().unwrap().connect("bob", &bob_token, vec![Capability::Push, Capability::Ephemeral]);
+            assert_eq!(typing(&state), Response::ResponseStatus { success: true });
+            match serde_json::from_str(&new_client.try_recv().unwrap()).unwrap() {
+                Response::Ephemeral { message } => assert_eq!(message.ciphertext, vec![10]),
+                response => panic!("Unexpected push: {:?}", response),
+            }
+            assert!(old_client.try_recv().is_err());
+
+            // Never stored
+            assert!(get_messages(&state, &bob_token).is_empty());
+        }
+    }
+
+    #[test]
+    fn sealed_message_access_key() {
+        for state in test_states() {
+            register(&state, "alice");
+            register(&state, "bob");
+            let bob_token: String = log_in(&state, "bob");
+
+            // No access key set by the receiver, the same error as an unknown receiver
+            assert_eq!(error_code(send_sealed_message(&state, "bob", [8; 16], vec![10])), Some(ErrorCode::AccessDenied));
+            assert_eq!(error_code(send_sealed_message(&state, "carol", [8; 16], vec![10])), Some(ErrorCode::AccessDenied));
+
+            assert_eq!(send(&state, Some(&bob_token), Action::SetDeliveryAccessKey { access_key: [8; 16] }), Response::ResponseStatus { success: true });
+            assert_eq!(error_code(send_sealed_message(&state, "bob", [9; 16], vec![10])), Some(ErrorCode::AccessDenied));
+            assert!(matches!(send_sealed_message(&state, "Bob", [8; 16], vec![11]), Response::MessageSent { .. }));
+
+            // Only the sealed message with the right access key is queued, without sender
+            let messages: Vec<Envelope> = get_messages(&state, &bob_token);
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].ciphertext, vec![11]);
+            assert_eq!(messages[0].sender, None);
+            assert_eq!(messages[0].sealed_sender, Some(vec![4]));
+        }
+    }
+
+    #[test]
+    fn delete_account() {
+        for state in test_states() {
+            register(&state, "alice");
+            register(&state, "bob");
+            let alice_token: String = log_in(&state, "alice");
+            let bob_token: String = log_in(&state, "bob");
+            send_message(&state, &alice_token, "bob", vec![10]);
+            send(&state, Some(&bob_token), Action::SetDeliveryAccessKey { access_key: [8; 16] });
+            send_sealed_message(&state, "bob", [8; 16], vec![11]);
+            send_message(&state, &bob_token, "alice", vec![12]);
+            for _ in 0..4 {
+                send(&state, None, Action::LogIn { username: "bob".to_string(), password: "wrong".to_string() });
+            }
+
+            assert_eq!(send(&state, Some(&bob_token), Action::DeleteAccount), Response::ResponseStatus { success: true });
+            // The sessions are revoked and every information about the user is removed
+            assert_eq!(error_code(send(&state, Some(&bob_token), Action::GetAllUsers)), Some(ErrorCode::Unauthenticated));
+            assert_eq!(send(&state, Some(&alice_token), Action::GetAllUsers), Response::UserList { result: vec![] });
+            assert_eq!(error_code(send_message(&state, &alice_token, "bob", vec![11])), Some(ErrorCode::UnknownRecipient));
+            let deleted_user: Response = send(&state, None, Action::LogIn { username: "bob".to_string(), password: "bob password".to_string() });
+            assert_eq!(error_code(deleted_user), Some(ErrorCode::InvalidCredentials));
+
+            // The messages sent by the deleted user are still delivered
+            assert_eq!(get_messages(&state, &alice_token).iter().map(|message| message.ciphertext.clone()).collect::<Vec<Vec<u8>>>(), vec![vec![12]]);
+
+            // The username can be registered again, without the messages (identified and sealed) and the failed logins of the deleted account
+            assert_eq!(register(&state, "bob"), Response::ResponseStatus { success: true });
+            let bob_token: String = log_in(&state, "bob");
+            assert!(get_messages(&state, &bob_token).is_empty());
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+
+const DEFAULT_CONFIG_FILE: &str = "mini-signal.toml";
+
+/// Command line of the server *(each flag can also be set with its environment variable, the flags override the configuration file)*
+#[derive(Debug, Parser)]
+#[command(about = "Mini signal server")]
+pub struct Cli {
+    /// Configuration file (TOML)
+    #[arg(long, global = true, env = "MINI_SIGNAL_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Address to listen on (repeat the flag or separate the addresses with commas)
+    #[arg(long = "listen", global = true, env = "MINI_SIGNAL_LISTEN", value_delimiter = ',')]
+    pub listen_addresses: Vec<SocketAddr>,
+    /// TLS certificate (PEM)
+    #[arg(long, global = true, env = "MINI_SIGNAL_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    /// TLS private key (PEM)
+    #[arg(long, global = true, env = "MINI_SIGNAL_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    /// Directory of the databases
+    #[arg(long, global = true, env = "MINI_SIGNAL_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+    /// Storage backend
+    #[arg(long, global = true, env = "MINI_SIGNAL_STORAGE", value_enum)]
+    pub storage: Option<StorageKind>,
+    /// Days a message waits for its receiver before being deleted
+    #[arg(long, global = true, env = "MINI_SIGNAL_MESSAGE_RETENTION_DAYS")]
+    pub message_retention_days: Option<u64>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Copy, Subcommand)]
+pub enum Command {
+    /// Migrate the databases of the data directory to the current schema, then exit (the server also migrates them when it starts)
+    Migrate,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen_addresses: Vec<SocketAddr>,
+    pub tls: TlsConfig,
+    pub data_dir: PathBuf,
+    pub storage: StorageKind,
+    pub database: DatabaseConfig,
+    pub limits: LimitsConfig,
+    pub retention: RetentionConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    Sqlite, // Databases in the data directory
+    Memory, // Nothing is written on disk, everything is lost when the server stops (development and tests)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub pool_size: u32, // Maximum number of connections to each sqlite database (requests waiting for a connection are queued)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_request_bytes: u64, // Size of a JSON request (the bytes of a message are sent as a JSON array)
+    pub default_page_size: u32, // Messages per GetMessages response when the client does not ask for a page size
+    pub max_page_size: u32,
+    pub page_byte_budget: usize, // Encrypted bytes per GetMessages response (at least one message is sent)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub message_retention_days: u64, // Messages not acknowledged after this period are deleted
+    pub sweep_interval_minutes: u64,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    NoListenAddress,
+    MissingFile(&'static str, PathBuf),
+    DataDir(PathBuf, std::io::Error),
+    Invalid(&'static str, String),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_addresses: vec![SocketAddr::from(([127, 0, 0, 1], 6379))],
+            tls: TlsConfig::default(),
+            data_dir: PathBuf::from("."),
+            storage: StorageKind::Sqlite,
+            database: DatabaseConfig::default(),
+            limits: LimitsConfig::default(),
+            retention: RetentionConfig::default(),
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig { cert_path: PathBuf::from("src/keys/cert.pem"), key_path: PathBuf::from("src/keys/key.rsa") }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig { pool_size: 16 }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig { max_request_bytes: 16 * 1024 * 1024, default_page_size: 50, max_page_size: 200, page_byte_budget: 1024 * 1024 }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig { message_retention_days: 30, sweep_interval_minutes: 60 }
+    }
+}
+
+impl Config {
+    /// Load the configuration file, apply the command line and environment overrides, and validate the result
+    ///
+    /// The default file (`mini-signal.toml`) is optional, a file given with `--config` must exist.
+    /// The TLS files are only needed to start the server *(not for the `migrate` command)*.
+    ///
+    /// # Arguments
+    ///
+    /// * `cli` (Cli): Parsed command line
+    pub fn load(cli: Cli) -> Result<Self, ConfigError> {
+        let mut config: Config = match &cli.config {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Config::default(),
+        };
+
+        if !cli.listen_addresses.is_empty() {
+            config.listen_addresses = cli.listen_addresses;
+        }
+        if let Some(cert_path) = cli.tls_cert {
+            config.tls.cert_path = cert_path;
+        }
+        if let Some(key_path) = cli.tls_key {
+            config.tls.key_path = key_path;
+        }
+        if let Some(data_dir) = cli.data_dir {
+            config.data_dir = data_dir;
+        }
+        if let Some(storage) = cli.storage {
+            config.storage = storage;
+        }
+        if let Some(message_retention_days) = cli.message_retention_days {
+            config.retention.message_retention_days = message_retention_days;
+        }
+
+        config.validate(cli.command.is_none())?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let content: String = std::fs::read_to_string(path).map_err(|error| ConfigError::Read(path.to_path_buf(), error))?;
+        toml::from_str(&content).map_err(|error| ConfigError::Parse(path.to_path_buf(), error))
+    }
+
+    /// Check the configuration before starting the server *(creates the data directory if needed)*
+    fn validate(&self, serve: bool) -> Result<(), ConfigError> {
+        if self.listen_addresses.is_empty() {
+            return Err(ConfigError::NoListenAddress)
+        }
+        if serve && !self.tls.cert_path.is_file() {
+            return Err(ConfigError::MissingFile("TLS certificate", self.tls.cert_path.clone()))
+        }
+        if serve && !self.tls.key_path.is_file() {
+            return Err(ConfigError::MissingFile("TLS private key", self.tls.key_path.clone()))
+        }
+        std::fs::create_dir_all(&self.data_dir).map_err(|error| ConfigError::DataDir(self.data_dir.clone(), error))?;
+
+        if self.database.pool_size == 0 {
+            return Err(ConfigError::Invalid("database.pool_size", "must be greater than 0".to_string()))
+        }
+        if self.limits.max_request_bytes == 0 {
+            return Err(ConfigError::Invalid("limits.max_request_bytes", "must be greater than 0".to_string()))
+        }
+        if self.limits.max_page_size == 0 {
+            return Err(ConfigError::Invalid("limits.max_page_size", "must be greater than 0".to_string()))
+        }
+        if self.limits.default_page_size == 0 || self.limits.default_page_size > self.limits.max_page_size {
+            return Err(ConfigError::Invalid("limits.default_page_size", format!("must be between 1 and limits.max_page_size ({})", self.limits.max_page_size)))
+        }
+        if self.limits.page_byte_budget == 0 {
+            return Err(ConfigError::Invalid("limits.page_byte_budget", "must be greater than 0".to_string()))
+        }
+        if self.retention.message_retention_days == 0 {
+            return Err(ConfigError::Invalid("retention.message_retention_days", "must be greater than 0".to_string()))
+        }
+        if self.retention.sweep_interval_minutes == 0 {
+            return Err(ConfigError::Invalid("retention.sweep_interval_minutes", "must be greater than 0".to_string()))
+        }
+        Ok(())
+    }
+}
+
+impl RetentionConfig {
+    pub fn message_retention(&self) -> Duration {
+        Duration::from_secs(self.message_retention_days * 60 * 60 * 24)
+    }
+
+    pub fn sweep_interval(&self) -> Duration {
+        Duration::from_secs(self.sweep_interval_minutes * 60)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, error) => write!(f, "cannot read the configuration file {}: {}", path.display(), error),
+            ConfigError::Parse(path, error) => write!(f, "invalid configuration file {}: {}", path.display(), error),
+            ConfigError::NoListenAddress => write!(f, "listen_addresses must contain at least one address"),
+            ConfigError::MissingFile(name, path) => write!(f, "{} not found: {}", name, path.display()),
+            ConfigError::DataDir(path, error) => write!(f, "cannot create the data directory {}: {}", path.display(), error),
+            ConfigError::Invalid(field, reason) => write!(f, "{} {}", field, reason),
+        }
+    }
+}
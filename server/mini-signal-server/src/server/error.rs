@@ -0,0 +1,34 @@
+use mini_signal_protocol::ErrorCode;
+
+/// Error of an action, answered with `Response::Error`
+#[derive(Debug)]
+pub struct ServerError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ServerError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerError { code, message: message.into() }
+    }
+
+    pub fn unauthenticated() -> Self {
+        ServerError::new(ErrorCode::Unauthenticated, "A valid session token is required")
+    }
+
+    pub fn internal() -> Self {
+        ServerError::new(ErrorCode::Internal, "Internal server error")
+    }
+}
+
+impl From<rusqlite::Error> for ServerError {
+    fn from(error: rusqlite::Error) -> Self {
+        println!("Database error: {}", error);
+        ServerError::internal()
+    }
+}
+
+/// Check if the error comes from a UNIQUE or PRIMARY KEY constraint *(e.g. the same username registered twice at the same time)*
+pub fn is_constraint_violation(error: &rusqlite::Error) -> bool {
+    error.sqlite_error_code() == Some(rusqlite::ErrorCode::ConstraintViolation)
+}
@@ -1,9 +1,95 @@
 use argon2::{password_hash::{
     rand_core::OsRng,
-    PasswordHash, PasswordHasher, PasswordVerifier, SaltString, Error
-}, Argon2};
+    PasswordHash, PasswordHasher, PasswordVerifier, SaltString
+}, Algorithm, Argon2, Params, Version};
+use std::sync::OnceLock;
 
-pub fn check_hash(password_hash: String) -> bool {
-    let parsed_hash = PasswordHash::new(&password_hash)?;
-    Argon2::default().verify_password(password_hash.as_ref(), &parsed_hash).is_ok()
-}
\ No newline at end of file
+// Salt hard-coded in the client, the passwords stored before server-side hashing are PHC strings with this salt
+const LEGACY_CLIENT_SALT: &str = "vRpg/cByxpn6m1L0ZPF5ew";
+
+/// Hash the password using Argon2id *(default parameters)* and a random salt
+///
+/// # Arguments
+///
+/// * `password` (&str): Password
+///
+/// # Output
+///
+/// * `password_hash` (String): PHC string *($argon2id$v=19$...)*
+pub fn get_hash(password: &str) -> String {
+    let salt: SaltString = SaltString::generate(&mut OsRng);
+
+    let argon2: Argon2 = Argon2::default();
+    let hash: String = match argon2.hash_password(password.as_bytes(), &salt) {
+        Ok(hash) => hash.to_string(),
+        Err(error) => panic!("{}", error),
+    };
+    hash
+}
+
+/// Check if the password corresponds to the PHC string stored *(constant time)*
+///
+/// # Arguments
+///
+/// * `password` (&str): Password
+/// * `password_hash` (&str): PHC string
+pub fn check_hash(password: &str, password_hash: &str) -> bool {
+    let parsed_hash: PasswordHash = match PasswordHash::new(password_hash) {
+        Ok(parsed_hash) => parsed_hash,
+        Err(_) => return false,
+    };
+    // Hash params from `parsed_hash` are used instead of what is configured in the `Argon2` instance
+    Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok()
+}
+
+/// Verify the password against a dummy hash, so a login with an unknown username takes as long as with a wrong password
+///
+/// # Arguments
+///
+/// * `password` (&str): Password
+pub fn check_dummy_hash(password: &str) {
+    // Computed once with the current parameters
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    check_hash(password, DUMMY_HASH.get_or_init(|| get_hash("mini-signal dummy password")));
+}
+
+/// Check if the PHC string has been computed with other parameters than the current ones
+///
+/// # Arguments
+///
+/// * `password_hash` (&str): PHC string
+pub fn needs_rehash(password_hash: &str) -> bool {
+    let parsed_hash: PasswordHash = match PasswordHash::new(password_hash) {
+        Ok(parsed_hash) => parsed_hash,
+        Err(_) => return true,
+    };
+
+    let same_algorithm: bool = parsed_hash.algorithm == Algorithm::Argon2id.ident();
+    let same_version: bool = parsed_hash.version == Some(Version::default().into());
+    let same_params: bool = match Params::try_from(&parsed_hash) {
+        Ok(params) => params == Params::default(),
+        Err(_) => false,
+    };
+
+    !(same_algorithm && same_version && same_params)
+}
+
+/// Check if the PHC string has been stored verbatim from the client *(before server-side hashing)*
+///
+/// # Arguments
+///
+/// * `password_hash` (&str): PHC string
+pub fn is_legacy_hash(password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed_hash) => parsed_hash.salt.is_some_and(|salt| salt.as_str() == LEGACY_CLIENT_SALT),
+        Err(_) => false,
+    }
+}
+
+/// Compare two byte strings in constant time
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1 +1,11 @@
-pub mod server;
\ No newline at end of file
+pub mod config;
+pub mod error;
+pub mod hash;
+pub mod push;
+pub mod rate_limit;
+pub mod retention;
+pub mod sealed_sender;
+pub mod session;
+pub mod time;
+#[cfg(feature = "opaque")]
+pub mod opaque;
\ No newline at end of file
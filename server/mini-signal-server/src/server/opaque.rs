@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use opaque_ke::{CipherSuite, CredentialFinalization, CredentialRequest, RegistrationRequest, RegistrationUpload,
+                ServerLogin, ServerLoginStartParameters, ServerRegistration, ServerSetup};
+use opaque_ke::errors::ProtocolError;
+use rand::rngs::OsRng;
+use crate::server::session::generate_token;
+
+pub const LOGIN_TIMEOUT: Duration = Duration::from_secs(60 * 5); // Time to finish an OPAQUE login
+
+/// OPAQUE cipher suite, must be the same on the client side
+pub struct DefaultCipherSuite;
+
+impl CipherSuite for DefaultCipherSuite {
+    type OprfCs = opaque_ke::Ristretto255;
+    type KeGroup = opaque_ke::Ristretto255;
+    type KeyExchange = opaque_ke::key_exchange::tripledh::TripleDh;
+    type Ksf = opaque_ke::ksf::Identity; // The client already stretches the password with Argon2id before OPAQUE
+}
+
+/// Server side of the OPAQUE registration and login *(the server never learns the password)*
+pub struct OpaqueServer {
+    setup: ServerSetup<DefaultCipherSuite>,
+    pending_logins: HashMap<String, (String, ServerLogin<DefaultCipherSuite>, Instant)>, // (Key: login id) (Value: username, login state, start of the login)
+}
+
+impl OpaqueServer {
+    /// Generate a new server setup *(OPRF seed and server key pair)*
+    pub fn new() -> Self {
+        OpaqueServer { setup: ServerSetup::<DefaultCipherSuite>::new(&mut OsRng), pending_logins: HashMap::new() }
+    }
+
+    /// Load the server setup stored in the password database
+    pub fn from_bytes(setup: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(OpaqueServer { setup: ServerSetup::<DefaultCipherSuite>::deserialize(setup)?, pending_logins: HashMap::new() })
+    }
+
+    pub fn get_setup_bytes(&self) -> Vec<u8> {
+        self.setup.serialize().to_vec()
+    }
+
+    /// First step of the registration
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (&str): Username *(credential identifier)*
+    /// * `registration_request` (&\[u8\]): Registration request of the client
+    ///
+    /// # Output
+    ///
+    /// * `registration_response` (Result\<Vec\<u8\>, ProtocolError\>): Registration response for the client
+    pub fn registration_start(&self, username: &str, registration_request: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let result = ServerRegistration::<DefaultCipherSuite>::start(
+            &self.setup,
+            RegistrationRequest::deserialize(registration_request)?,
+            username.as_bytes())?;
+
+        Ok(result.message.serialize().to_vec())
+    }
+
+    /// Last step of the registration
+    ///
+    /// # Arguments
+    ///
+    /// * `registration_upload` (&\[u8\]): Registration upload of the client
+    ///
+    /// # Output
+    ///
+    /// * `password_file` (Result\<Vec\<u8\>, ProtocolError\>): Password file to store for the user
+    pub fn registration_finish(&self, registration_upload: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let password_file = ServerRegistration::<DefaultCipherSuite>::finish(RegistrationUpload::deserialize(registration_upload)?);
+
+        Ok(password_file.serialize().to_vec())
+    }
+
+    /// First step of the login
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (&str): Username *(credential identifier)*
+    /// * `password_file` (Option\<Vec\<u8\>\>): Password file of the user *(None if the user does not exist, a fake response is sent)*
+    /// * `credential_request` (&\[u8\]): Credential request of the client
+    ///
+    /// # Output
+    ///
+    /// * `(login_id, credential_response)` (Result\<(String, Vec\<u8\>), ProtocolError\>): Login id to send with the last step and credential response for the client
+    pub fn login_start(&mut self, username: &str, password_file: Option<Vec<u8>>, credential_request: &[u8]) -> Result<(String, Vec<u8>), ProtocolError> {
+        self.pending_logins.retain(|_, (_, _, started_at)| started_at.elapsed() < LOGIN_TIMEOUT);
+
+        let password_file: Option<ServerRegistration<DefaultCipherSuite>> = match password_file {
+            Some(password_file) => Some(ServerRegistration::<DefaultCipherSuite>::deserialize(&password_file)?),
+            None => None,
+        };
+
+        let result = ServerLogin::start(
+            &mut OsRng,
+            &self.setup,
+            password_file,
+            CredentialRequest::deserialize(credential_request)?,
+            username.as_bytes(),
+            ServerLoginStartParameters::default())?;
+
+        let login_id: String = generate_token();
+        self.pending_logins.insert(login_id.clone(), (username.to_string(), result.state, Instant::now()));
+
+        Ok((login_id, result.message.serialize().to_vec()))
+    }
+
+    /// Last step of the login
+    ///
+    /// # Arguments
+    ///
+    /// * `login_id` (&str): Login id returned by `login_start`
+    /// * `credential_finalization` (&\[u8\]): Credential finalization of the client
+    ///
+    /// # Output
+    ///
+    /// * `(username, authenticated)` ((Option\<String\>, bool)): Username of the login *(None if the login id is unknown)* and `true` if the client knows the password
+    pub fn login_finish(&mut self, login_id: &str, credential_finalization: &[u8]) -> (Option<String>, bool) {
+        let (username, state, started_at) = match self.pending_logins.remove(login_id) {
+            Some(pending_login) => pending_login,
+            None => return (None, false),
+        };
+        if started_at.elapsed() >= LOGIN_TIMEOUT {
+            return (Some(username), false)
+        }
+
+        let authenticated: bool = match CredentialFinalization::<DefaultCipherSuite>::deserialize(credential_finalization) {
+            Ok(credential_finalization) => state.finish(credential_finalization).is_ok(),
+            Err(_) => false,
+        };
+        (Some(username), authenticated)
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use mini_signal_protocol::Capability;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+type PushConnection = (u64, String, Vec<Capability>, UnboundedSender<String>); // Connection id, session token, capabilities, sender to the WebSocket task
+
+/// WebSocket connections of the connected users, used to push the messages instead of queuing them
+pub struct PushManager {
+    connections: HashMap<String, Vec<PushConnection>>, // (Key: username) (Value: connections of the user)
+    next_connection_id: u64,
+}
+
+impl PushManager {
+    pub fn new() -> Self {
+        PushManager { connections: HashMap::new(), next_connection_id: 0 }
+    }
+
+    /// Register a new WebSocket connection of the user
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (&str): Username of the session
+    /// * `session_token` (&str): Session token used to open the connection
+    /// * `capabilities` (Vec\<Capability\>): Capabilities negotiated by the client *(see CAPABILITIES_HEADER)*
+    ///
+    /// # Output
+    ///
+    /// * `(connection_id, receiver)` ((u64, UnboundedReceiver\<String\>)): Id to give to `disconnect` and receiver of the payloads to send *(closed when the session is revoked)*
+    pub fn connect(&mut self, username: &str, session_token: &str, capabilities: Vec<Capability>) -> (u64, UnboundedReceiver<String>) {
+        let (sender, receiver) = unbounded_channel::<String>();
+        let connection_id: u64 = self.next_connection_id;
+        self.next_connection_id += 1;
+
+        self.connections.entry(username.to_string()).or_default().push((connection_id, session_token.to_string(), capabilities, sender));
+        (connection_id, receiver)
+    }
+
+    /// Remove a closed WebSocket connection
+    pub fn disconnect(&mut self, username: &str, connection_id: u64) {
+        if let Some(connections) = self.connections.get_mut(username) {
+            connections.retain(|(id, _, _, _)| *id != connection_id);
+            if connections.is_empty() {
+                self.connections.remove(username);
+            }
+        }
+    }
+
+    /// Close the connections opened with the corresponding session token *(LogOut)*
+    pub fn disconnect_session(&mut self, session_token: &str) {
+        for connections in self.connections.values_mut() {
+            connections.retain(|(_, token, _, _)| token != session_token);
+        }
+        self.connections.retain(|_, connections| !connections.is_empty());
+    }
+
+    /// Close all the connections of the user except the ones opened with `except_token` *(DeleteAccount, ChangePassword)*
+    pub fn disconnect_user(&mut self, username: &str, except_token: Option<&String>) {
+        if let Some(connections) = self.connections.get_mut(username) {
+            connections.retain(|(_, token, _, _)| Some(token) == except_token);
+            if connections.is_empty() {
+                self.connections.remove(username);
+            }
+        }
+    }
+
+    /// Send the payload to every connection of the user
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (&str): Username of the receiver
+    /// * `payload` (&str): JSON payload
+    ///
+    /// # Output
+    ///
+    /// * `pushed` (bool): `false` if the user has no open connection *(the message must be queued)*
+    pub fn push(&mut self, username: &str, payload: &str) -> bool {
+        self.push_if(username, payload, |_| true)
+    }
+
+    /// Send the payload to the connections of the user that negotiated the capability
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (&str): Username of the receiver
+    /// * `payload` (&str): JSON payload
+    /// * `capability` (Capability): Capability needed to read the payload
+    ///
+    /// # Output
+    ///
+    /// * `pushed` (bool): `false` if no connection of the user negotiated the capability
+    pub fn push_with_capability(&mut self, username: &str, payload: &str, capability: Capability) -> bool {
+        self.push_if(username, payload, |capabilities| capabilities.contains(&capability))
+    }
+
+    fn push_if(&mut self, username: &str, payload: &str, accepts: impl Fn(&Vec<Capability>) -> bool) -> bool {
+        let mut pushed: bool = false;
+        if let Some(connections) = self.connections.get_mut(username) {
+            // A send only fails when the WebSocket task has already stopped
+            connections.retain(|(_, _, capabilities, sender)| {
+                if !accepts(capabilities) {
+                    return true
+                }
+                let sent: bool = sender.send(payload.to_string()).is_ok();
+                pushed |= sent;
+                sent
+            });
+        }
+        pushed
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const FREE_FAILURES: u32 = 3; // Failed logins allowed before the first lockout
+const BASE_LOCKOUT: Duration = Duration::from_secs(30);
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60); // One hour
+pub const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(60); // Reservation of the keys of a login checked in one request (released at its end)
+pub const FAILURE_WINDOW: Duration = Duration::from_secs(60 * 60 * 24); // Failures are forgotten after one day without failure
+
+/// Return the lockout duration after `failures` consecutive failed logins *(exponential backoff)*
+///
+/// # Arguments
+///
+/// * `failures` (u32): Number of consecutive failed logins
+///
+/// # Output
+///
+/// * `lockout` (Duration): 0 for the first `FREE_FAILURES` failures, then 30s, 60s, 120s... up to one hour
+pub fn lockout_duration(failures: u32) -> Duration {
+    if failures <= FREE_FAILURES {
+        return Duration::ZERO
+    }
+
+    let exponent: u32 = (failures - FREE_FAILURES - 1).min(16);
+    BASE_LOCKOUT.saturating_mul(2u32.pow(exponent)).min(MAX_LOCKOUT)
+}
+
+/// Keys used to limit the login attempts *(per username and per source address)*
+///
+/// # Arguments
+///
+/// * `username` (&str): Username
+/// * `ip_addr` (Option\<SocketAddr\>): Source address of the request *(the port is ignored)*
+pub fn attempt_keys(username: &str, ip_addr: Option<SocketAddr>) -> Vec<String> {
+    let mut keys: Vec<String> = vec![format!("user:{}", username)];
+    if let Some(addr) = ip_addr {
+        keys.push(format!("ip:{}", addr.ip()));
+    }
+    keys
+}
+
+/// Login attempts in progress
+///
+/// The lockout of a key is checked and the result of the attempt is recorded before the next attempt with this key starts:
+/// otherwise the attempts sent at the same time would all pass the check, and the limit would scale with the concurrency.
+pub struct LoginAttempts {
+    in_progress: Mutex<HashMap<String, Instant>>, // (Key: attempt key) (Value: end of the reservation)
+}
+
+/// Keys reserved for one login attempt, released when it is dropped
+pub struct AttemptReservation<'a> {
+    attempts: &'a LoginAttempts,
+    keys: Vec<String>,
+}
+
+impl LoginAttempts {
+    pub fn new() -> Self {
+        LoginAttempts { in_progress: Mutex::new(HashMap::new()) }
+    }
+
+    /// Reserve the keys for one login attempt
+    ///
+    /// # Arguments
+    ///
+    /// * `keys` (&Vec\<String\>): Keys of the attempt *(see `attempt_keys`)*
+    /// * `timeout` (Duration): The reservation ends after `timeout` if it is kept and not released
+    ///
+    /// # Output
+    ///
+    /// * `reservation` (Option\<AttemptReservation\>): None if an attempt with one of the keys is in progress
+    pub fn reserve(&self, keys: &Vec<String>, timeout: Duration) -> Option<AttemptReservation<'_>> {
+        let mut in_progress = self.in_progress.lock().unwrap();
+        let now: Instant = Instant::now();
+        in_progress.retain(|_, reserved_until| *reserved_until > now);
+        if keys.iter().any(|key| in_progress.contains_key(key)) {
+            return None
+        }
+        for key in keys {
+            in_progress.insert(key.clone(), now + timeout);
+        }
+        Some(AttemptReservation { attempts: self, keys: keys.clone() })
+    }
+
+    /// End the reservation of the keys *(kept with `AttemptReservation::keep`)*
+    pub fn release(&self, keys: &Vec<String>) {
+        let mut in_progress = self.in_progress.lock().unwrap();
+        for key in keys {
+            in_progress.remove(key);
+        }
+    }
+}
+
+#[cfg(feature = "opaque")]
+impl AttemptReservation<'_> {
+    /// Keep the keys reserved after the end of the request, until `LoginAttempts::release` or the timeout *(login in two requests)*
+    pub fn keep(mut self) {
+        self.keys.clear();
+    }
+}
+
+impl Drop for AttemptReservation<'_> {
+    fn drop(&mut self) {
+        self.attempts.release(&self.keys);
+    }
+}
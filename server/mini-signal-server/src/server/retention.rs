@@ -0,0 +1,32 @@
+use std::sync::Arc;
+use std::time::Duration;
+use crate::database::storage::StorageBackend;
+use crate::server::time::unix_time_now;
+
+/// Delete the expired messages every `sweep_interval` *(runs until the server stops)*
+///
+/// # Arguments
+///
+/// * `storage` (Arc\<StorageBackend\>): Storage of the messages
+/// * `retention` (Duration): Time a message waits for its receiver
+/// * `sweep_interval` (Duration): Time between two sweeps *(the first one runs at the start)*
+pub async fn run_expiry_sweeper(storage: Arc<StorageBackend>, retention: Duration, sweep_interval: Duration) {
+    let mut interval = tokio::time::interval(sweep_interval);
+    loop {
+        interval.tick().await;
+        let sweep_storage: Arc<StorageBackend> = storage.clone();
+        match tokio::task::spawn_blocking(move || sweep_expired_messages(&sweep_storage, retention)).await {
+            Ok(Ok(Ok(0))) => {},
+            Ok(Ok(Ok(expired))) => println!("{} expired messages deleted", expired),
+            Ok(Ok(Err(error))) => println!("Error when deleting the expired messages: {}", error),
+            Ok(Err(error)) => println!("No database connection to delete the expired messages: {}", error),
+            Err(error) => println!("Expiry sweeper stopped unexpectedly: {}", error),
+        }
+    }
+}
+
+/// Delete the messages older than the retention
+fn sweep_expired_messages(storage: &StorageBackend, retention: Duration) -> Result<rusqlite::Result<usize>, r2d2::Error> {
+    let now: u64 = unix_time_now();
+    storage.with_storage(|storage| storage.expire_messages(now.saturating_sub(retention.as_secs()), now))
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+use ed25519_dalek::{Signer, SigningKey};
+use mini_signal_protocol::SenderCertificate;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use crate::server::hash::constant_time_eq;
+use crate::server::time::unix_time_now;
+
+const CERTIFICATE_DURATION: Duration = Duration::from_secs(60 * 60 * 24); // One day, the client asks for a new one when it expires
+
+/// Issue the sender certificates put in the sealed messages *(signed with the Ed25519 key of the server)*
+pub struct CertificateIssuer {
+    signing_key: SigningKey,
+}
+
+impl CertificateIssuer {
+    /// Generate a new signing key
+    pub fn new() -> Self {
+        let mut secret_key: [u8; 32] = [0u8; 32];
+        OsRng.fill_bytes(&mut secret_key);
+        CertificateIssuer { signing_key: SigningKey::from_bytes(&secret_key) }
+    }
+
+    /// Load the signing key stored in the password database
+    pub fn from_bytes(secret_key: &[u8; 32]) -> Self {
+        CertificateIssuer { signing_key: SigningKey::from_bytes(secret_key) }
+    }
+
+    pub fn get_secret_key_bytes(&self) -> [u8; 32] {
+        self.signing_key.to_bytes()
+    }
+
+    /// Public key given to the clients to verify the certificates
+    pub fn get_verifying_key(&self) -> [u8; 32] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    /// Certify that the Identity Key `ik` belongs to `sender`
+    ///
+    /// # Arguments
+    ///
+    /// * `sender` (&str): Username of the session
+    /// * `ik` (\[u8; 32\]): Identity Key published by the user
+    ///
+    /// # Output
+    ///
+    /// * SenderCertificate
+    pub fn issue(&self, sender: &str, ik: [u8; 32]) -> SenderCertificate {
+        let expires_at: u64 = unix_time_now() + CERTIFICATE_DURATION.as_secs();
+        let signature: [u8; 64] = self.signing_key.sign(&SenderCertificate::signed_bytes(sender, &ik, expires_at)).to_bytes();
+        SenderCertificate { sender: sender.to_string(), ik, expires_at, signature }
+    }
+}
+
+/// Check the delivery access key presented for a sealed message *(constant time, the key is a secret of the receiver)*
+///
+/// # Arguments
+///
+/// * `expected` (Option\<\[u8; 16\]\>): Access key set by the receiver, None if it does not accept sealed messages
+/// * `presented` (&\[u8; 16\]): Access key sent with the message
+pub fn check_access_key(expected: Option<[u8; 16]>, presented: &[u8; 16]) -> bool {
+    match expected {
+        Some(expected) => constant_time_eq(&expected, presented),
+        None => false,
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rand::RngCore;
+use rand::rngs::OsRng;
+
+const SESSION_TOKEN_SIZE: usize = 32;
+const SESSION_DURATION: Duration = Duration::from_secs(60 * 60 * 24); // One day
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    username: String,
+    expires_at: SystemTime,
+}
+
+impl Session {
+    pub fn get_username(&self) -> String {
+        self.username.clone()
+    }
+
+    /// Expiry of the session in seconds since the UNIX epoch
+    pub fn get_expires_at(&self) -> u64 {
+        self.expires_at.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+    }
+
+    fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.expires_at
+    }
+}
+
+/// Keep track of the authenticated users *(a user can have several sessions at the same time)*
+pub struct SessionManager {
+    sessions: HashMap<String, Session>, // (Key: session token) (Value: session)
+}
+
+impl SessionManager {
+    pub fn new() -> Self {
+        SessionManager { sessions: HashMap::new() }
+    }
+
+    /// Open a new session for the corresponding `username`
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (&str): Username
+    ///
+    /// # Output
+    ///
+    /// * `(token, session)` ((String, Session)): Opaque session token and the session created
+    pub fn create_session(&mut self, username: &str) -> (String, Session) {
+        self.remove_expired_sessions();
+
+        let token: String = generate_token();
+        let session: Session = Session { username: username.to_string(), expires_at: SystemTime::now() + SESSION_DURATION };
+        self.sessions.insert(token.clone(), session.clone());
+
+        (token, session)
+    }
+
+    /// Return the username of the session if the token is valid and not expired
+    ///
+    /// # Arguments
+    ///
+    /// * `token` (Option\<&String\>): Session token sent by the client
+    ///
+    /// # Output
+    ///
+    /// * `username` (Option\<String\>): Username authenticated by the token
+    pub fn validate(&mut self, token: Option<&String>) -> Option<String> {
+        let token: &String = token?;
+        match self.sessions.get(token) {
+            Some(session) if session.is_expired() => {
+                self.sessions.remove(token);
+                None
+            },
+            Some(session) => Some(session.get_username()),
+            None => None,
+        }
+    }
+
+    /// Close the session of the corresponding `token`
+    ///
+    /// # Output
+    ///
+    /// * bool: `true` if the session existed
+    pub fn revoke(&mut self, token: &str) -> bool {
+        self.sessions.remove(token).is_some()
+    }
+
+    /// Close every session of the corresponding `username`
+    ///
+    /// # Arguments
+    ///
+    /// * `username` (&str): Username
+    /// * `except_token` (Option\<&String\>): Session to keep open
+    pub fn revoke_user(&mut self, username: &str, except_token: Option<&String>) {
+        self.sessions.retain(|token, session| session.username != username || Some(token) == except_token);
+    }
+
+    fn remove_expired_sessions(&mut self) {
+        self.sessions.retain(|_, session| !session.is_expired());
+    }
+}
+
+/// Generate a random token *(hex encoded)*
+pub fn generate_token() -> String {
+    let mut token_bytes: [u8; SESSION_TOKEN_SIZE] = [0u8; SESSION_TOKEN_SIZE];
+    OsRng.fill_bytes(&mut token_bytes);
+    token_bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,6 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Current time in seconds since the UNIX epoch *(the time stored in the databases and sent to the clients)*
+pub fn unix_time_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}